@@ -0,0 +1,19 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use rust_poker::game::Game;
+
+// Sends the commands to a game, each from a seat that may also be invalid. Whatever the
+// commands, the game answers with JSON and no chips appear or disappear.
+fuzz_target!(|commands: Vec<(u8, String)>| {
+    let mut game = Game::new_with_stacks_and_sb(500, 500, 5);
+    for (seat, command) in commands {
+        let (response, hand_result) = game.process_user_command(&command, seat % 3);
+        assert!(serde_json::from_str::<serde_json::Value>(&response).is_ok(), "not JSON: {}", response);
+        if let Some(result) = hand_result {
+            assert_eq!(result.btn_stack + result.bb_stack, 1000);
+        }
+        let hand = game.current_hand();
+        assert_eq!(hand.btn_stack + hand.bb_stack + hand.pot, 1000);
+    }
+});
@@ -0,0 +1,333 @@
+use std::cmp::{max, min};
+use std::fs;
+use std::io;
+use std::path::Path;
+use serde::{Serialize, Deserialize};
+
+use crate::cfr::{CfrGame, CfrSolver, CfrVariant, Strategy};
+use crate::common::Position;
+use crate::street::{Action, ActionOption, ActionResult, Street, StreetName};
+
+// Describes an abstracted heads-up no-limit game. Hole cards are replaced by
+// hand strength buckets that are dealt independently and uniformly at random, and
+// bets and raises are limited to the given fractions of the pot plus all in.
+// The betting itself follows the rules of Street.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AbstractionConfig{
+    pub sb_size: u64,
+    pub stack: u64, // Starting stack of both players
+    pub bet_sizes: Vec<f64>, // Fractions of the pot
+    pub buckets: u8,
+    pub last_street: StreetName, // The hand goes to showdown after this street
+    pub max_raises_per_street: usize, // Bets and raises per street
+}
+
+// The abstract game tree defined by an AbstractionConfig
+pub struct HuAbstraction{
+    pub config: AbstractionConfig,
+}
+
+#[derive(Debug, Clone)]
+pub struct HuState{
+    buckets: Option<(u8, u8)>, // Button, big blind. Higher bucket wins at showdown.
+    streets: Vec<Street>,
+    labels: Vec<Vec<String>>, // Abstract action labels of each street, without blinds
+    folded: Option<Position>,
+    showdown: bool,
+}
+
+// Chips put in the pot on the streets before the last one
+pub fn pot_before_last_street(streets: &[Street]) -> u64{
+    streets.iter().rev().skip(1).map(|street| {
+        let (btn_added_chips, bb_added_chips, _, _) = street.get_street_status();
+        btn_added_chips + bb_added_chips
+    }).sum()
+}
+
+pub fn next_street_name(street: StreetName) -> StreetName{
+    match street{
+        StreetName::Preflop => StreetName::Flop,
+        StreetName::Flop => StreetName::Turn,
+        StreetName::Turn => StreetName::River,
+        StreetName::River | StreetName::End => StreetName::End,
+    }
+}
+
+// Returns the abstract actions available at the street, labeled as follows:
+// "f" fold, "x" check, "c" call, "b50" bet of 50% of the pot, "r100" pot-sized raise, "a" all in.
+// Blinds are labeled "p". pot_before_street is the amount of chips from the earlier streets.
+pub fn abstract_actions(street: &Street, pot_before_street: u64, bet_sizes: &[f64], max_raises: usize) -> Vec<(String, Action)>{
+
+    let (btn_added_chips, bb_added_chips, _, _) = street.get_street_status();
+    let pot = pot_before_street + btn_added_chips + bb_added_chips;
+    let bigger = max(btn_added_chips, bb_added_chips);
+    let smaller = min(btn_added_chips, bb_added_chips);
+    let raises = street.actions.iter().filter(|a| matches!(a, Action::Bet(_) | Action::Raise(_))).count();
+    let options = street.get_available_actions();
+    let facing_bet = options.iter().any(|o| matches!(o, ActionOption::Call(_)));
+
+    let mut actions: Vec<(String, Action)> = Vec::new();
+    for option in options{
+        match option{
+            ActionOption::PostBlind(amount) => actions.push(("p".to_string(), Action::PostBlind(amount))),
+            ActionOption::Fold => if facing_bet {
+                actions.push(("f".to_string(), Action::Fold));
+            },
+            ActionOption::Check => actions.push(("x".to_string(), Action::Check)),
+            ActionOption::Call(amount) => actions.push(("c".to_string(), Action::Call(amount))),
+            ActionOption::Bet(minimum, maximum) => if minimum <= maximum && raises < max_raises {
+                for fraction in bet_sizes{
+                    let amount = ((pot as f64 * fraction) as u64).clamp(minimum, maximum);
+                    if amount < maximum && !actions.iter().any(|(_, a)| *a == Action::Bet(amount)) {
+                        actions.push((format!("b{}", (fraction * 100.0).round()), Action::Bet(amount)));
+                    }
+                }
+                actions.push(("a".to_string(), Action::Bet(maximum)));
+            },
+            ActionOption::Raise(minimum, maximum) => if minimum <= maximum && raises < max_raises {
+                let pot_after_call = pot + bigger - smaller;
+                for fraction in bet_sizes{
+                    let amount = (bigger + (pot_after_call as f64 * fraction) as u64).clamp(minimum, maximum);
+                    if amount < maximum && !actions.iter().any(|(_, a)| *a == Action::Raise(amount)) {
+                        actions.push((format!("r{}", (fraction * 100.0).round()), Action::Raise(amount)));
+                    }
+                }
+                actions.push(("a".to_string(), Action::Raise(maximum)));
+            },
+        }
+    }
+    actions
+}
+
+// Maps a concrete action to the label of the closest abstract action of the same kind.
+// Returns None if there is no abstract action of that kind.
+pub fn closest_abstract_action(action: Action, abstract_actions: &[(String, Action)]) -> Option<String>{
+    let amount = |a: &Action| match a{
+        Action::Fold | Action::Check => 0,
+        Action::PostBlind(x) | Action::Call(x) | Action::Bet(x) | Action::Raise(x) => *x,
+    };
+    abstract_actions.iter()
+        .filter(|(_, a)| std::mem::discriminant(a) == std::mem::discriminant(&action))
+        .min_by_key(|(_, a)| amount(a).abs_diff(amount(&action)))
+        .map(|(label, _)| label.clone())
+}
+
+pub fn info_set_key(position: Position, bucket: u8, labels: &[Vec<String>]) -> String{
+    let position = match position{
+        Position::Button => "btn",
+        Position::BigBlind => "bb",
+    };
+    let history: Vec<String> = labels.iter().map(|street| street.join(".")).collect();
+    format!("{}|{}|{}", position, bucket, history.join("/"))
+}
+
+impl HuAbstraction{
+    pub fn new(config: AbstractionConfig) -> HuAbstraction{
+        HuAbstraction{config}
+    }
+
+    fn actions_with_labels(&self, state: &HuState) -> Vec<(String, Action)>{
+        abstract_actions(state.streets.last().unwrap(),
+                         pot_before_last_street(&state.streets),
+                         &self.config.bet_sizes,
+                         self.config.max_raises_per_street)
+    }
+}
+
+impl CfrGame for HuAbstraction{
+    type State = HuState;
+
+    fn root(&self) -> HuState{
+        let mut preflop = Street::new(StreetName::Preflop, 2 * self.config.sb_size, self.config.stack, self.config.stack);
+        preflop.submit_action(Action::PostBlind(self.config.sb_size)).unwrap();
+        preflop.submit_action(Action::PostBlind(2 * self.config.sb_size)).unwrap();
+        HuState{buckets: None, streets: vec![preflop], labels: vec![vec![]], folded: None, showdown: false}
+    }
+
+    fn is_terminal(&self, state: &HuState) -> bool{
+        state.folded.is_some() || state.showdown
+    }
+
+    // In big blinds
+    fn payoff(&self, state: &HuState) -> f64{
+        let street = state.streets.last().unwrap();
+        let btn_added = self.config.stack - street.btn_stack;
+        let bb_added = self.config.stack - street.bb_stack;
+        let chips = match state.folded{
+            Some(Position::Button) => -(btn_added as f64),
+            Some(Position::BigBlind) => bb_added as f64,
+            None => {
+                let (btn_bucket, bb_bucket) = state.buckets.unwrap();
+                match btn_bucket.cmp(&bb_bucket){
+                    std::cmp::Ordering::Greater => bb_added as f64,
+                    std::cmp::Ordering::Less => -(btn_added as f64),
+                    std::cmp::Ordering::Equal => 0.0,
+                }
+            }
+        };
+        chips / (2 * self.config.sb_size) as f64
+    }
+
+    fn chance_outcomes(&self, state: &HuState) -> Option<Vec<(HuState, f64)>>{
+        if state.buckets.is_some() {
+            return None;
+        }
+        let n = self.config.buckets;
+        let prob = 1.0 / (n as f64 * n as f64);
+        let mut outcomes = Vec::new();
+        for btn_bucket in 0..n{
+            for bb_bucket in 0..n{
+                let mut next = state.clone();
+                next.buckets = Some((btn_bucket, bb_bucket));
+                outcomes.push((next, prob));
+            }
+        }
+        Some(outcomes)
+    }
+
+    fn current_player(&self, state: &HuState) -> usize{
+        let (_, _, _, active_player) = state.streets.last().unwrap().get_street_status();
+        match active_player{
+            Position::Button => 0,
+            Position::BigBlind => 1,
+        }
+    }
+
+    fn info_set(&self, state: &HuState) -> String{
+        let (btn_bucket, bb_bucket) = state.buckets.unwrap();
+        match self.current_player(state){
+            0 => info_set_key(Position::Button, btn_bucket, &state.labels),
+            _ => info_set_key(Position::BigBlind, bb_bucket, &state.labels),
+        }
+    }
+
+    fn actions(&self, state: &HuState) -> Vec<String>{
+        self.actions_with_labels(state).into_iter().map(|(label, _)| label).collect()
+    }
+
+    fn apply(&self, state: &HuState, action: usize) -> HuState{
+        let (label, action) = self.actions_with_labels(state).swap_remove(action);
+        let mut next = state.clone();
+        let street = next.streets.last_mut().unwrap();
+        let streetname = street.street;
+        let result = street.submit_action(action).unwrap();
+        let all_in = street.btn_stack == 0 || street.bb_stack == 0;
+        let (btn_stack, bb_stack) = (street.btn_stack, street.bb_stack);
+        next.labels.last_mut().unwrap().push(label);
+
+        match result{
+            ActionResult::Fold(player) => next.folded = Some(player),
+            ActionResult::BettingClosed => {
+                if streetname == self.config.last_street || all_in {
+                    next.showdown = true;
+                } else {
+                    next.streets.push(Street::new(next_street_name(streetname), 2 * self.config.sb_size, btn_stack, bb_stack));
+                    next.labels.push(vec![]);
+                }
+            },
+            ActionResult::BettingOpen => (),
+        }
+        next
+    }
+}
+
+// A solved abstraction that can be exported to disk and loaded into a bot
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HuStrategy{
+    pub config: AbstractionConfig,
+    pub strategy: Strategy,
+}
+
+impl HuStrategy{
+    pub fn save(&self, path: &Path) -> io::Result<()>{
+        fs::write(path, serde_json::to_string(self).unwrap())
+    }
+
+    pub fn load(path: &Path) -> io::Result<HuStrategy>{
+        let json = fs::read_to_string(path)?;
+        serde_json::from_str(&json).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+}
+
+pub fn solve(config: AbstractionConfig, variant: CfrVariant, iterations: u64) -> HuStrategy{
+    let mut solver = CfrSolver::new(HuAbstraction::new(config.clone()), variant);
+    solver.train(iterations);
+    HuStrategy{config, strategy: solver.average_strategy()}
+}
+
+#[cfg(test)]
+mod tests{
+
+    use super::*;
+    use crate::cfr::exploitability;
+
+    fn preflop_config() -> AbstractionConfig{
+        AbstractionConfig{
+            sb_size: 5,
+            stack: 100,
+            bet_sizes: vec![1.0],
+            buckets: 3,
+            last_street: StreetName::Preflop,
+            max_raises_per_street: 2,
+        }
+    }
+
+    #[test]
+    fn test_abstract_actions_after_blinds(){
+        let mut street = Street::new(StreetName::Preflop, 10, 100, 100);
+        street.submit_action(Action::PostBlind(5)).unwrap();
+        street.submit_action(Action::PostBlind(10)).unwrap();
+
+        let actions = abstract_actions(&street, 0, &[0.5, 1.0], 2);
+        // Pot is 15 and the call costs 5, so a pot-sized raise is to 10 + 20 = 30.
+        // Half pot would be to 20 which equals the minimum raise.
+        assert_eq!(actions, vec![
+            ("f".to_string(), Action::Fold),
+            ("c".to_string(), Action::Call(10)),
+            ("r50".to_string(), Action::Raise(20)),
+            ("r100".to_string(), Action::Raise(30)),
+            ("a".to_string(), Action::Raise(100)),
+        ]);
+
+        assert_eq!(closest_abstract_action(Action::Raise(33), &actions), Some("r100".to_string()));
+        assert_eq!(closest_abstract_action(Action::Check, &actions), None);
+    }
+
+    #[test]
+    fn test_no_fold_when_check_is_possible(){
+        let street = Street::new(StreetName::Flop, 10, 100, 100);
+        let labels: Vec<String> = abstract_actions(&street, 20, &[1.0], 2).into_iter().map(|(l, _)| l).collect();
+        assert_eq!(labels, vec!["b100", "a", "x"]);
+    }
+
+    #[test]
+    fn test_solve_preflop_game(){
+        let solution = solve(preflop_config(), CfrVariant::Plus, 300);
+        let game = HuAbstraction::new(preflop_config());
+
+        assert!(exploitability(&game, &solution.strategy) < 0.01);
+
+        // The best bucket never folds to an all in
+        let key = info_set_key(Position::BigBlind, 2, &[vec!["a".to_string()]]);
+        assert!(solution.strategy.probability(&key, "f").unwrap() < 0.01);
+    }
+
+    #[test]
+    fn test_multi_street_tree_reaches_flop(){
+        let mut config = preflop_config();
+        config.last_street = StreetName::Flop;
+        let game = HuAbstraction::new(config);
+        let (state, _) = game.chance_outcomes(&game.root()).unwrap().swap_remove(0);
+
+        // Button limps and big blind checks
+        let call = game.actions(&state).iter().position(|a| a == "c").unwrap();
+        let state = game.apply(&state, call);
+        let check = game.actions(&state).iter().position(|a| a == "x").unwrap();
+        let state = game.apply(&state, check);
+
+        assert!(!game.is_terminal(&state));
+        assert_eq!(state.streets.last().unwrap().street, StreetName::Flop);
+        assert_eq!(game.current_player(&state), 1);
+        assert_eq!(game.info_set(&state), "bb|0|c.x/");
+    }
+}
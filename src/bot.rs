@@ -0,0 +1,183 @@
+use std::io;
+use std::path::Path;
+use poker::{Card, Evaluator};
+use rand::seq::SliceRandom;
+
+use crate::abstraction::{abstract_actions, closest_abstract_action, info_set_key, pot_before_last_street, HuStrategy};
+use crate::common::Position;
+use crate::game::Game;
+use crate::street::{Action, ActionOption, Street};
+
+// A bot that plays a solved abstraction through Game
+pub struct Bot{
+    solution: HuStrategy,
+    evaluator: Evaluator,
+    equity_samples: usize,
+}
+
+impl Bot{
+
+    pub fn new(solution: HuStrategy) -> Bot{
+        Bot{solution, evaluator: Evaluator::new(), equity_samples: 200}
+    }
+
+    pub fn load(path: &Path) -> io::Result<Bot>{
+        Ok(Bot::new(HuStrategy::load(path)?))
+    }
+
+    // Estimates the probability of winning against a random hand by sampling the
+    // opponent's hole cards and the rest of the board. Ties count as half a win.
+    pub fn estimate_equity(&self, hole_cards: (Card, Card), board: &[Card]) -> f64{
+        let mut deck: Vec<Card> = Card::generate_deck()
+            .filter(|c| *c != hole_cards.0 && *c != hole_cards.1 && !board.contains(c))
+            .collect();
+        let missing_board_cards = 5 - board.len();
+        let mut rng = rand::thread_rng();
+
+        let mut wins = 0.0;
+        for _ in 0..self.equity_samples{
+            let (sample, _) = deck.partial_shuffle(&mut rng, 2 + missing_board_cards);
+            let mut full_board = board.to_vec();
+            full_board.extend_from_slice(&sample[2..]);
+
+            let mut own = vec![hole_cards.0, hole_cards.1];
+            own.extend_from_slice(&full_board);
+            let mut opponent = vec![sample[0], sample[1]];
+            opponent.extend_from_slice(&full_board);
+
+            let own_eval = self.evaluator.evaluate(&own).unwrap();
+            let opponent_eval = self.evaluator.evaluate(&opponent).unwrap();
+            if own_eval.is_better_than(opponent_eval) {
+                wins += 1.0;
+            } else if !opponent_eval.is_better_than(own_eval) {
+                wins += 0.5;
+            }
+        }
+        wins / self.equity_samples as f64
+    }
+
+    fn bucket(&self, hole_cards: (Card, Card), board: &[Card]) -> u8{
+        let buckets = self.solution.config.buckets;
+        let bucket = (self.estimate_equity(hole_cards, board) * buckets as f64) as u8;
+        bucket.min(buckets - 1)
+    }
+
+    // Translates the action history of the hand into abstract action labels
+    fn abstract_history(&self, streets: &[Street]) -> Vec<Vec<String>>{
+        let config = &self.solution.config;
+        let mut history = Vec::new();
+        for i in 0..streets.len(){
+            let street = &streets[i];
+            let pot = pot_before_last_street(&streets[..=i]);
+            let mut replay = Street::new(street.street, street.min_open_raise, street.btn_start_stack, street.bb_start_stack);
+            let mut labels = Vec::new();
+            for action in street.actions.iter(){
+                if !matches!(action, Action::PostBlind(_)) {
+                    let options = abstract_actions(&replay, pot, &config.bet_sizes, config.max_raises_per_street);
+                    labels.push(closest_abstract_action(*action, &options).unwrap_or_else(|| "?".to_string()));
+                }
+                let _ = replay.submit_action(*action);
+            }
+            history.push(labels);
+        }
+        history
+    }
+
+    // Returns the action the bot takes for the given seat, or None if it is not that seat's turn.
+    // Situations outside of the solved tree are played passively by checking or calling.
+    pub fn choose_action(&self, game: &Game, seat: u8) -> Option<Action>{
+        let hand = game.current_hand();
+        let position = game.get_position(seat);
+        let street = hand.streets.last().unwrap();
+        let (_, _, _, active_player) = street.get_street_status();
+        if active_player != position {
+            return None;
+        }
+
+        let options = street.get_available_actions();
+        if let [ActionOption::PostBlind(amount)] = options[..] {
+            return Some(Action::PostBlind(amount));
+        }
+        if options.is_empty() {
+            return None;
+        }
+
+        let config = &self.solution.config;
+        let hole_cards = match position{
+            Position::Button => hand.btn_hole_cards,
+            Position::BigBlind => hand.bb_hole_cards,
+        };
+        let key = info_set_key(position, self.bucket(hole_cards, &hand.board_cards), &self.abstract_history(&hand.streets));
+        let actions = abstract_actions(street, pot_before_last_street(&hand.streets), &config.bet_sizes, config.max_raises_per_street);
+
+        let chosen = self.solution.strategy.sample(&key, &mut rand::thread_rng())
+            .and_then(|label| actions.iter().find(|(l, _)| *l == label).map(|(_, a)| *a));
+
+        chosen.or_else(|| {
+            options.iter().find_map(|option| match option{
+                ActionOption::Check => Some(Action::Check),
+                ActionOption::Call(amount) => Some(Action::Call(*amount)),
+                _ => None,
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests{
+
+    use super::*;
+    use crate::abstraction::{solve, AbstractionConfig};
+    use crate::cfr::CfrVariant;
+    use crate::street::StreetName;
+    use poker::cards;
+
+    fn solution() -> HuStrategy{
+        let config = AbstractionConfig{
+            sb_size: 5,
+            stack: 200,
+            bet_sizes: vec![0.5, 1.0],
+            buckets: 4,
+            last_street: StreetName::Preflop,
+            max_raises_per_street: 2,
+        };
+        solve(config, CfrVariant::Plus, 50)
+    }
+
+    #[test]
+    fn test_equity_estimate(){
+        let bot = Bot::new(solution());
+        let aces: Vec<Card> = cards!("Ah As").try_collect().unwrap();
+        let seven_deuce: Vec<Card> = cards!("7h 2c").try_collect().unwrap();
+        assert!(bot.estimate_equity((aces[0], aces[1]), &[]) > 0.7);
+        assert!(bot.estimate_equity((seven_deuce[0], seven_deuce[1]), &[]) < 0.5);
+    }
+
+    #[test]
+    fn test_bots_play_through_game(){
+        let path = std::env::temp_dir().join(format!("hu_strategy_{}.json", std::process::id()));
+        solution().save(&path).unwrap();
+        let bot = Bot::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let mut game = Game::new_with_stacks_and_sb(200, 200, 5);
+        let mut hands_played = 0;
+        for _ in 0..1000{
+            let (seat, action) = match (bot.choose_action(&game, 0), bot.choose_action(&game, 1)){
+                (Some(action), None) => (0, action),
+                (None, Some(action)) => (1, action),
+                (None, None) => break, // Someone is out of chips
+                (Some(_), Some(_)) => panic!("Both seats think it is their turn"),
+            };
+            if game.submit_action(action, seat).unwrap().is_some() {
+                hands_played += 1;
+                if hands_played == 10 {
+                    break;
+                }
+            }
+        }
+        assert!(hands_played > 0);
+        let hand = game.current_hand();
+        assert_eq!(hand.btn_start_stack + hand.bb_start_stack, 400);
+    }
+}
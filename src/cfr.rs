@@ -0,0 +1,628 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io;
+use std::path::Path;
+use rand::Rng;
+use serde::{Serialize, Deserialize};
+
+// A two-player zero-sum game of imperfect information that can be solved with CFR.
+// Player 0 is the first player of the game and player 1 the second. All payoffs
+// are from the point of view of player 0.
+pub trait CfrGame{
+    type State: Clone;
+
+    fn root(&self) -> Self::State;
+    fn is_terminal(&self, state: &Self::State) -> bool;
+
+    // Payoff to player 0 at a terminal state. Player 1 gets the negation.
+    fn payoff(&self, state: &Self::State) -> f64;
+
+    // Returns the possible outcomes and their probabilities if the state is a chance node,
+    // otherwise returns None.
+    fn chance_outcomes(&self, state: &Self::State) -> Option<Vec<(Self::State, f64)>>;
+
+    fn current_player(&self, state: &Self::State) -> usize;
+
+    // Key identifying everything the player in turn knows at this state
+    fn info_set(&self, state: &Self::State) -> String;
+
+    // Labels of the actions available at a decision node
+    fn actions(&self, state: &Self::State) -> Vec<String>;
+
+    // Applies the action with the given index in the list returned by actions()
+    fn apply(&self, state: &Self::State, action: usize) -> Self::State;
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CfrVariant{
+    Vanilla,
+    Plus, // Regrets are floored at zero and the average strategy is weighted linearly
+}
+
+// Accumulated regrets and strategies of a single information set
+#[derive(Debug, Clone)]
+struct InfoSetNode{
+    actions: Vec<String>,
+    regret_sum: Vec<f64>,
+    strategy_sum: Vec<f64>,
+    regret_delta: Vec<f64>, // Regrets of the ongoing traversal. The strategy must not change during a traversal.
+}
+
+impl InfoSetNode{
+    fn new(actions: Vec<String>) -> InfoSetNode{
+        let n = actions.len();
+        InfoSetNode{actions, regret_sum: vec![0.0; n], strategy_sum: vec![0.0; n], regret_delta: vec![0.0; n]}
+    }
+
+    // Regret matching
+    fn current_strategy(&self) -> Vec<f64>{
+        let positive: Vec<f64> = self.regret_sum.iter().map(|r| r.max(0.0)).collect();
+        normalize_or_uniform(&positive)
+    }
+
+    fn average_strategy(&self) -> Vec<f64>{
+        normalize_or_uniform(&self.strategy_sum)
+    }
+}
+
+fn normalize_or_uniform(weights: &[f64]) -> Vec<f64>{
+    let total: f64 = weights.iter().sum();
+    if total > 0.0 {
+        weights.iter().map(|w| w / total).collect()
+    } else {
+        vec![1.0 / weights.len() as f64; weights.len()]
+    }
+}
+
+pub struct CfrSolver<G: CfrGame>{
+    game: G,
+    variant: CfrVariant,
+    nodes: HashMap<String, InfoSetNode>,
+    iterations: u64,
+}
+
+impl<G: CfrGame> CfrSolver<G>{
+
+    pub fn new(game: G, variant: CfrVariant) -> CfrSolver<G>{
+        CfrSolver{game, variant, nodes: HashMap::new(), iterations: 0}
+    }
+
+    pub fn game(&self) -> &G{
+        &self.game
+    }
+
+    pub fn iterations(&self) -> u64{
+        self.iterations
+    }
+
+    // Runs the given number of iterations. Each iteration updates the regrets of
+    // player 0 first and then the regrets of player 1 (alternating updates).
+    pub fn train(&mut self, iterations: u64){
+        for _ in 0..iterations{
+            self.iterations += 1;
+            let root = self.game.root();
+            for traverser in 0..2{
+                self.cfr(&root, traverser, 1.0, 1.0);
+                self.apply_regret_deltas();
+            }
+        }
+    }
+
+    fn apply_regret_deltas(&mut self){
+        for node in self.nodes.values_mut(){
+            for i in 0..node.regret_sum.len(){
+                node.regret_sum[i] += node.regret_delta[i];
+                node.regret_delta[i] = 0.0;
+                if self.variant == CfrVariant::Plus {
+                    node.regret_sum[i] = node.regret_sum[i].max(0.0);
+                }
+            }
+        }
+    }
+
+    // Returns the counterfactual value of the state for the traverser. own_reach is the
+    // probability that the traverser plays to this state, other_reach is the probability
+    // contribution of the opponent and chance.
+    fn cfr(&mut self, state: &G::State, traverser: usize, own_reach: f64, other_reach: f64) -> f64{
+
+        if self.game.is_terminal(state){
+            let payoff = self.game.payoff(state);
+            return if traverser == 0 { payoff } else { -payoff };
+        }
+
+        if let Some(outcomes) = self.game.chance_outcomes(state){
+            return outcomes.iter()
+                .map(|(child, prob)| prob * self.cfr(child, traverser, own_reach, other_reach * prob))
+                .sum();
+        }
+
+        let player = self.game.current_player(state);
+        let key = self.game.info_set(state);
+        let strategy = match self.nodes.get(&key){
+            Some(node) => node.current_strategy(),
+            None => {
+                let node = InfoSetNode::new(self.game.actions(state));
+                let strategy = node.current_strategy();
+                self.nodes.insert(key.clone(), node);
+                strategy
+            }
+        };
+
+        if player != traverser {
+            let mut value = 0.0;
+            for (i, prob) in strategy.iter().enumerate(){
+                if *prob > 0.0 {
+                    let child = self.game.apply(state, i);
+                    value += prob * self.cfr(&child, traverser, own_reach, other_reach * prob);
+                }
+            }
+            return value;
+        }
+
+        let mut action_values = vec![0.0; strategy.len()];
+        for (i, prob) in strategy.iter().enumerate(){
+            let child = self.game.apply(state, i);
+            action_values[i] = self.cfr(&child, traverser, own_reach * prob, other_reach);
+        }
+        let node_value: f64 = strategy.iter().zip(action_values.iter()).map(|(p, v)| p * v).sum();
+
+        let weight = match self.variant{
+            CfrVariant::Vanilla => 1.0,
+            CfrVariant::Plus => self.iterations as f64,
+        };
+
+        let node = self.nodes.get_mut(&key).unwrap();
+        for i in 0..strategy.len(){
+            node.regret_delta[i] += other_reach * (action_values[i] - node_value);
+            node.strategy_sum[i] += weight * own_reach * strategy[i];
+        }
+
+        node_value
+    }
+
+    // The average strategy over all iterations so far. This is the strategy that
+    // converges to an equilibrium.
+    pub fn average_strategy(&self) -> Strategy{
+        let mut info_sets = BTreeMap::new();
+        for (key, node) in self.nodes.iter(){
+            let probs = node.average_strategy();
+            info_sets.insert(key.clone(), node.actions.iter().cloned().zip(probs).collect());
+        }
+        Strategy{info_sets}
+    }
+}
+
+// A strategy profile mapping each information set to a probability for every action label
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Strategy{
+    pub info_sets: BTreeMap<String, Vec<(String, f64)>>,
+}
+
+impl Strategy{
+
+    pub fn get(&self, info_set: &str) -> Option<&Vec<(String, f64)>>{
+        self.info_sets.get(info_set)
+    }
+
+    // Probability of the given action at the given info set, if the info set is known
+    pub fn probability(&self, info_set: &str, action: &str) -> Option<f64>{
+        self.get(info_set)?.iter().find(|(label, _)| label == action).map(|(_, p)| *p)
+    }
+
+    // Samples an action label from the distribution at the info set
+    pub fn sample<R: Rng>(&self, info_set: &str, rng: &mut R) -> Option<String>{
+        let probs = self.get(info_set)?;
+        let mut x: f64 = rng.gen();
+        for (label, p) in probs.iter(){
+            if x < *p {
+                return Some(label.clone());
+            }
+            x -= p;
+        }
+        probs.last().map(|(label, _)| label.clone())
+    }
+
+    // Probabilities of the actions at the state, in the order of CfrGame::actions.
+    // Unknown info sets are played uniformly at random.
+    fn action_probabilities<G: CfrGame>(&self, game: &G, state: &G::State) -> Vec<f64>{
+        let actions = game.actions(state);
+        match self.get(&game.info_set(state)){
+            Some(probs) => actions.iter()
+                .map(|a| probs.iter().find(|(label, _)| label == a).map(|(_, p)| *p).unwrap_or(0.0))
+                .collect(),
+            None => vec![1.0 / actions.len() as f64; actions.len()],
+        }
+    }
+
+    pub fn to_json(&self) -> String{
+        serde_json::to_string(self).unwrap()
+    }
+
+    pub fn from_json(json: &str) -> serde_json::Result<Strategy>{
+        serde_json::from_str(json)
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()>{
+        fs::write(path, self.to_json())
+    }
+
+    pub fn load(path: &Path) -> io::Result<Strategy>{
+        let json = fs::read_to_string(path)?;
+        Strategy::from_json(&json).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+}
+
+// Expected payoff to player 0 when both players follow the strategy
+pub fn expected_value<G: CfrGame>(game: &G, strategy: &Strategy) -> f64{
+    fn value<G: CfrGame>(game: &G, strategy: &Strategy, state: &G::State) -> f64{
+        if game.is_terminal(state){
+            return game.payoff(state);
+        }
+        if let Some(outcomes) = game.chance_outcomes(state){
+            return outcomes.iter().map(|(child, prob)| prob * value(game, strategy, child)).sum();
+        }
+        strategy.action_probabilities(game, state).iter().enumerate()
+            .filter(|(_, p)| **p > 0.0)
+            .map(|(i, p)| p * value(game, strategy, &game.apply(state, i)))
+            .sum()
+    }
+    value(game, strategy, &game.root())
+}
+
+// Value of a best response of the given player against the strategy of the opponent,
+// from the point of view of the responding player.
+pub fn best_response_value<G: CfrGame>(game: &G, strategy: &Strategy, responder: usize) -> f64{
+
+    // First collect every state of each responder info set together with the probability
+    // that chance and the opponent reach it.
+    fn collect<G: CfrGame>(game: &G, strategy: &Strategy, responder: usize, state: &G::State, reach: f64,
+                           info_sets: &mut HashMap<String, Vec<(G::State, f64)>>){
+        if game.is_terminal(state){
+            return;
+        }
+        if let Some(outcomes) = game.chance_outcomes(state){
+            for (child, prob) in outcomes.iter(){
+                collect(game, strategy, responder, child, reach * prob, info_sets);
+            }
+            return;
+        }
+        let n = game.actions(state).len();
+        if game.current_player(state) == responder {
+            info_sets.entry(game.info_set(state)).or_default().push((state.clone(), reach));
+            for i in 0..n{
+                collect(game, strategy, responder, &game.apply(state, i), reach, info_sets);
+            }
+        } else {
+            for (i, p) in strategy.action_probabilities(game, state).iter().enumerate(){
+                if *p > 0.0 {
+                    collect(game, strategy, responder, &game.apply(state, i), reach * p, info_sets);
+                }
+            }
+        }
+    }
+
+    struct BestResponse<'a, G: CfrGame>{
+        game: &'a G,
+        strategy: &'a Strategy,
+        responder: usize,
+        info_sets: HashMap<String, Vec<(G::State, f64)>>,
+        chosen: HashMap<String, usize>,
+    }
+
+    impl<G: CfrGame> BestResponse<'_, G>{
+        fn value(&mut self, state: &G::State) -> f64{
+            if self.game.is_terminal(state){
+                let payoff = self.game.payoff(state);
+                return if self.responder == 0 { payoff } else { -payoff };
+            }
+            if let Some(outcomes) = self.game.chance_outcomes(state){
+                return outcomes.iter().map(|(child, prob)| prob * self.value(child)).sum();
+            }
+            if self.game.current_player(state) == self.responder {
+                let action = self.best_action(&self.game.info_set(state));
+                let child = self.game.apply(state, action);
+                return self.value(&child);
+            }
+            let probs = self.strategy.action_probabilities(self.game, state);
+            let mut value = 0.0;
+            for (i, p) in probs.iter().enumerate(){
+                if *p > 0.0 {
+                    value += p * self.value(&self.game.apply(state, i));
+                }
+            }
+            value
+        }
+
+        // The action maximizing the reach-weighted value over all states of the info set
+        fn best_action(&mut self, key: &str) -> usize{
+            if let Some(action) = self.chosen.get(key){
+                return *action;
+            }
+            let states = self.info_sets.get(key).cloned().unwrap_or_default();
+            let n = match states.first(){
+                Some((state, _)) => self.game.actions(state).len(),
+                None => return 0,
+            };
+            let mut best = (0, f64::NEG_INFINITY);
+            for action in 0..n{
+                let total: f64 = states.iter()
+                    .map(|(state, reach)| reach * self.value(&self.game.apply(state, action)))
+                    .sum();
+                if total > best.1 {
+                    best = (action, total);
+                }
+            }
+            self.chosen.insert(key.to_string(), best.0);
+            best.0
+        }
+    }
+
+    let root = game.root();
+    let mut info_sets = HashMap::new();
+    collect(game, strategy, responder, &root, 1.0, &mut info_sets);
+    let mut br = BestResponse{game, strategy, responder, info_sets, chosen: HashMap::new()};
+    br.value(&root)
+}
+
+// Average gain of the two best responders against the strategy. Zero at a Nash equilibrium.
+pub fn exploitability<G: CfrGame>(game: &G, strategy: &Strategy) -> f64{
+    (best_response_value(game, strategy, 0) + best_response_value(game, strategy, 1)) / 2.0
+}
+
+#[cfg(test)]
+mod tests{
+
+    use super::*;
+
+    // Kuhn poker: three cards, one card each, ante 1, one bet of size 1.
+    struct Kuhn;
+
+    #[derive(Clone)]
+    struct KuhnState{
+        cards: Option<(u8, u8)>,
+        history: String, // 'p' = pass, 'b' = bet
+    }
+
+    impl CfrGame for Kuhn{
+        type State = KuhnState;
+
+        fn root(&self) -> KuhnState{
+            KuhnState{cards: None, history: String::new()}
+        }
+
+        fn is_terminal(&self, state: &KuhnState) -> bool{
+            matches!(state.history.as_str(), "pp" | "bp" | "bb" | "pbp" | "pbb")
+        }
+
+        fn payoff(&self, state: &KuhnState) -> f64{
+            let (c0, c1) = state.cards.unwrap();
+            let showdown = if c0 > c1 { 1.0 } else { -1.0 };
+            match state.history.as_str(){
+                "pp" => showdown,
+                "bb" | "pbb" => 2.0 * showdown,
+                "bp" => 1.0,
+                "pbp" => -1.0,
+                _ => panic!("Not a terminal state"),
+            }
+        }
+
+        fn chance_outcomes(&self, state: &KuhnState) -> Option<Vec<(KuhnState, f64)>>{
+            if state.cards.is_some() {
+                return None;
+            }
+            let mut outcomes = Vec::new();
+            for c0 in 0..3{
+                for c1 in 0..3{
+                    if c0 != c1 {
+                        outcomes.push((KuhnState{cards: Some((c0, c1)), history: String::new()}, 1.0 / 6.0));
+                    }
+                }
+            }
+            Some(outcomes)
+        }
+
+        fn current_player(&self, state: &KuhnState) -> usize{
+            state.history.len() % 2
+        }
+
+        fn info_set(&self, state: &KuhnState) -> String{
+            let (c0, c1) = state.cards.unwrap();
+            let card = if self.current_player(state) == 0 { c0 } else { c1 };
+            format!("{}{}", card, state.history)
+        }
+
+        fn actions(&self, _state: &KuhnState) -> Vec<String>{
+            vec!["p".to_string(), "b".to_string()]
+        }
+
+        fn apply(&self, state: &KuhnState, action: usize) -> KuhnState{
+            let mut next = state.clone();
+            next.history.push(if action == 0 { 'p' } else { 'b' });
+            next
+        }
+    }
+
+    // Leduc poker: six cards (two suits of J, Q, K), ante 1, two limit betting rounds with
+    // bet sizes 2 and 4, at most two raises per round and one public card after the first round.
+    struct Leduc;
+
+    #[derive(Clone)]
+    struct LeducState{
+        private: Option<(u8, u8)>, // Ranks 0..3
+        public: Option<u8>,
+        rounds: Vec<String>, // Actions of each round: 'c' = check/call, 'r' = bet/raise, 'f' = fold
+    }
+
+    impl Leduc{
+        fn round_closed(round: &str) -> bool{
+            round.ends_with('f') || (round.len() >= 2 && round.ends_with('c'))
+        }
+
+        // Chips put in by each player, including the ante
+        fn contributions(state: &LeducState) -> [f64; 2]{
+            let mut contributions = [1.0, 1.0];
+            for (i, round) in state.rounds.iter().enumerate(){
+                let size = if i == 0 { 2.0 } else { 4.0 };
+                let mut player = 0;
+                for action in round.chars(){
+                    let other = contributions[1 - player];
+                    match action{
+                        'c' => contributions[player] = other,
+                        'r' => contributions[player] = other + size,
+                        _ => (),
+                    }
+                    player = 1 - player;
+                }
+            }
+            contributions
+        }
+    }
+
+    impl CfrGame for Leduc{
+        type State = LeducState;
+
+        fn root(&self) -> LeducState{
+            LeducState{private: None, public: None, rounds: vec![String::new()]}
+        }
+
+        fn is_terminal(&self, state: &LeducState) -> bool{
+            let last = state.rounds.last().unwrap();
+            last.ends_with('f') || (state.rounds.len() == 2 && Leduc::round_closed(last))
+        }
+
+        fn payoff(&self, state: &LeducState) -> f64{
+            let contributions = Leduc::contributions(state);
+            let last = state.rounds.last().unwrap();
+            if last.ends_with('f') {
+                // The player who folded is the one who acted last
+                let folder = (last.len() - 1) % 2;
+                return if folder == 0 { -contributions[0] } else { contributions[1] };
+            }
+            let (p0, p1) = state.private.unwrap();
+            let public = state.public.unwrap();
+            let strength = |card: u8| if card == public { 10 + card } else { card };
+            match strength(p0).cmp(&strength(p1)){
+                std::cmp::Ordering::Greater => contributions[1],
+                std::cmp::Ordering::Less => -contributions[0],
+                std::cmp::Ordering::Equal => 0.0,
+            }
+        }
+
+        fn chance_outcomes(&self, state: &LeducState) -> Option<Vec<(LeducState, f64)>>{
+            let deck = [0, 0, 1, 1, 2, 2];
+            match state.private{
+                None => {
+                    let mut outcomes = Vec::new();
+                    for i in 0..6{
+                        for j in 0..6{
+                            if i != j {
+                                let mut next = state.clone();
+                                next.private = Some((deck[i], deck[j]));
+                                outcomes.push((next, 1.0 / 30.0));
+                            }
+                        }
+                    }
+                    Some(outcomes)
+                },
+                Some((p0, p1)) => {
+                    if state.public.is_some() || !Leduc::round_closed(state.rounds.last().unwrap()) {
+                        return None;
+                    }
+                    let mut remaining = deck.to_vec();
+                    for card in [p0, p1]{
+                        let pos = remaining.iter().position(|c| *c == card).unwrap();
+                        remaining.remove(pos);
+                    }
+                    Some(remaining.iter().map(|card| {
+                        let mut next = state.clone();
+                        next.public = Some(*card);
+                        next.rounds.push(String::new());
+                        (next, 0.25)
+                    }).collect())
+                }
+            }
+        }
+
+        fn current_player(&self, state: &LeducState) -> usize{
+            state.rounds.last().unwrap().len() % 2
+        }
+
+        fn info_set(&self, state: &LeducState) -> String{
+            let (p0, p1) = state.private.unwrap();
+            let card = if self.current_player(state) == 0 { p0 } else { p1 };
+            let public = state.public.map(|c| c.to_string()).unwrap_or_default();
+            format!("{}{}:{}", card, public, state.rounds.join("/"))
+        }
+
+        fn actions(&self, state: &LeducState) -> Vec<String>{
+            let round = state.rounds.last().unwrap();
+            let raises = round.chars().filter(|c| *c == 'r').count();
+            let mut actions = Vec::new();
+            if raises > 0 {
+                actions.push("f".to_string());
+            }
+            actions.push("c".to_string());
+            if raises < 2 {
+                actions.push("r".to_string());
+            }
+            actions
+        }
+
+        fn apply(&self, state: &LeducState, action: usize) -> LeducState{
+            let label = self.actions(state)[action].clone();
+            let mut next = state.clone();
+            next.rounds.last_mut().unwrap().push_str(&label);
+            next
+        }
+    }
+
+    #[test]
+    fn test_kuhn_converges_to_equilibrium(){
+        for variant in [CfrVariant::Vanilla, CfrVariant::Plus]{
+            let mut solver = CfrSolver::new(Kuhn, variant);
+            solver.train(5000);
+            let strategy = solver.average_strategy();
+
+            // The game value of Kuhn poker for the first player is -1/18
+            let value = expected_value(&Kuhn, &strategy);
+            assert!((value + 1.0 / 18.0).abs() < 0.005, "value was {}", value);
+            assert!(exploitability(&Kuhn, &strategy) < 0.01);
+
+            // The second player always bets with the king after a pass and never calls with the jack
+            assert!(strategy.probability("2p", "b").unwrap() > 0.99);
+            assert!(strategy.probability("0b", "b").unwrap() < 0.01);
+        }
+    }
+
+    #[test]
+    fn test_leduc_converges(){
+        let mut solver = CfrSolver::new(Leduc, CfrVariant::Plus);
+        solver.train(100);
+        let strategy = solver.average_strategy();
+
+        // The game value of Leduc poker for the first player is about -0.0856
+        let value = expected_value(&Leduc, &strategy);
+        assert!((value + 0.0856).abs() < 0.005, "value was {}", value);
+        assert!(exploitability(&Leduc, &strategy) < 0.02);
+    }
+
+    #[test]
+    fn test_strategy_export_round_trip(){
+        let mut solver = CfrSolver::new(Kuhn, CfrVariant::Plus);
+        solver.train(100);
+        let strategy = solver.average_strategy();
+
+        let path = std::env::temp_dir().join(format!("kuhn_strategy_{}.json", std::process::id()));
+        strategy.save(&path).unwrap();
+        let loaded = Strategy::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        // Floats may lose their last bit in the JSON round trip
+        assert_eq!(loaded.info_sets.len(), 12);
+        for (key, probs) in strategy.info_sets.iter(){
+            for (label, p) in probs{
+                assert!((loaded.probability(key, label).unwrap() - p).abs() < 1e-12);
+            }
+        }
+    }
+}
@@ -1,4 +1,5 @@
 use serde::{Serialize, Deserialize};
+use std::time::{SystemTime, UNIX_EPOCH};
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub enum Position{
@@ -12,3 +13,87 @@ pub fn other_player(player: Position) -> Position{
         Position::BigBlind => Position::Button,
     }
 }
+
+// Seconds since the Unix epoch
+pub fn unix_time() -> u64{
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+// Serde helpers for poker::Card, which does not implement Serialize. Cards are
+// written as rank-suit strings like "As". Use with #[serde(with = "...")].
+pub mod card_serde{
+    use poker::Card;
+    use serde::{Deserialize, Deserializer, Serializer, de::Error};
+
+    pub fn to_string(card: &Card) -> String{
+        card.rank_suit_string()
+    }
+
+    pub fn from_str<E: Error>(s: &str) -> Result<Card, E>{
+        s.parse::<Card>().map_err(|e| E::custom(format!("invalid card {}: {}", s, e)))
+    }
+
+    pub fn serialize<S: Serializer>(card: &Card, serializer: S) -> Result<S::Ok, S::Error>{
+        serializer.serialize_str(&to_string(card))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Card, D::Error>{
+        from_str(&String::deserialize(deserializer)?)
+    }
+
+    pub mod pair{
+        use poker::Card;
+        use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+        pub fn serialize<S: Serializer>(cards: &(Card, Card), serializer: S) -> Result<S::Ok, S::Error>{
+            (super::to_string(&cards.0), super::to_string(&cards.1)).serialize(serializer)
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<(Card, Card), D::Error>{
+            let (a, b) = <(String, String)>::deserialize(deserializer)?;
+            Ok((super::from_str(&a)?, super::from_str(&b)?))
+        }
+    }
+
+    pub mod option{
+        use poker::Card;
+        use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+        pub fn serialize<S: Serializer>(card: &Option<Card>, serializer: S) -> Result<S::Ok, S::Error>{
+            card.as_ref().map(super::to_string).serialize(serializer)
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Card>, D::Error>{
+            Option::<String>::deserialize(deserializer)?.map(|s| super::from_str(&s)).transpose()
+        }
+    }
+
+    pub mod option_pair{
+        use poker::Card;
+        use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+        pub fn serialize<S: Serializer>(cards: &Option<(Card, Card)>, serializer: S) -> Result<S::Ok, S::Error>{
+            cards.map(|cards| (super::to_string(&cards.0), super::to_string(&cards.1))).serialize(serializer)
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<(Card, Card)>, D::Error>{
+            match Option::<(String, String)>::deserialize(deserializer)?{
+                Some((a, b)) => Ok(Some((super::from_str(&a)?, super::from_str(&b)?))),
+                None => Ok(None),
+            }
+        }
+    }
+
+    pub mod vec{
+        use poker::Card;
+        use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+        pub fn serialize<S: Serializer>(cards: &[Card], serializer: S) -> Result<S::Ok, S::Error>{
+            cards.iter().map(super::to_string).collect::<Vec<String>>().serialize(serializer)
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<Card>, D::Error>{
+            Vec::<String>::deserialize(deserializer)?.iter().map(|s| super::from_str(s)).collect()
+        }
+    }
+}
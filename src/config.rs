@@ -0,0 +1,298 @@
+use crate::pubsub::DEFAULT_REPLAY_EVENTS;
+use clap::Parser;
+use rust_poker::game::{ChatSettings, SpectatorDelay};
+use serde::Deserialize;
+use warp::http::Uri;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::PathBuf;
+
+// Server configuration. The defaults are overridden by the TOML config file, which is
+// overridden by environment variables, which are overridden by command-line flags.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub bind_address: SocketAddr,
+    pub public_url: Option<String>, // Base URL of the server as the clients see it, e.g. behind a proxy
+    pub cors_origins: Vec<String>, // Like https://example.com, any origin is allowed if empty
+    pub data_dir: PathBuf, // Game logs
+    pub defaults: GameDefaults,
+    pub limits: Limits,
+}
+
+// Used for the settings missing from a create_game request
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct GameDefaults {
+    pub sb_size: u64,
+    pub stack: u64,
+    pub allow_undo: bool,
+    pub spectator_delay: Option<SpectatorDelay>,
+    pub chat: ChatSettings,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Limits {
+    pub max_games: usize,
+    pub max_stack: u64,
+    pub max_spectators_per_game: usize,
+    pub idle_timeout_minutes: u64, // Games in which nobody acts for this long are closed
+    pub replay_events: usize, // Latest events of each game kept for the clients that reconnect
+}
+
+#[derive(Parser, Debug)]
+#[command(name = "server", about = "Heads up poker server")]
+pub struct Args {
+    /// TOML config file
+    #[arg(long, env = "POKER_CONFIG")]
+    pub config: Option<PathBuf>,
+    #[arg(long, env = "POKER_BIND_ADDRESS")]
+    pub bind_address: Option<SocketAddr>,
+    #[arg(long, env = "POKER_PUBLIC_URL")]
+    pub public_url: Option<String>,
+    /// Allowed CORS origin, can be repeated. Comma-separated in the environment variable.
+    #[arg(long = "cors-origin", env = "POKER_CORS_ORIGINS", value_delimiter = ',')]
+    pub cors_origins: Vec<String>,
+    #[arg(long, env = "POKER_DATA_DIR")]
+    pub data_dir: Option<PathBuf>,
+    /// Small blind of new games
+    #[arg(long, env = "POKER_SB_SIZE")]
+    pub sb_size: Option<u64>,
+    /// Starting stack of both seats in new games
+    #[arg(long, env = "POKER_STACK")]
+    pub stack: Option<u64>,
+    #[arg(long, env = "POKER_ALLOW_UNDO")]
+    pub allow_undo: Option<bool>,
+    /// Like hands:2 or minutes:5
+    #[arg(long, env = "POKER_SPECTATOR_DELAY", value_parser = parse_spectator_delay)]
+    pub spectator_delay: Option<SpectatorDelay>,
+    #[arg(long, env = "POKER_CHAT_MAX_LENGTH")]
+    pub chat_max_length: Option<usize>,
+    #[arg(long, env = "POKER_CHAT_MESSAGES_PER_MINUTE")]
+    pub chat_messages_per_minute: Option<usize>,
+    #[arg(long, env = "POKER_CHAT_TO_SPECTATORS")]
+    pub chat_to_spectators: Option<bool>,
+    #[arg(long, env = "POKER_MAX_GAMES")]
+    pub max_games: Option<usize>,
+    #[arg(long, env = "POKER_MAX_STACK")]
+    pub max_stack: Option<u64>,
+    #[arg(long, env = "POKER_MAX_SPECTATORS_PER_GAME")]
+    pub max_spectators_per_game: Option<usize>,
+    #[arg(long, env = "POKER_IDLE_TIMEOUT_MINUTES")]
+    pub idle_timeout_minutes: Option<u64>,
+    #[arg(long, env = "POKER_REPLAY_EVENTS")]
+    pub replay_events: Option<usize>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            bind_address: ([127, 0, 0, 1], 8000).into(),
+            public_url: None,
+            cors_origins: Vec::new(),
+            data_dir: PathBuf::from("data"),
+            defaults: GameDefaults::default(),
+            limits: Limits::default(),
+        }
+    }
+}
+
+impl Default for GameDefaults {
+    fn default() -> Self {
+        GameDefaults { sb_size: 5, stack: 1000, allow_undo: false, spectator_delay: None, chat: ChatSettings::default() }
+    }
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_games: 1000,
+            max_stack: 1_000_000_000,
+            max_spectators_per_game: 20,
+            idle_timeout_minutes: 24 * 60,
+            replay_events: DEFAULT_REPLAY_EVENTS,
+        }
+    }
+}
+
+impl Config {
+
+    // Reads the config file named in the arguments, if any, and applies the arguments on top
+    pub fn load(args: Args) -> Result<Config, String> {
+        let config = match &args.config {
+            Some(path) => {
+                let text = std::fs::read_to_string(path).map_err(|e| format!("could not read {}: {}", path.display(), e))?;
+                toml::from_str(&text).map_err(|e| format!("invalid config file {}: {}", path.display(), e))?
+            },
+            None => Config::default(),
+        };
+        let config = config.with_args(args);
+        for origin in &config.cors_origins {
+            check_origin(origin)?;
+        }
+        Ok(config)
+    }
+
+    fn with_args(mut self, args: Args) -> Config {
+        if let Some(bind_address) = args.bind_address {
+            self.bind_address = bind_address;
+        }
+        if let Some(public_url) = args.public_url {
+            self.public_url = Some(public_url);
+        }
+        if !args.cors_origins.is_empty() {
+            self.cors_origins = args.cors_origins;
+        }
+        if let Some(data_dir) = args.data_dir {
+            self.data_dir = data_dir;
+        }
+        if let Some(sb_size) = args.sb_size {
+            self.defaults.sb_size = sb_size;
+        }
+        if let Some(stack) = args.stack {
+            self.defaults.stack = stack;
+        }
+        if let Some(allow_undo) = args.allow_undo {
+            self.defaults.allow_undo = allow_undo;
+        }
+        if let Some(spectator_delay) = args.spectator_delay {
+            self.defaults.spectator_delay = Some(spectator_delay);
+        }
+        if let Some(max_length) = args.chat_max_length {
+            self.defaults.chat.max_length = max_length;
+        }
+        if let Some(messages_per_minute) = args.chat_messages_per_minute {
+            self.defaults.chat.messages_per_minute = messages_per_minute;
+        }
+        if let Some(spectators) = args.chat_to_spectators {
+            self.defaults.chat.spectators = spectators;
+        }
+        if let Some(max_games) = args.max_games {
+            self.limits.max_games = max_games;
+        }
+        if let Some(max_stack) = args.max_stack {
+            self.limits.max_stack = max_stack;
+        }
+        if let Some(max_spectators_per_game) = args.max_spectators_per_game {
+            self.limits.max_spectators_per_game = max_spectators_per_game;
+        }
+        if let Some(idle_timeout_minutes) = args.idle_timeout_minutes {
+            self.limits.idle_timeout_minutes = idle_timeout_minutes;
+        }
+        if let Some(replay_events) = args.replay_events {
+            self.limits.replay_events = replay_events;
+        }
+        self
+    }
+
+    // The public URL, or the bind address if there is none. An address that binds all interfaces
+    // is reached through the loopback interface.
+    pub fn public_url(&self) -> String {
+        if let Some(public_url) = &self.public_url {
+            return public_url.clone();
+        }
+        let mut address = self.bind_address;
+        if address.ip().is_unspecified() {
+            address.set_ip(match address {
+                SocketAddr::V4(_) => Ipv4Addr::LOCALHOST.into(),
+                SocketAddr::V6(_) => Ipv6Addr::LOCALHOST.into(),
+            });
+        }
+        format!("http://{}", address)
+    }
+
+    // Websocket URL for a path on the public URL, e.g. https://example.com/poker -> wss://example.com/poker/ws/...
+    pub fn ws_url(&self, path: &str) -> String {
+        let public_url = self.public_url();
+        let base = public_url.trim_end_matches('/');
+        let base = match base.split_once("://") {
+            Some(("https", rest)) => format!("wss://{}", rest),
+            Some((_, rest)) => format!("ws://{}", rest),
+            None => format!("ws://{}", base),
+        };
+        format!("{}/{}", base, path.trim_start_matches('/'))
+    }
+}
+
+// Spectator delay flag, like hands:2 or minutes:5
+fn parse_spectator_delay(text: &str) -> Result<SpectatorDelay, String> {
+    let invalid = || format!("invalid spectator delay {}, expected hands:N or minutes:N", text);
+    let (unit, amount) = text.split_once(':').ok_or_else(invalid)?;
+    let amount = amount.trim().parse::<u64>().map_err(|_| invalid())?;
+    match unit.trim() {
+        "hands" => Ok(SpectatorDelay::Hands(amount)),
+        "minutes" => Ok(SpectatorDelay::Minutes(amount)),
+        _ => Err(invalid()),
+    }
+}
+
+// Checks that the CORS origin is a scheme and a host, as warp panics on anything else
+fn check_origin(origin: &str) -> Result<(), String> {
+    let uri = match origin.contains("://") {
+        true => origin.parse::<Uri>().ok(),
+        false => None,
+    };
+    match uri {
+        Some(uri) if uri.scheme().is_some() && uri.authority().is_some() && uri.path_and_query().is_none_or(|path| path == "/") => Ok(()),
+        _ => Err(format!("invalid CORS origin {}, expected a scheme and a host like https://example.com", origin)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_file_and_flags() {
+        let config: Config = toml::from_str(include_str!("../server.example.toml")).unwrap();
+        assert_eq!(config.bind_address, "0.0.0.0:8080".parse().unwrap());
+        assert_eq!(config.defaults.spectator_delay, Some(SpectatorDelay::Hands(2)));
+        assert_eq!(config.limits.max_spectators_per_game, Limits::default().max_spectators_per_game); // Not in the file
+        assert_eq!(config.defaults.chat, ChatSettings { max_length: 140, ..ChatSettings::default() });
+
+        let args = Args::try_parse_from(["server", "--bind-address", "127.0.0.1:9000",
+            "--cors-origin", "https://a.example.com", "--cors-origin", "https://b.example.com"]).unwrap();
+        let config = config.with_args(args);
+        assert_eq!(config.bind_address, "127.0.0.1:9000".parse().unwrap());
+        assert_eq!(config.cors_origins, vec!["https://a.example.com", "https://b.example.com"]);
+        assert_eq!(config.public_url.as_deref(), Some("https://poker.example.com/hu/")); // From the file
+
+        assert!(toml::from_str::<Config>("bind_adress = \"0.0.0.0:80\"").is_err());
+
+        // Game defaults and limits have flags too
+        let args = Args::try_parse_from(["server", "--sb-size", "25", "--stack", "5000", "--allow-undo", "true",
+            "--spectator-delay", "minutes:5", "--chat-max-length", "80", "--chat-to-spectators", "true",
+            "--max-stack", "10000", "--max-spectators-per-game", "3", "--replay-events", "50"]).unwrap();
+        let config = config.with_args(args);
+        assert_eq!((config.defaults.sb_size, config.defaults.stack, config.defaults.allow_undo), (25, 5000, true));
+        assert_eq!(config.defaults.spectator_delay, Some(SpectatorDelay::Minutes(5)));
+        assert_eq!(config.defaults.chat, ChatSettings { max_length: 80, spectators: true, ..ChatSettings::default() });
+        assert_eq!((config.limits.max_stack, config.limits.max_spectators_per_game, config.limits.replay_events), (10000, 3, 50));
+        assert!(Args::try_parse_from(["server", "--spectator-delay", "5"]).is_err());
+    }
+
+    #[test]
+    fn test_ws_url() {
+        let mut config = Config::default();
+        assert_eq!(config.ws_url("ws/abc"), "ws://127.0.0.1:8000/ws/abc");
+        config.bind_address = "0.0.0.0:9000".parse().unwrap();
+        assert_eq!(config.ws_url("ws/abc"), "ws://127.0.0.1:9000/ws/abc");
+        config.public_url = Some("https://poker.example.com/hu/".to_string());
+        assert_eq!(config.ws_url("/ws/abc"), "wss://poker.example.com/hu/ws/abc");
+    }
+
+    #[test]
+    fn test_cors_origins() {
+        for origin in ["https://poker.example.com", "http://localhost:3000", "https://poker.example.com/"] {
+            assert_eq!(check_origin(origin), Ok(()));
+            let _ = warp::cors().allow_origin(origin); // Does not panic
+        }
+        for origin in ["example.com", "https://", "https://example.com/hu", "*", "null"] {
+            assert!(check_origin(origin).is_err());
+        }
+
+        let args = Args::try_parse_from(["server", "--cors-origin", "https://a.example.com", "--cors-origin", "example.com"]).unwrap();
+        assert_eq!(Config::load(args).unwrap_err(), "invalid CORS origin example.com, expected a scheme and a host like https://example.com");
+    }
+}
@@ -0,0 +1,109 @@
+use std::sync::OnceLock;
+use poker::{Card, Evaluator};
+use rand::seq::SliceRandom;
+
+// Number of random runouts used when too many board cards are missing to enumerate them all
+const MONTE_CARLO_SAMPLES: usize = 5000;
+
+// Shared evaluator, because building the lookup tables is slow
+pub fn evaluator() -> &'static Evaluator{
+    static EVALUATOR: OnceLock<Evaluator> = OnceLock::new();
+    EVALUATOR.get_or_init(Evaluator::new)
+}
+
+// Returns the share of the pot each player wins on the given runout: 1, 0 or 0.5 for a split
+fn showdown_shares(hand1: (Card, Card), hand2: (Card, Card), board: &[Card]) -> (f64, f64){
+    let mut cards1 = vec![hand1.0, hand1.1];
+    cards1.extend_from_slice(board);
+    let mut cards2 = vec![hand2.0, hand2.1];
+    cards2.extend_from_slice(board);
+
+    let eval1 = evaluator().evaluate(&cards1).unwrap();
+    let eval2 = evaluator().evaluate(&cards2).unwrap();
+    if eval1.is_better_than(eval2) {
+        (1.0, 0.0)
+    } else if eval2.is_better_than(eval1) {
+        (0.0, 1.0)
+    } else {
+        (0.5, 0.5)
+    }
+}
+
+// Calls f with every way of choosing k cards from the slice
+fn for_each_combination(cards: &[Card], k: usize, chosen: &mut Vec<Card>, f: &mut impl FnMut(&[Card])){
+    if chosen.len() == k {
+        f(chosen);
+        return;
+    }
+    let needed = k - chosen.len();
+    for i in 0..cards.len(){
+        if cards.len() - i < needed {
+            break;
+        }
+        chosen.push(cards[i]);
+        for_each_combination(&cards[i+1..], k, chosen, f);
+        chosen.pop();
+    }
+}
+
+// Returns the pot equities of two hands given the known board cards. Ties count as half.
+// Runouts are enumerated exactly when at most two board cards are missing, otherwise sampled.
+pub fn heads_up_equity(hand1: (Card, Card), hand2: (Card, Card), board: &[Card]) -> (f64, f64){
+    let known = [hand1.0, hand1.1, hand2.0, hand2.1];
+    let remaining: Vec<Card> = Card::generate_deck()
+        .filter(|c| !known.contains(c) && !board.contains(c))
+        .collect();
+    let missing = 5 - board.len();
+
+    let mut total = (0.0, 0.0);
+    let mut runouts = 0;
+    let mut add_runout = |extra: &[Card]| {
+        let mut full_board = board.to_vec();
+        full_board.extend_from_slice(extra);
+        let (share1, share2) = showdown_shares(hand1, hand2, &full_board);
+        total.0 += share1;
+        total.1 += share2;
+        runouts += 1;
+    };
+
+    if missing <= 2 {
+        for_each_combination(&remaining, missing, &mut Vec::new(), &mut add_runout);
+    } else {
+        let mut deck = remaining.clone();
+        let mut rng = rand::thread_rng();
+        for _ in 0..MONTE_CARLO_SAMPLES{
+            let (sample, _) = deck.partial_shuffle(&mut rng, missing);
+            add_runout(sample);
+        }
+    }
+
+    (total.0 / runouts as f64, total.1 / runouts as f64)
+}
+
+#[cfg(test)]
+mod tests{
+
+    use super::*;
+    use poker::cards;
+
+    fn two(s: &str) -> (Card, Card){
+        let cards: Vec<Card> = cards!(s).try_collect().unwrap();
+        (cards[0], cards[1])
+    }
+
+    #[test]
+    fn test_turn_equity_is_exact(){
+        // Aces against a flush draw on the turn: 9 hearts out of 44 cards
+        let board: Vec<Card> = cards!("2h 7h Kc 3s").try_collect().unwrap();
+        let (aces, draw) = heads_up_equity(two("As Ad"), two("Qh Jh"), &board);
+        assert!((draw - 9.0 / 44.0).abs() < 1e-9);
+        assert!((aces + draw - 1.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_preflop_equity_is_estimated(){
+        let (aces, kings) = heads_up_equity(two("As Ad"), two("Kc Kd"), &[]);
+        assert!((aces - 0.82).abs() < 0.03);
+        assert!((aces + kings - 1.0).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,87 @@
+use std::fmt;
+use serde::{Serialize, Deserialize};
+
+use crate::common::Position;
+use crate::street::{Action, ActionOption};
+
+// Reasons why an action or a command was rejected. Serialized with an "error" tag
+// so that clients can match on the kind of the error.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "error", rename_all = "snake_case")]
+pub enum ActionError{
+    NotYourTurn{active_player: Position},
+    InvalidSeat{seat: u8},
+    HandOver, // Nobody can act, e.g. a player can not afford the blinds
+    ActionNotAvailable{action: Action, available_actions: Vec<ActionOption>},
+    ChoiceNotAvailable{choice: String, available_actions: Vec<ActionOption>}, // A helper like AllIn that does not fit the situation
+    WrongAmount{amount: u64, expected: u64}, // For calls and blinds, which have an exact amount
+    AmountBelowMinimum{amount: u64, minimum: u64, maximum: u64},
+    AmountAboveStack{amount: u64, minimum: u64, maximum: u64},
+    MalformedCommand{message: String},
+    AlreadyYourTurn, // Queueing an action is for while the opponent decides
+    NothingToUndo,
+    UndoNotAllowed, // Undo is not enabled for this game
+    GameNotFound{game_id: u64},
+    HandNotFound{hand_number: u64},
+    NothingToShow, // No hand has finished yet
+    SeatTaken{seat: u8}, // Claimed by someone else, rejoining needs the seat token
+    InvalidSeatToken{seat: u8},
+    InvalidInviteCode{seat: u8},
+    SpectatorCannotAct,
+    Unauthorized, // Needs the token of one of the seats
+    LimitReached{limit: String, maximum: u64},
+    ChatTooLong{length: usize, maximum: usize},
+    HandshakeRequired, // The first websocket message must be a hello
+    UnsupportedVersion{version: u32, supported: u32},
+}
+
+impl fmt::Display for ActionError{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result{
+        match self{
+            ActionError::NotYourTurn{active_player} => write!(f, "It is not your turn to act. Waiting for {:?}", active_player),
+            ActionError::InvalidSeat{seat} => write!(f, "Invalid seat {}", seat),
+            ActionError::HandOver => write!(f, "The hand is over"),
+            ActionError::ActionNotAvailable{action, ..} => write!(f, "Action {:?} is not available", action),
+            ActionError::ChoiceNotAvailable{choice, ..} => write!(f, "{} is not possible now", choice),
+            ActionError::WrongAmount{amount, expected} => write!(f, "Amount {} should be {}", amount, expected),
+            ActionError::AmountBelowMinimum{amount, minimum, ..} => write!(f, "Amount {} is below the minimum {}", amount, minimum),
+            ActionError::AmountAboveStack{amount, maximum, ..} => write!(f, "Amount {} is above the maximum {}", amount, maximum),
+            ActionError::MalformedCommand{message} => write!(f, "Malformed command: {}", message),
+            ActionError::AlreadyYourTurn => write!(f, "It is your turn already, act instead of queueing an action"),
+            ActionError::NothingToUndo => write!(f, "There is nothing to undo in this hand"),
+            ActionError::UndoNotAllowed => write!(f, "Undo is not enabled for this game"),
+            ActionError::GameNotFound{game_id} => write!(f, "Game {} not found", game_id),
+            ActionError::HandNotFound{hand_number} => write!(f, "Hand {} has not finished yet", hand_number),
+            ActionError::NothingToShow => write!(f, "No hand has finished yet"),
+            ActionError::SeatTaken{seat} => write!(f, "Seat {} is already taken", seat),
+            ActionError::InvalidSeatToken{seat} => write!(f, "Invalid token for seat {}", seat),
+            ActionError::InvalidInviteCode{seat} => write!(f, "Invalid invite code for seat {}", seat),
+            ActionError::SpectatorCannotAct => write!(f, "Spectators can not act"),
+            ActionError::Unauthorized => write!(f, "This needs the token of a seat in the game"),
+            ActionError::LimitReached{limit, maximum} => write!(f, "The limit of {} {} was reached", maximum, limit),
+            ActionError::ChatTooLong{length, maximum} => write!(f, "The chat message has {} characters, the maximum is {}", length, maximum),
+            ActionError::HandshakeRequired => write!(f, "Send a hello with the protocol version first"),
+            ActionError::UnsupportedVersion{version, supported} => write!(f, "Protocol version {} is not supported, the server speaks version {}", version, supported),
+        }
+    }
+}
+
+impl std::error::Error for ActionError {}
+
+// Response to a rejected command, sent to websocket clients
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ErrorResponse{
+    pub action_response: String, // Always "error"
+    pub error: ActionError,
+    pub message: String,
+}
+
+impl ErrorResponse{
+    pub fn new(error: ActionError) -> ErrorResponse{
+        ErrorResponse{action_response: "error".to_string(), message: error.to_string(), error}
+    }
+
+    pub fn to_json(&self) -> String{
+        serde_json::to_string(self).unwrap()
+    }
+}
@@ -1,12 +1,184 @@
-use crate::common::Position;
-use crate::street::{Action, ActionOption};
+use crate::common::{Position, card_serde, unix_time};
+use crate::street::{Action, ActionChoice, ActionOption};
+use crate::error::{ActionError, ErrorResponse};
 use crate::hand::{Hand, HandResult};
+use crate::session::{session_report, SessionReport};
+use crate::stats::{StatsReport, StatsTracker};
+use crate::street::Street;
 use serde::{Serialize, Deserialize};
-use poker::{Card, cards};
+use poker::Card;
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Game{
     current_hand: Hand,
     button_seat: u8, // 0 or 1
+    hand_history: Vec<HandRecord>,
+    stats: StatsTracker,
+    settings: GameSettings,
+    undo_requested_by: Option<u8>, // Seat waiting for the opponent to agree to an undo
+    #[serde(default)]
+    seats: [SeatAccess; 2],
+    #[serde(default)]
+    last_action_at: u64, // Seconds since the Unix epoch, or when the game was created
+    #[serde(default)]
+    chat: Vec<ChatMessage>,
+    #[serde(default)]
+    chat_muted: [bool; 2], // Seats that do not want to see the chat of their opponent
+    #[serde(default)]
+    pre_actions: [Option<PreAction>; 2], // Queued by each seat before its turn
+    #[serde(skip)]
+    events: Vec<GameEvent>, // Not yet taken with take_events
+}
+
+// Who may play a seat. The first player to join gets a secret token, which is needed to join
+// the seat again. If the seat has an invite code, the first join needs it.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SeatAccess{
+    invite_code: Option<String>,
+    token: Option<String>,
+}
+
+// Options chosen when the game is created
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct GameSettings{
+    pub allow_undo: bool, // Actions can be taken back if both seats agree
+    #[serde(default)]
+    pub spectator_delay: Option<SpectatorDelay>, // None shows spectators only the cards shown at showdown
+    #[serde(default)]
+    pub chat: ChatSettings,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ChatSettings{
+    pub max_length: usize, // In characters
+    pub messages_per_minute: usize, // Per seat
+    pub spectators: bool, // Spectators see the chat
+}
+
+// A chat message of a seat
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChatMessage{
+    pub seat: u8,
+    pub time: u64, // Seconds since the Unix epoch
+    pub text: String,
+}
+
+// How long spectators wait before they see all the cards of a finished hand
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SpectatorDelay{
+    Hands(u64), // Until this many more hands have finished
+    Minutes(u64),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GameStatus{
+    WaitingForPlayers, // A seat has not been claimed yet
+    Playing,
+    Finished, // A player has no chips left
+}
+
+// Hole cards a player turns up after the hand
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ShowCards{
+    First,
+    Second,
+    Both,
+}
+
+// A decision a seat queues before its turn. It is taken as soon as the turn comes, if it is
+// still possible then. A bet or raise of the opponent clears Check and Call, and a new street
+// or hand clears them all.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PreAction{
+    CheckOrFold,
+    Check,
+    CallAny, // Check if there is nothing to call, also after a raise
+    Call(u64), // Only a call of exactly this amount, *to* not *by*
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum UndoStatus{
+    Requested, // Waiting for the other seat to agree
+    Undone(Action),
+}
+
+// Changes to the game, in the order they happened. Replaying them on a copy of the
+// game reproduces it, including the decks of new hands.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum GameEvent{
+    Action{seat: u8, action: Action, time: u64},
+    UndoRequest{seat: u8},
+    Chat{message: ChatMessage},
+    ChatMuted{seat: u8, muted: bool},
+    HandFinished{result: Box<HandResult>},
+    CardsShown{seat: u8, cards: ShowCards},
+    PreActionQueued{seat: u8, pre_action: Option<PreAction>}, // None when cleared
+    SeatClaimed{seat: u8, token: String},
+    NewHand{
+        button_seat: u8,
+        #[serde(with = "card_serde::vec")]
+        deck: Vec<Card>, // Before the hole cards were dealt
+    },
+}
+
+// A finished hand
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HandRecord{
+    pub hand_number: u64, // Starting from 1
+    pub button_seat: u8,
+    pub finished_at: u64, // Seconds since the Unix epoch
+    #[serde(with = "card_serde::pair")]
+    pub btn_hole_cards: (Card, Card),
+    #[serde(with = "card_serde::pair")]
+    pub bb_hole_cards: (Card, Card),
+    #[serde(with = "card_serde::vec")]
+    pub board_cards: Vec<Card>,
+    pub streets: Vec<Street>,
+    pub result: HandResult,
+}
+
+// Bumped whenever the serialized form of Game changes
+pub const SNAPSHOT_VERSION: u32 = 7;
+
+#[derive(Serialize, Deserialize)]
+struct Snapshot{
+    version: u32,
+    game: Game,
+}
+
+impl Default for Game{
+    fn default() -> Self{
+        Self::new()
+    }
+}
+
+impl PreAction{
+    // The action it stands for, if it is one of the available actions
+    fn resolve(self, available_actions: &[ActionOption]) -> Option<Action>{
+        let available = |option: ActionOption| available_actions.contains(&option);
+        let call = available_actions.iter().find_map(|option| match option{
+            ActionOption::Call(amount) => Some(*amount),
+            _ => None,
+        });
+        match (self, call){
+            (PreAction::CheckOrFold | PreAction::Check | PreAction::CallAny, _) if available(ActionOption::Check) => Some(Action::Check),
+            (PreAction::CheckOrFold, _) if available(ActionOption::Fold) => Some(Action::Fold),
+            (PreAction::CallAny, Some(amount)) => Some(Action::Call(amount)),
+            (PreAction::Call(queued), Some(amount)) if queued == amount => Some(Action::Call(amount)),
+            _ => None,
+        }
+    }
+}
+
+impl Default for ChatSettings{
+    fn default() -> Self{
+        ChatSettings{max_length: 200, messages_per_minute: 10, spectators: false}
+    }
 }
 
 // Game state struct passed to players
@@ -25,24 +197,234 @@ pub struct GameState{
     board_cards: Vec<String>,
     available_actions: Vec<ActionOption>,
     active_player: Position,
+    undo_requested_by: Option<u8>,
+    #[serde(default)]
+    pre_action: Option<PreAction>, // Queued by the seat asking
 }
 
 impl Game{
     pub fn new() -> Game{
         let deck: Vec<Card> = Card::generate_shuffled_deck().to_vec();
-        let mut hand = Hand::new(deck, 995, 990, 5);
-        Game{current_hand: hand, button_seat: 0}
+        let hand = Hand::new(deck, 995, 990, 5);
+        Game::from_hand(hand, 0)
     }
 
     pub fn new_with_stacks_and_sb(btn_stack: u64, bb_stack: u64, sb_size: u64) -> Game{
         let deck: Vec<Card> = Card::generate_shuffled_deck().to_vec();
         let hand = Hand::new(deck, btn_stack, bb_stack, sb_size);
-        Game{current_hand: hand, button_seat: 0}
+        Game::from_hand(hand, 0)
+    }
+
+    pub fn from_hand(hand: Hand, button_seat: u8) -> Game{
+        Game{current_hand: hand,
+             button_seat,
+             hand_history: Vec::new(),
+             stats: StatsTracker::new(),
+             settings: GameSettings::default(),
+             undo_requested_by: None,
+             seats: Default::default(),
+             last_action_at: unix_time(),
+             chat: Vec::new(),
+             chat_muted: [false; 2],
+             pre_actions: [None; 2],
+             events: Vec::new()}
+    }
+
+    // Number of the hand being played, starting from 1
+    pub fn hand_number(&self) -> u64{
+        self.hand_history.len() as u64 + 1
+    }
+
+    // Current stacks of seat 0 and seat 1
+    pub fn seat_stacks(&self) -> [u64; 2]{
+        let hand = &self.current_hand;
+        match self.button_seat{
+            0 => [hand.btn_stack, hand.bb_stack],
+            _ => [hand.bb_stack, hand.btn_stack],
+        }
+    }
+
+    pub fn last_action_at(&self) -> u64{
+        self.last_action_at
+    }
+
+    pub fn status(&self) -> GameStatus{
+        if self.current_hand.btn_start_stack == 0 || self.current_hand.bb_start_stack == 0 {
+            GameStatus::Finished
+        } else if !(self.is_seat_claimed(0) && self.is_seat_claimed(1)) {
+            GameStatus::WaitingForPlayers
+        } else {
+            GameStatus::Playing
+        }
+    }
+
+    pub fn set_invite_code(&mut self, seat: u8, invite_code: Option<String>){
+        self.seats[seat as usize].invite_code = invite_code;
+    }
+
+    pub fn is_seat_claimed(&self, seat: u8) -> bool{
+        self.seats.get(seat as usize).is_some_and(|access| access.token.is_some())
+    }
+
+    // Returns the token of the seat. An unclaimed seat is claimed with a new token,
+    // a claimed seat needs its token.
+    pub fn claim_seat(&mut self, seat: u8, token: Option<&str>, invite_code: Option<&str>) -> Result<String, ActionError>{
+        let access = match self.seats.get(seat as usize){
+            Some(access) => access,
+            None => return Err(ActionError::InvalidSeat{seat}),
+        };
+
+        match (&access.token, token){
+            (Some(seat_token), Some(token)) if seat_token == token => Ok(token.to_string()),
+            (Some(_), Some(_)) => Err(ActionError::InvalidSeatToken{seat}),
+            (Some(_), None) => Err(ActionError::SeatTaken{seat}),
+            (None, _) => {
+                if access.invite_code.is_some() && access.invite_code.as_deref() != invite_code {
+                    return Err(ActionError::InvalidInviteCode{seat});
+                }
+                let token = uuid::Uuid::new_v4().as_simple().to_string();
+                self.assign_seat_token(seat, token.clone());
+                Ok(token)
+            }
+        }
+    }
+
+    // Fails unless the token belongs to the seat
+    pub fn check_seat_token(&self, seat: u8, token: &str) -> Result<(), ActionError>{
+        if seat > 1 {
+            return Err(ActionError::InvalidSeat{seat});
+        }
+        match self.seat_for_token(token){
+            Some(owner) if owner == seat => Ok(()),
+            _ => Err(ActionError::InvalidSeatToken{seat}),
+        }
+    }
+
+    // Returns the seat the token belongs to
+    pub fn seat_for_token(&self, token: &str) -> Option<u8>{
+        self.seats.iter().position(|access| access.token.as_deref() == Some(token)).map(|seat| seat as u8)
+    }
+
+    // Gives the seat to the holder of the token. Used when replaying a stored game.
+    pub fn assign_seat_token(&mut self, seat: u8, token: String){
+        self.events.push(GameEvent::SeatClaimed{seat, token: token.clone()});
+        self.seats[seat as usize].token = Some(token);
+    }
+
+    // Puts back the logged result of the last finished hand. Used when replaying a stored game,
+    // as the all in equity before the turn is sampled and comes out different when run again.
+    pub fn restore_hand_result(&mut self, result: HandResult){
+        if let Some(record) = self.hand_history.last_mut() {
+            record.result = result;
+        }
+    }
+
+    // Returns the events since the last call
+    pub fn take_events(&mut self) -> Vec<GameEvent>{
+        std::mem::take(&mut self.events)
+    }
+
+    // Replaces the current hand with a fresh one dealt from the given deck, keeping the stacks
+    pub fn deal_hand(&mut self, deck: Vec<Card>){
+        let (btn_stack, bb_stack) = (self.current_hand.btn_start_stack, self.current_hand.bb_start_stack);
+        self.start_hand(deck, btn_stack, bb_stack);
+    }
+
+    fn start_hand(&mut self, deck: Vec<Card>, btn_stack: u64, bb_stack: u64){
+        self.events.push(GameEvent::NewHand{button_seat: self.button_seat, deck: deck.clone()});
+        self.current_hand = Hand::new(deck, btn_stack, bb_stack, self.current_hand.sb_size);
+        self.undo_requested_by = None;
+        self.pre_actions = [None; 2];
+    }
+
+    // Full game state including the remaining deck, so a restored game deals the same cards
+    pub fn to_snapshot_json(&self) -> String{
+        serde_json::to_string(&Snapshot{version: SNAPSHOT_VERSION, game: self.clone()}).unwrap()
+    }
+
+    pub fn from_snapshot_json(json: &str) -> Result<Game, serde_json::Error>{
+        let snapshot: Snapshot = serde_json::from_str(json)?;
+        if snapshot.version != SNAPSHOT_VERSION {
+            return Err(serde::de::Error::custom(format!("unsupported snapshot version {}", snapshot.version)));
+        }
+        Ok(snapshot.game)
+    }
+
+    pub fn current_hand(&self) -> &Hand{
+        &self.current_hand
+    }
+
+    pub fn button_seat(&self) -> u8{
+        self.button_seat
+    }
+
+    pub fn settings(&self) -> &GameSettings{
+        &self.settings
+    }
+
+    pub fn set_settings(&mut self, settings: GameSettings){
+        self.settings = settings;
+    }
+
+    pub fn hand_history(&self) -> &[HandRecord]{
+        &self.hand_history
+    }
+
+    // Finished hands whose cards spectators may see after the spectator delay
+    pub fn spectator_hands(&self, now: u64) -> &[HandRecord]{
+        let revealed = match self.settings.spectator_delay{
+            None => 0,
+            Some(SpectatorDelay::Hands(hands)) => self.hand_history.len().saturating_sub(hands as usize),
+            Some(SpectatorDelay::Minutes(minutes)) => self.hand_history.iter()
+                .take_while(|record| record.finished_at.saturating_add(minutes.saturating_mul(60)) <= now)
+                .count(),
+        };
+        &self.hand_history[..revealed]
+    }
+
+    pub fn get_stats(&self) -> StatsReport{
+        self.stats.report()
+    }
+
+    pub fn get_stats_json(&self) -> String{
+        serde_json::to_string(&self.get_stats()).unwrap()
+    }
+
+    pub fn get_session_report(&self) -> SessionReport{
+        session_report(&self.hand_history)
+    }
+
+    pub fn get_session_report_json(&self) -> String{
+        serde_json::to_string(&self.get_session_report()).unwrap()
+    }
+
+    pub fn get_position(&self, seat: u8) -> Position{
+        match seat == self.button_seat{
+            true => Position::Button,
+            false => Position::BigBlind,
+        }
+    }
+
+    pub fn get_state(&self, for_seat: u8) -> GameState{
+        self.state(Some(for_seat))
     }
 
     pub fn get_state_json(&self, for_seat: u8) -> String{
+        serde_json::to_string(&self.get_state(for_seat)).unwrap()
+    }
+
+    // State without any hole cards or available actions
+    pub fn get_spectator_state(&self) -> GameState{
+        self.state(None)
+    }
+
+    pub fn get_spectator_state_json(&self) -> String{
+        serde_json::to_string(&self.get_spectator_state()).unwrap()
+    }
+
+    fn state(&self, viewer: Option<u8>) -> GameState{
 
-        let (btn_added_chips, bb_added_chips, minimum_raise_size, active_player) = self.current_hand.streets.last().unwrap().get_street_status();
+        let (btn_added_chips, bb_added_chips, _, active_player) = self.current_hand.streets.last().unwrap().get_street_status();
         let button_seat = self.button_seat;
 
         let button_card1 = self.current_hand.btn_hole_cards.0.rank_suit_string();
@@ -71,55 +453,95 @@ impl Game{
             bb_stack: self.current_hand.bb_stack,
             btn_added_chips_this_street: btn_added_chips,
             bb_added_chips_this_street: bb_added_chips,
-            button_seat: button_seat,
+            button_seat,
             sb_size: self.current_hand.sb_size,
             bb_size: self.current_hand.sb_size*2,
-            btn_hole_cards: match for_seat{
-                _ if for_seat == button_seat => Some((button_card1, button_card2)),
+            btn_hole_cards: match viewer{
+                Some(seat) if seat == button_seat => Some((button_card1, button_card2)),
                 _ => None,
             },
-            bb_hole_cards: match for_seat{
-                _ if for_seat == button_seat => None,
-                _ => Some((bb_card1, bb_card2)),
+            bb_hole_cards: match viewer{
+                Some(seat) if seat == 1 - button_seat => Some((bb_card1, bb_card2)),
+                _ => None,
             },
             board_cards: board,
-            available_actions: match for_seat == active_seat {
+            available_actions: match viewer == Some(active_seat) {
                 true => self.current_hand.streets.last().unwrap().get_available_actions(),
                 false => vec![] // Not our turn to act
             },
             active_player,
+            undo_requested_by: self.undo_requested_by,
+            pre_action: viewer.and_then(|seat| self.pre_actions.get(seat as usize).copied().flatten()),
         };
 
-        serde_json::to_string(&gamestate).unwrap()
-        
+        gamestate
+    }
+
+    // Whether the seat has an action to take now
+    pub fn is_turn_of(&self, seat: u8) -> bool{
+        let street = self.current_hand.streets.last().unwrap();
+        let (_, _, _, active_player) = street.get_street_status();
+        seat < 2 && self.get_position(seat) == active_player && !street.get_available_actions().is_empty()
     }
 
     // If the action ends the hand, returns HandResult. Otherwise returns None, unless there
-    // was an error, returns an error message as a string.
-    pub fn submit_action(&mut self, action: Action, from_seat: u8) -> Result<Option<HandResult>, String>{
+    // was an error, in which case returns the reason.
+    pub fn submit_action(&mut self, action: Action, from_seat: u8) -> Result<Option<HandResult>, ActionError>{
+        self.submit_action_at(action, from_seat, unix_time())
+    }
+
+    // Like submit_action, with the time of the action in seconds since the Unix epoch
+    pub fn submit_action_at(&mut self, action: Action, from_seat: u8, time: u64) -> Result<Option<HandResult>, ActionError>{
+        let hand_result = self.replay_action_at(action, from_seat, time)?;
+        let queued_result = self.take_pre_actions(time);
+        Ok(hand_result.or(queued_result))
+    }
+
+    // Like submit_action_at, without taking the queued actions whose turn comes. A game log has
+    // those as actions of their own.
+    pub fn replay_action_at(&mut self, action: Action, from_seat: u8, time: u64) -> Result<Option<HandResult>, ActionError>{
+
+        if from_seat > 1 {
+            return Err(ActionError::InvalidSeat{seat: from_seat});
+        }
 
         // See if it is the user's turn to act
         let (_,_,_,active_player) = self.current_hand.streets.last().unwrap().get_street_status();
-        let player_position = match from_seat == self.button_seat{
-            true => Position::Button,
-            false => Position::BigBlind,
-        };
+        let player_position = self.get_position(from_seat);
         
         if player_position != active_player{
-            return Err("It is not your turn to act".to_string());
+            return Err(ActionError::NotYourTurn{active_player});
         }
 
         // Submit the action and return the response
-        match self.current_hand.submit_action(action){
+        let streets = self.current_hand.streets.len();
+        let result = self.current_hand.submit_action(action);
+        if result.is_ok() {
+            self.undo_requested_by = None; // The situation changed
+            self.events.push(GameEvent::Action{seat: from_seat, action, time});
+            self.last_action_at = time;
+            self.update_pre_actions(action, from_seat, streets != self.current_hand.streets.len());
+        }
+        match result{
             Ok(hand_result) => {
                 match hand_result{
                     Some(res) => {
-                        // Deal a new hand
-                        let deck: Vec<Card> = Card::generate_shuffled_deck().to_vec();
+                        self.stats.record_hand(&self.current_hand.streets, &res, self.button_seat);
+                        self.hand_history.push(HandRecord{
+                            hand_number: self.hand_history.len() as u64 + 1,
+                            button_seat: self.button_seat,
+                            finished_at: time,
+                            btn_hole_cards: self.current_hand.btn_hole_cards,
+                            bb_hole_cards: self.current_hand.bb_hole_cards,
+                            board_cards: self.current_hand.board_cards.clone(),
+                            streets: self.current_hand.streets.clone(),
+                            result: res.clone(),
+                        });
+                        self.events.push(GameEvent::HandFinished{result: Box::new(res.clone())});
 
-                        // New hand: swap stacks between button and sb
-                        self.current_hand = Hand::new(deck, res.bb_stack, res.btn_stack, self.current_hand.sb_size);
+                        // Deal a new hand: swap stacks between button and sb
                         self.button_seat = 1 - self.button_seat; // Switch who is on the button
+                        self.start_hand(Card::generate_shuffled_deck().to_vec(), res.bb_stack, res.btn_stack);
                         Ok(Some(res))
                     }
                     None => { // No showdown, but valid action
@@ -131,24 +553,215 @@ impl Game{
         }
     }
 
-    // Takes a user command and returns a JSON response to the user, and a HandResult if the hand is over
-    pub fn process_user_command(&mut self, input: &String, from_seat: u8) -> (String, Option<HandResult>){
+    // Turns a helper like AllIn into the action it stands for, for the seat in turn
+    pub fn resolve_choice(&self, choice: ActionChoice, from_seat: u8) -> Result<Action, ActionError>{
+        if from_seat > 1 {
+            return Err(ActionError::InvalidSeat{seat: from_seat});
+        }
+        let street = self.current_hand.streets.last().unwrap();
+        let (_, _, _, active_player) = street.get_street_status();
+        if self.get_position(from_seat) != active_player {
+            return Err(ActionError::NotYourTurn{active_player});
+        }
+        street.resolve_choice(choice, self.current_hand.pot)
+    }
 
-        // Deserialize input as Action
-        let action: Action = match serde_json::from_str(input){
-            Ok(action) => action,
-            Err(e) => {
-                return (format!("{{\"action_response\": \"{}\"}}", e), None);
+    // Like submit_action for a choice, which also returns the action it stood for
+    pub fn submit_choice(&mut self, choice: ActionChoice, from_seat: u8) -> Result<(Action, Option<HandResult>), ActionError>{
+        let action = self.resolve_choice(choice, from_seat)?;
+        Ok((action, self.submit_action(action, from_seat)?))
+    }
+
+    // Queues a decision of the seat for its next turn, or clears it with None
+    pub fn queue_pre_action(&mut self, from_seat: u8, pre_action: Option<PreAction>) -> Result<(), ActionError>{
+        if from_seat > 1 {
+            return Err(ActionError::InvalidSeat{seat: from_seat});
+        }
+        if pre_action.is_some() && self.is_turn_of(from_seat) {
+            return Err(ActionError::AlreadyYourTurn);
+        }
+        self.pre_actions[from_seat as usize] = pre_action;
+        self.events.push(GameEvent::PreActionQueued{seat: from_seat, pre_action});
+        Ok(())
+    }
+
+    pub fn pre_action(&self, seat: u8) -> Option<PreAction>{
+        self.pre_actions.get(seat as usize).copied().flatten()
+    }
+
+    // Clears the queued actions that no longer fit after the action of the seat
+    fn update_pre_actions(&mut self, action: Action, from_seat: u8, new_street: bool){
+        self.pre_actions[from_seat as usize] = None;
+        if new_street {
+            self.pre_actions = [None; 2];
+        }
+        let opponent = &mut self.pre_actions[1 - from_seat as usize];
+        if matches!(action, Action::Bet(_) | Action::Raise(_)) && matches!(opponent, Some(PreAction::Check | PreAction::Call(_))) {
+            *opponent = None;
+        }
+    }
+
+    // Takes the queued action of the seat in turn, as long as there is one and it is still possible.
+    // A queued action that is no longer possible, or that fails, is cleared without failing the
+    // action that came before it. Returns the hand result if a queued action finished the hand.
+    fn take_pre_actions(&mut self, time: u64) -> Option<HandResult>{
+        let mut hand_result = None;
+        loop{
+            let street = self.current_hand.streets.last().unwrap();
+            let (_, _, _, active_player) = street.get_street_status();
+            let seat = match active_player == Position::Button{
+                true => self.button_seat,
+                false => 1 - self.button_seat,
+            };
+            let pre_action = match self.pre_actions[seat as usize]{
+                Some(pre_action) => pre_action,
+                None => return hand_result,
+            };
+            let available_actions = street.get_available_actions();
+            if available_actions.iter().any(|option| matches!(option, ActionOption::PostBlind(_))) {
+                return hand_result; // Blinds first, the decision comes after
             }
+            match pre_action.resolve(&available_actions).map(|action| self.replay_action_at(action, seat, time)){
+                Some(Ok(result)) => hand_result = result.or(hand_result),
+                _ => {
+                    self.pre_actions[seat as usize] = None;
+                    self.events.push(GameEvent::PreActionQueued{seat, pre_action: None});
+                    return hand_result;
+                }
+            }
+        }
+    }
+
+    // Takes back the last action of the current hand. Does not check the game settings.
+    pub fn undo_last_action(&mut self) -> Result<Action, ActionError>{
+        self.undo_requested_by = None;
+        self.pre_actions = [None; 2];
+        self.current_hand.undo_last_action()
+    }
+
+    // An undo needs the agreement of both seats: the first request is stored, and
+    // the undo happens when the other seat requests it too.
+    pub fn request_undo(&mut self, from_seat: u8) -> Result<UndoStatus, ActionError>{
+        if from_seat > 1 {
+            return Err(ActionError::InvalidSeat{seat: from_seat});
+        }
+        if !self.settings.allow_undo {
+            return Err(ActionError::UndoNotAllowed);
+        }
+        if self.current_hand.streets.iter().all(|street| street.actions.is_empty()) {
+            return Err(ActionError::NothingToUndo);
+        }
+
+        self.events.push(GameEvent::UndoRequest{seat: from_seat});
+        match self.undo_requested_by{
+            Some(seat) if seat != from_seat => Ok(UndoStatus::Undone(self.undo_last_action()?)),
+            _ => {
+                self.undo_requested_by = Some(from_seat);
+                Ok(UndoStatus::Requested)
+            }
+        }
+    }
+
+    // Turns up hole cards of the seat in the last finished hand, e.g. after the opponent folded
+    // or after mucking at showdown. Returns the number of the hand and the cards.
+    pub fn show_cards(&mut self, from_seat: u8, cards: ShowCards) -> Result<(u64, Vec<Card>), ActionError>{
+        if from_seat > 1 {
+            return Err(ActionError::InvalidSeat{seat: from_seat});
+        }
+        let record = self.hand_history.last_mut().ok_or(ActionError::NothingToShow)?;
+        let position = record.position_of(from_seat);
+        let hole_cards = match position{
+            Position::Button => record.btn_hole_cards,
+            Position::BigBlind => record.bb_hole_cards,
+        };
+        let shown = match cards{
+            ShowCards::First => vec![hole_cards.0],
+            ShowCards::Second => vec![hole_cards.1],
+            ShowCards::Both => vec![hole_cards.0, hole_cards.1],
         };
+        record.result.shown.show(position, &shown);
+        self.events.push(GameEvent::CardsShown{seat: from_seat, cards});
+        Ok((record.hand_number, shown))
+    }
 
-        match self.submit_action(action , from_seat){
-            Ok(hand_result) => {
-                return ("{\"action_response\": \"ok\"}".to_string(), hand_result);
-            },
+    // Stamps the chat message of the seat with the time and keeps it with the game
+    pub fn send_chat(&mut self, text: &str, from_seat: u8) -> Result<ChatMessage, ActionError>{
+        self.send_chat_at(text, from_seat, unix_time())
+    }
+
+    pub fn send_chat_at(&mut self, text: &str, from_seat: u8, time: u64) -> Result<ChatMessage, ActionError>{
+        if from_seat > 1 {
+            return Err(ActionError::InvalidSeat{seat: from_seat});
+        }
+        let text = text.trim();
+        let settings = &self.settings.chat;
+        if text.is_empty() {
+            return Err(ActionError::MalformedCommand{message: "empty chat message".to_string()});
+        }
+        let length = text.chars().count();
+        if length > settings.max_length {
+            return Err(ActionError::ChatTooLong{length, maximum: settings.max_length});
+        }
+        let last_minute = self.chat.iter().rev()
+            .take_while(|message| message.time + 60 > time)
+            .filter(|message| message.seat == from_seat)
+            .count();
+        if last_minute >= settings.messages_per_minute {
+            return Err(ActionError::LimitReached{limit: "chat messages per minute".to_string(), maximum: settings.messages_per_minute as u64});
+        }
+
+        let message = ChatMessage{seat: from_seat, time, text: text.to_string()};
+        self.chat.push(message.clone());
+        self.events.push(GameEvent::Chat{message: message.clone()});
+        Ok(message)
+    }
+
+    // Hides the chat of the opponent from the seat, or shows it again
+    pub fn mute_chat(&mut self, from_seat: u8, muted: bool) -> Result<(), ActionError>{
+        if from_seat > 1 {
+            return Err(ActionError::InvalidSeat{seat: from_seat});
+        }
+        self.chat_muted[from_seat as usize] = muted;
+        self.events.push(GameEvent::ChatMuted{seat: from_seat, muted});
+        Ok(())
+    }
+
+    // Whether a seat, or a spectator for None, sees the chat message
+    pub fn chat_visible_to(&self, message: &ChatMessage, viewer: Option<u8>) -> bool{
+        match viewer{
+            Some(seat) => seat == message.seat || !self.chat_muted.get(seat as usize).copied().unwrap_or(false),
+            None => self.settings.chat.spectators,
+        }
+    }
+
+    // The chat so far, as the seat or a spectator sees it
+    pub fn chat_history(&self, viewer: Option<u8>) -> Vec<ChatMessage>{
+        self.chat.iter().filter(|message| self.chat_visible_to(message, viewer)).cloned().collect()
+    }
+
+    // Takes a user command and returns a JSON response to the user, and a HandResult if the hand is over
+    pub fn process_user_command(&mut self, input: &str, from_seat: u8) -> (String, Option<HandResult>){
+
+        if input == "undo" {
+            return match self.request_undo(from_seat){
+                Ok(status) => (serde_json::json!({"undo_response": status}).to_string(), None),
+                Err(e) => (ErrorResponse::new(e).to_json(), None),
+            };
+        }
+
+        // Deserialize input as an action, or a helper like AllIn
+        let choice: ActionChoice = match serde_json::from_str(input){
+            Ok(choice) => choice,
             Err(e) => {
-                return (format!("{{\"action_response\": \"{}\"}}", e), None);
+                let error = ActionError::MalformedCommand{message: e.to_string()};
+                return (ErrorResponse::new(error).to_json(), None);
             }
+        };
+
+        match self.submit_choice(choice, from_seat){
+            // The action taken, so that a client sending a helper like AllIn learns what it stood for
+            Ok((action, hand_result)) => (serde_json::json!({"action_response": "ok", "action": action}).to_string(), hand_result),
+            Err(e) => (ErrorResponse::new(e).to_json(), None),
         }
     }
         
@@ -158,6 +771,9 @@ impl Game{
 mod tests{
 
     use super::*;
+    use crate::street::StreetName;
+    use poker::cards;
+    use proptest::prelude::*;
 
     #[test]
     fn test_initial_state(){
@@ -204,9 +820,9 @@ mod tests{
         dbg!(&state);
         // Button raises
         //assert!(game.current_hand.submit_action(Action::Raise(40)).is_ok());
-        assert!(game.process_user_command(&serde_json::to_string(&Action::Raise(40)).unwrap(), 0).0 == "{\"action_response\": \"ok\"}");
-        // BB folds
-        assert!(game.process_user_command(&serde_json::to_string(&Action::Fold).unwrap(), 1).0 == "{\"action_response\": \"ok\"}");
+        assert!(game.process_user_command(&serde_json::to_string(&Action::Raise(40)).unwrap(), 0).0 == r#"{"action":{"Raise":40},"action_response":"ok"}"#);
+        // BB folds, sent as a helper
+        assert!(game.process_user_command("\"CheckOrFold\"", 1).0 == r#"{"action":"Fold","action_response":"ok"}"#);
 
         // The first hand should be over now
 
@@ -235,16 +851,166 @@ mod tests{
         assert_eq!(state.active_player, Position::Button);
     }
 
+    #[test]
+    fn test_hand_history_and_stats(){
+        let mut game = Game::new_with_stacks_and_sb(500, 600, 5);
+        game.submit_action(Action::PostBlind(5), 0).unwrap();
+        game.submit_action(Action::PostBlind(10), 1).unwrap();
+        game.submit_action(Action::Raise(30), 0).unwrap();
+        assert!(game.submit_action(Action::Fold, 1).unwrap().is_some());
+
+        assert_eq!(game.hand_history().len(), 1);
+        let record = &game.hand_history()[0];
+        assert_eq!(record.hand_number, 1);
+        assert_eq!(record.button_seat, 0);
+        assert_eq!(record.streets[0].actions.len(), 4);
+
+        let stats = game.get_stats();
+        assert_eq!(stats.hands, 1);
+        assert_eq!(stats.seats[0].button.pfr, Some(100.0));
+        assert_eq!(stats.seats[1].big_blind.vpip, Some(0.0));
+        assert_eq!(stats.seats[1].big_blind.three_bet, Some(0.0));
+    }
+
+    #[test]
+    fn test_all_in_adjusted_session_report(){
+
+        // Aces against a flush draw, all in on the turn. The draw misses on the river.
+        let deck: Vec<Card> = cards!("2c 3s Kc 7h 2h Jh Qh Ad As").try_collect().unwrap();
+        let hand = Hand::new(deck, 500, 500, 5);
+        let mut game = Game::from_hand(hand, 0);
+
+        game.submit_action(Action::PostBlind(5), 0).unwrap();
+        game.submit_action(Action::PostBlind(10), 1).unwrap();
+        game.submit_action(Action::Call(10), 0).unwrap();
+        game.submit_action(Action::Check, 1).unwrap();
+
+        game.submit_action(Action::Check, 1).unwrap();
+        game.submit_action(Action::Check, 0).unwrap();
+
+        game.submit_action(Action::Bet(490), 1).unwrap(); // Big blind shoves the turn with the draw
+        game.submit_action(Action::Call(490), 0).unwrap();
+
+        game.submit_action(Action::Check, 1).unwrap();
+        let result = game.submit_action(Action::Check, 0).unwrap().unwrap();
+        assert_eq!(result.winner, Some(Position::Button));
+
+        let all_in = result.all_in.unwrap();
+        assert_eq!(all_in.street, StreetName::Turn);
+        assert_eq!(all_in.pot, 1000);
+        assert!((all_in.bb_equity - 9.0 / 44.0).abs() < 1e-9);
+
+        let report = game.get_session_report();
+        assert_eq!(report.hands, 1);
+        assert_eq!(report.seats[0].net_chips, 500);
+        assert_eq!(report.seats[1].net_chips, -500);
+        assert!((report.seats[0].adjusted_net_chips - (1000.0 * 35.0 / 44.0 - 500.0)).abs() < 1e-6);
+        assert!((report.seats[0].adjusted_net_chips + report.seats[1].adjusted_net_chips).abs() < 1e-6);
+        assert_eq!(report.seats[0].bb_per_100, Some(5000.0));
+        assert_eq!(report.seats[1].all_in_hands, 1);
+    }
+
+    #[test]
+    fn test_all_in_between_unequal_stacks(){
+
+        // The button has 300 and the big blind 800. The big blind shoves and the button calls all in.
+        let deck: Vec<Card> = cards!("2c 3s Kc 7h 2h Jh Qh Ad As").try_collect().unwrap();
+        let hand = Hand::new(deck, 300, 800, 5);
+        let mut game = Game::from_hand(hand, 0);
+        game.submit_action(Action::PostBlind(5), 0).unwrap();
+        game.submit_action(Action::PostBlind(10), 1).unwrap();
+        game.submit_action(Action::Call(10), 0).unwrap();
+        game.submit_action(Action::Raise(800), 1).unwrap();
+        game.submit_action(Action::Call(300), 0).unwrap();
+        for _ in 0..3 {
+            game.submit_action(Action::Check, 1).unwrap();
+            game.submit_action(Action::Check, 0).unwrap();
+        }
+
+        let all_in = game.hand_history()[0].result.all_in.clone().unwrap();
+        assert_eq!((all_in.pot, all_in.btn_contribution, all_in.bb_contribution), (600, 300, 300));
+
+        let report = game.get_session_report();
+        assert!((report.seats[0].adjusted_net_chips - (all_in.btn_expected_share - 300.0)).abs() < 1e-6);
+        assert!((report.seats[1].adjusted_net_chips - (all_in.bb_expected_share - 300.0)).abs() < 1e-6);
+        assert!((report.seats[0].adjusted_net_chips + report.seats[1].adjusted_net_chips).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_error_responses(){
+        let mut game = Game::new_with_stacks_and_sb(500, 600, 5);
+        game.submit_action(Action::PostBlind(5), 0).unwrap();
+        game.submit_action(Action::PostBlind(10), 1).unwrap();
+
+        assert_eq!(game.submit_action(Action::Fold, 1).unwrap_err(), ActionError::NotYourTurn{active_player: Position::Button});
+        assert_eq!(game.submit_action(Action::Fold, 2).unwrap_err(), ActionError::InvalidSeat{seat: 2});
+
+        let (answer, _) = game.process_user_command(&serde_json::to_string(&Action::Raise(15)).unwrap(), 0);
+        let response: ErrorResponse = serde_json::from_str(&answer).unwrap();
+        assert_eq!(response.action_response, "error");
+        assert_eq!(response.error, ActionError::AmountBelowMinimum{amount: 15, minimum: 20, maximum: 500});
+
+        let (answer, _) = game.process_user_command("not json", 0);
+        let value: serde_json::Value = serde_json::from_str(&answer).unwrap();
+        assert_eq!(value["error"]["error"], "malformed_command");
+    }
+
+    #[test]
+    fn test_undo_across_streets(){
+        let deck: Vec<Card> = cards!("2s 4h 6d 8d Ts Ah Ad Kc Ks").try_collect().unwrap();
+        let hand = Hand::new(deck, 500, 600, 5);
+        let mut game = Game::from_hand(hand, 0);
+        game.set_settings(GameSettings{allow_undo: true, ..Default::default()});
+
+        game.submit_action(Action::PostBlind(5), 0).unwrap();
+        game.submit_action(Action::PostBlind(10), 1).unwrap();
+        game.submit_action(Action::Raise(30), 0).unwrap();
+        game.submit_action(Action::Call(30), 1).unwrap();
+        assert_eq!(game.current_hand.board_cards.len(), 3);
+        let deck_before_undo = game.current_hand.deck.clone();
+
+        // One seat asks, the other agrees
+        assert_eq!(game.request_undo(1).unwrap(), UndoStatus::Requested);
+        assert_eq!(game.request_undo(1).unwrap(), UndoStatus::Requested); // Asking twice is not agreeing
+        assert_eq!(game.request_undo(0).unwrap(), UndoStatus::Undone(Action::Call(30)));
+
+        // Back on the preflop with the flop cards back on the deck
+        assert_eq!(game.current_hand.streets.len(), 1);
+        assert!(game.current_hand.board_cards.is_empty());
+        assert_eq!(game.current_hand.deck.len(), deck_before_undo.len() + 3);
+        assert_eq!(game.current_hand.pot, 40);
+        assert_eq!(game.current_hand.bb_stack, 590);
+
+        // BB chooses another line
+        game.submit_action(Action::Raise(100), 1).unwrap();
+        assert_eq!(game.current_hand.bb_stack, 500);
+        game.submit_action(Action::Call(100), 0).unwrap();
+        assert_eq!(game.current_hand.board_cards.len(), 3);
+        assert_eq!(game.current_hand.deck, deck_before_undo); // Same flop again
+    }
+
+    #[test]
+    fn test_undo_requires_setting(){
+        let mut game = Game::new_with_stacks_and_sb(500, 600, 5);
+        game.submit_action(Action::PostBlind(5), 0).unwrap();
+        assert_eq!(game.request_undo(0).unwrap_err(), ActionError::UndoNotAllowed);
+
+        let (answer, _) = game.process_user_command("undo", 1);
+        let response: ErrorResponse = serde_json::from_str(&answer).unwrap();
+        assert_eq!(response.error, ActionError::UndoNotAllowed);
+
+        game.set_settings(GameSettings{allow_undo: true, ..Default::default()});
+        game.undo_last_action().unwrap();
+        assert_eq!(game.request_undo(0).unwrap_err(), ActionError::NothingToUndo);
+    }
+
     #[test]
     fn test_split_pot(){
 
         // Rig a deck to give both players AA and a straight flush on board
         let deck: Vec<Card> = cards!("2s 3s 4s 5s 6s Ah Ad Ac As").try_collect().unwrap();
         let hand = Hand::new(deck, 500, 600, 5);
-        let mut game = Game{
-            current_hand: hand,
-            button_seat: 0,
-        };
+        let mut game = Game::from_hand(hand, 0);
 
         game.submit_action(Action::PostBlind(5), 0).unwrap();
         game.submit_action(Action::PostBlind(10), 1).unwrap();
@@ -273,10 +1039,7 @@ mod tests{
         // Rig a deck to deal out AA and KK and 2 4 6 8 T on the board
         let deck: Vec<Card> = cards!("2s 4h 6d 8d Ts Ah Ad Kc Ks").try_collect().unwrap();
         let hand = Hand::new(deck, 500, 600, 5);
-        let mut game = Game{
-            current_hand: hand,
-            button_seat: 0,
-        };
+        let mut game = Game::from_hand(hand, 0);
 
         game.submit_action(Action::PostBlind(5), 0).unwrap();
         game.submit_action(Action::PostBlind(10), 1).unwrap();
@@ -299,4 +1062,373 @@ mod tests{
 
     }
 
+    #[test]
+    fn test_spectators(){
+        let mut game = Game::new_with_stacks_and_sb(500, 600, 5);
+        game.submit_action(Action::PostBlind(5), 0).unwrap();
+        game.submit_action(Action::PostBlind(10), 1).unwrap();
+
+        let state: GameState = serde_json::from_str(&game.get_spectator_state_json()).unwrap();
+        assert_eq!(state.btn_hole_cards, None);
+        assert_eq!(state.bb_hole_cards, None);
+        assert_eq!(state.available_actions, vec![]);
+        assert_eq!(state.pot_size, 15);
+        let state: GameState = serde_json::from_str(&game.get_state_json(2)).unwrap();
+        assert_eq!(state.bb_hole_cards, None);
+
+        for (seat, finished_at) in [(0, 100), (1, 200), (0, 300)]{
+            game.submit_action_at(Action::Fold, seat, finished_at).unwrap();
+            game.submit_action_at(Action::PostBlind(5), 1 - seat, finished_at).unwrap();
+            game.submit_action_at(Action::PostBlind(10), seat, finished_at).unwrap();
+        }
+        assert_eq!(game.hand_history()[1].finished_at, 200);
+        assert_eq!(game.last_action_at(), 300);
+
+        assert_eq!(game.spectator_hands(1000).len(), 0);
+        game.set_settings(GameSettings{spectator_delay: Some(SpectatorDelay::Hands(1)), ..Default::default()});
+        assert_eq!(game.spectator_hands(1000).len(), 2);
+        game.set_settings(GameSettings{spectator_delay: Some(SpectatorDelay::Minutes(2)), ..Default::default()});
+        assert_eq!(game.spectator_hands(330).len(), 2);
+        assert_eq!(game.spectator_hands(1000).len(), 3);
+        game.set_settings(GameSettings{spectator_delay: Some(SpectatorDelay::Minutes(u64::MAX)), ..Default::default()});
+        assert_eq!(game.spectator_hands(u64::MAX - 1).len(), 0); // Does not overflow
+    }
+
+    #[test]
+    fn test_status(){
+        let mut game = Game::new_with_stacks_and_sb(500, 600, 5);
+        assert_eq!(game.status(), GameStatus::WaitingForPlayers);
+        game.claim_seat(0, None, None).unwrap();
+        game.claim_seat(1, None, None).unwrap();
+        assert_eq!(game.status(), GameStatus::Playing);
+        assert_eq!(game.hand_number(), 1);
+
+        game.submit_action(Action::PostBlind(5), 0).unwrap();
+        game.submit_action(Action::PostBlind(10), 1).unwrap();
+        assert_eq!(game.seat_stacks(), [495, 590]);
+        game.submit_action(Action::Raise(500), 0).unwrap();
+        game.submit_action(Action::Call(500), 1).unwrap();
+        for _ in 0..3{
+            game.submit_action(Action::Check, 1).unwrap();
+            game.submit_action(Action::Check, 0).unwrap();
+        }
+
+        // Seat 0 wins or loses everything, unless the pot is split
+        assert_eq!(game.hand_number(), 2);
+        let stacks = game.seat_stacks();
+        assert!(stacks == [1000, 100] || stacks == [0, 1100] || stacks == [500, 600]);
+        assert_eq!(game.status() == GameStatus::Finished, stacks[0] == 0);
+    }
+
+    #[test]
+    fn test_seat_claims(){
+        let mut game = Game::new();
+        game.set_invite_code(1, Some("secret".to_string()));
+
+        let token = game.claim_seat(0, None, None).unwrap();
+        assert!(game.is_seat_claimed(0));
+        assert_eq!(game.claim_seat(0, None, None).unwrap_err(), ActionError::SeatTaken{seat: 0});
+        assert_eq!(game.claim_seat(0, Some("guess"), None).unwrap_err(), ActionError::InvalidSeatToken{seat: 0});
+        assert_eq!(game.claim_seat(0, Some(&token), None).unwrap(), token);
+        assert_eq!(game.seat_for_token(&token), Some(0));
+        assert_eq!(game.seat_for_token("guess"), None);
+        assert!(game.check_seat_token(0, &token).is_ok());
+        assert_eq!(game.check_seat_token(1, &token).unwrap_err(), ActionError::InvalidSeatToken{seat: 1});
+        assert_eq!(game.check_seat_token(2, &token).unwrap_err(), ActionError::InvalidSeat{seat: 2});
+
+        assert_eq!(game.claim_seat(1, None, None).unwrap_err(), ActionError::InvalidInviteCode{seat: 1});
+        assert_eq!(game.claim_seat(1, None, Some("wrong")).unwrap_err(), ActionError::InvalidInviteCode{seat: 1});
+        assert_ne!(game.claim_seat(1, None, Some("secret")).unwrap(), token);
+        assert_eq!(game.claim_seat(2, None, None).unwrap_err(), ActionError::InvalidSeat{seat: 2});
+
+        // Claims survive snapshots
+        let restored = Game::from_snapshot_json(&game.to_snapshot_json()).unwrap();
+        assert!(restored.clone().claim_seat(0, Some(&token), None).is_ok());
+    }
+
+    #[test]
+    fn test_chat(){
+        let mut game = Game::new();
+        game.set_settings(GameSettings{chat: ChatSettings{max_length: 5, messages_per_minute: 2, spectators: false}, ..Default::default()});
+
+        let message = game.send_chat_at(" gl hf ", 0, 1000).unwrap();
+        assert_eq!(message, ChatMessage{seat: 0, time: 1000, text: "gl hf".to_string()});
+        assert_eq!(game.send_chat_at("thanks", 1, 1000).unwrap_err(), ActionError::ChatTooLong{length: 6, maximum: 5});
+        assert!(matches!(game.send_chat_at("  ", 1, 1000), Err(ActionError::MalformedCommand{..})));
+        assert!(game.send_chat_at("ty", 1, 1000).is_ok());
+
+        // Two messages per minute for each seat
+        assert!(game.send_chat_at("ok", 0, 1030).is_ok());
+        assert!(matches!(game.send_chat_at("ok?", 0, 1059), Err(ActionError::LimitReached{maximum: 2, ..})));
+        assert!(game.send_chat_at("ok!", 0, 1060).is_ok());
+
+        // Muting hides the opponent, and spectators see nothing unless the settings allow it
+        assert_eq!(game.chat_history(Some(1)).len(), 4);
+        game.mute_chat(1, true).unwrap();
+        assert_eq!(game.chat_history(Some(1)).iter().map(|m| m.text.as_str()).collect::<Vec<_>>(), vec!["ty"]);
+        assert_eq!(game.chat_history(Some(0)).len(), 4);
+        assert!(game.chat_history(None).is_empty());
+        game.mute_chat(1, false).unwrap();
+        assert_eq!(game.chat_history(Some(1)).len(), 4);
+    }
+
+    #[test]
+    fn test_show_and_muck(){
+
+        // Seat 0 on the button has KK, seat 1 has AA
+        let deck: Vec<Card> = cards!("2s 4h 6d 8d Ts Ah Ad Kc Ks").try_collect().unwrap();
+        let mut game = Game::from_hand(Hand::new(deck, 500, 600, 5), 0);
+        assert_eq!(game.show_cards(0, ShowCards::Both).unwrap_err(), ActionError::NothingToShow);
+
+        game.submit_action(Action::PostBlind(5), 0).unwrap();
+        game.submit_action(Action::PostBlind(10), 1).unwrap();
+        game.submit_action(Action::Call(10), 0).unwrap();
+        game.submit_action(Action::Check, 1).unwrap();
+        for _ in 0..2{
+            game.submit_action(Action::Check, 1).unwrap();
+            game.submit_action(Action::Check, 0).unwrap();
+        }
+
+        // The button bet the river last, so it shows first even though it loses
+        game.submit_action(Action::Check, 1).unwrap();
+        game.submit_action(Action::Bet(20), 0).unwrap();
+        let result = game.submit_action(Action::Call(20), 1).unwrap().unwrap();
+        let showdown = result.showdown.as_ref().unwrap();
+        assert_eq!(showdown.first_to_show(), Position::Button);
+        assert_eq!(showdown.made_hand(Position::BigBlind).unwrap().description, "Pair of Aces, Ten kicker");
+        assert_eq!(showdown.made_hand(Position::Button).unwrap().category, crate::ranking::HandCategory::Pair);
+        assert_eq!(showdown.deciding_kicker(), None);
+        assert!(result.shown.hand_shown(Position::Button) && result.shown.hand_shown(Position::BigBlind));
+
+        // The next hand is folded, and the winner shows one card
+        game.submit_action(Action::PostBlind(5), 1).unwrap();
+        game.submit_action(Action::PostBlind(10), 0).unwrap();
+        let result = game.submit_action(Action::Fold, 1).unwrap().unwrap();
+        assert!(result.shown.btn.is_empty() && result.shown.bb.is_empty());
+        let hole_cards = game.hand_history()[1].bb_hole_cards;
+        assert_eq!(game.show_cards(0, ShowCards::Second).unwrap(), (2, vec![hole_cards.1]));
+        assert_eq!(game.hand_history()[1].result.shown.bb, vec![hole_cards.1]);
+        assert!(matches!(game.take_events().last(), Some(GameEvent::CardsShown{seat: 0, cards: ShowCards::Second})));
+    }
+
+    #[test]
+    fn test_losing_hand_is_mucked_unless_shown(){
+
+        // Seat 0 on the button has KK, seat 1 has AA. The hand is checked down.
+        let deck: Vec<Card> = cards!("2s 4h 6d 8d Ts Ah Ad Kc Ks").try_collect().unwrap();
+        let mut game = Game::from_hand(Hand::new(deck, 500, 600, 5), 0);
+        game.submit_action(Action::PostBlind(5), 0).unwrap();
+        game.submit_action(Action::PostBlind(10), 1).unwrap();
+        game.submit_action(Action::Call(10), 0).unwrap();
+        game.submit_action(Action::Check, 1).unwrap();
+        for _ in 0..2{
+            game.submit_action(Action::Check, 1).unwrap();
+            game.submit_action(Action::Check, 0).unwrap();
+        }
+        game.submit_action(Action::Check, 1).unwrap();
+        let result = game.submit_action(Action::Check, 0).unwrap().unwrap();
+
+        // The big blind shows first and wins, and the losing button mucks by default
+        assert_eq!(result.winner, Some(Position::BigBlind));
+        assert!(result.shown.hand_shown(Position::BigBlind));
+        assert!(result.shown.btn.is_empty());
+        assert!(result.visible_to(Some(Position::BigBlind)).showdown.unwrap().hole_cards(Position::Button).is_none());
+
+        // The button can still choose to show after the hand
+        game.show_cards(0, ShowCards::Both).unwrap();
+        assert!(game.hand_history()[0].result.shown.hand_shown(Position::Button));
+    }
+
+    #[test]
+    fn test_pre_actions(){
+        let mut game = Game::new_with_stacks_and_sb(500, 500, 5);
+        assert_eq!(game.queue_pre_action(0, Some(PreAction::Check)).unwrap_err(), ActionError::AlreadyYourTurn);
+
+        // The big blind queues a call of the small blind, and calls as soon as it has posted
+        game.queue_pre_action(1, Some(PreAction::CallAny)).unwrap();
+        game.submit_action(Action::PostBlind(5), 0).unwrap();
+        assert_eq!(game.pre_action(1), Some(PreAction::CallAny)); // Blinds first
+        game.submit_action(Action::PostBlind(10), 1).unwrap();
+        game.queue_pre_action(1, Some(PreAction::Call(10))).unwrap();
+        assert_eq!(game.get_state(1).pre_action, Some(PreAction::Call(10)));
+        assert_eq!(game.get_state(0).pre_action, None);
+
+        // A raise clears a call of a fixed amount
+        game.submit_action(Action::Raise(30), 0).unwrap();
+        assert_eq!(game.pre_action(1), None);
+        assert!(game.is_turn_of(1));
+
+        // On the flop, the button checks from the queue after the big blind checks
+        game.submit_action(Action::Call(30), 1).unwrap();
+        game.queue_pre_action(0, Some(PreAction::CallAny)).unwrap();
+        game.submit_action(Action::Check, 1).unwrap();
+        assert_eq!(game.current_hand.board_cards.len(), 4);
+        assert_eq!(game.pre_actions, [None; 2]);
+
+        // Check or fold folds to a bet, which finishes the hand
+        game.queue_pre_action(0, Some(PreAction::CheckOrFold)).unwrap();
+        let result = game.submit_action(Action::Bet(20), 1).unwrap().unwrap();
+        assert_eq!(result.winner, Some(Position::BigBlind));
+        assert_eq!(game.hand_number(), 2);
+
+        // A check is not possible against a bet, so it is cleared
+        game.submit_action(Action::PostBlind(5), 1).unwrap();
+        game.submit_action(Action::PostBlind(10), 0).unwrap();
+        game.submit_action(Action::Call(10), 1).unwrap();
+        game.submit_action(Action::Check, 0).unwrap();
+        game.queue_pre_action(1, Some(PreAction::Check)).unwrap();
+        game.queue_pre_action(1, None).unwrap();
+        game.queue_pre_action(1, Some(PreAction::Call(40))).unwrap();
+        game.submit_action(Action::Bet(20), 0).unwrap();
+        assert_eq!(game.pre_action(1), None);
+        assert!(game.is_turn_of(1));
+    }
+
+    #[test]
+    fn test_snapshot_round_trip(){
+        let mut game = Game::new_with_stacks_and_sb(500, 600, 5);
+        game.set_settings(GameSettings{allow_undo: true, ..Default::default()});
+
+        // Play a hand to showdown, then start the next one
+        game.submit_action(Action::PostBlind(5), 0).unwrap();
+        game.submit_action(Action::PostBlind(10), 1).unwrap();
+        game.submit_action(Action::Call(10), 0).unwrap();
+        game.submit_action(Action::Check, 1).unwrap();
+        for _ in 0..3{
+            game.submit_action(Action::Check, 1).unwrap();
+            game.submit_action(Action::Check, 0).unwrap();
+        }
+        assert_eq!(game.hand_history().len(), 1);
+        assert!(game.hand_history()[0].result.showdown.is_some());
+        game.submit_action(Action::PostBlind(5), 1).unwrap();
+        game.submit_action(Action::PostBlind(10), 0).unwrap();
+        game.submit_action(Action::Call(10), 1).unwrap();
+
+        let json = game.to_snapshot_json();
+        let mut restored = Game::from_snapshot_json(&json).unwrap();
+        assert_eq!(restored.to_snapshot_json(), json);
+        assert_eq!(restored.settings(), game.settings());
+        assert_eq!(restored.button_seat(), 1);
+
+        // Both games deal the same flop from the remaining deck
+        game.submit_action(Action::Check, 0).unwrap();
+        restored.submit_action(Action::Check, 0).unwrap();
+        assert_eq!(restored.current_hand().board_cards, game.current_hand().board_cards);
+        assert_eq!(restored.get_state_json(0), game.get_state_json(0));
+
+        let bad_version = json.replacen(&format!("\"version\":{}", SNAPSHOT_VERSION), "\"version\":0", 1);
+        assert!(Game::from_snapshot_json(&bad_version).is_err());
+    }
+
+    // The seat in turn, and the actions it can take
+    fn turn(game: &Game) -> (u8, Vec<ActionOption>){
+        let street = game.current_hand.streets.last().unwrap();
+        let seat = (0..2).find(|seat| game.get_position(*seat) == street.get_street_status().3).unwrap();
+        (seat, street.get_available_actions())
+    }
+
+    // Turns an available action into an action, with an amount picked by the fraction
+    fn pick(option: ActionOption, fraction: f64) -> Action{
+        let amount = |minimum: u64, maximum: u64| match minimum < maximum{
+            true => minimum + ((maximum - minimum) as f64 * fraction) as u64,
+            false => maximum,
+        };
+        match option{
+            ActionOption::Fold => Action::Fold,
+            ActionOption::Check => Action::Check,
+            ActionOption::PostBlind(amount) => Action::PostBlind(amount),
+            ActionOption::Call(amount) => Action::Call(amount),
+            ActionOption::Bet(minimum, maximum) => Action::Bet(amount(minimum, maximum)),
+            ActionOption::Raise(minimum, maximum) => Action::Raise(amount(minimum, maximum)),
+        }
+    }
+
+    // Checks the invariants before the seat in turn acts
+    fn check_invariants(game: &Game, total_chips: u64){
+        let hand = &game.current_hand;
+        assert_eq!(hand.btn_start_stack + hand.bb_start_stack, total_chips);
+        assert_eq!(hand.btn_stack + hand.bb_stack + hand.pot, total_chips);
+        assert!(hand.btn_stack <= hand.btn_start_stack && hand.bb_stack <= hand.bb_start_stack);
+
+        // Only the seat in turn can act
+        let (seat, options) = turn(game);
+        for option in &options{
+            let error = game.clone().submit_action(pick(*option, 0.0), 1 - seat).unwrap_err();
+            assert!(matches!(error, ActionError::NotYourTurn{..}));
+        }
+
+        // A raise adds at least the big blind and the last raise, unless it is all in
+        let street = hand.streets.last().unwrap();
+        let (btn_added_chips, bb_added_chips, _, _) = street.get_street_status();
+        let bet = btn_added_chips.max(bb_added_chips);
+        for option in &options{
+            if let ActionOption::Raise(minimum, maximum) = *option {
+                assert!(minimum > bet);
+                if minimum < maximum {
+                    assert!(minimum - bet >= 2 * hand.sb_size);
+                    assert!(game.clone().submit_action(Action::Raise(minimum - 1), seat).is_err());
+                }
+            }
+        }
+    }
+
+    proptest!{
+        #![proptest_config(ProptestConfig::with_cases(32))]
+
+        #[test]
+        fn test_random_play_keeps_invariants(steps in prop::collection::vec((0..6_usize, 0.0..1.0_f64), 1..150)){
+            let mut game = Game::new_with_stacks_and_sb(500, 500, 5);
+            for (choice, fraction) in steps{
+                let (seat, options) = turn(&game);
+                if options.is_empty() {
+                    break; // A stack can not cover its blind, the match is over
+                }
+                check_invariants(&game, 1000);
+                if let Some(result) = game.submit_action(pick(options[choice % options.len()], fraction), seat).unwrap() {
+                    assert_eq!(result.btn_stack + result.bb_stack, 1000);
+                }
+            }
+
+            // Checking, calling and posting blinds ends the hand within a few actions
+            let hand_number = game.hand_number();
+            for _ in 0..12{
+                let (seat, options) = turn(&game);
+                let passive = options.iter().find(|option| matches!(option, ActionOption::Check | ActionOption::Call(_) | ActionOption::PostBlind(_)));
+                match passive{
+                    Some(option) => game.submit_action(pick(*option, 0.0), seat).unwrap(),
+                    None => break,
+                };
+            }
+            let (_, options) = turn(&game);
+            prop_assert!(game.hand_number() > hand_number || options.is_empty());
+        }
+
+        // Like the fuzz target, with commands that are often almost valid. The seat is mostly the
+        // one in turn, else the other one or an invalid seat.
+        #[test]
+        fn test_any_command_is_answered(commands in prop::collection::vec((0..4_u8, prop_oneof![
+            any::<String>(),
+            "\"(Fold|Check|AllIn|CheckOrFold|CheckOrCall)\"|undo",
+            (prop_oneof![Just("Call"), Just("Bet"), Just("Raise"), Just("PostBlind"), Just("BetBy"), Just("RaiseBy")], any::<u64>())
+                .prop_map(|(name, amount)| format!("{{\"{}\": {}}}", name, amount % 2000)),
+            (prop_oneof![Just("BetBy"), Just("RaiseBy")], (u64::MAX - 1000)..=u64::MAX)
+                .prop_map(|(name, amount)| format!("{{\"{}\": {}}}", name, amount)),
+            any::<f64>().prop_map(|fraction| format!("{{\"PotFraction\": {}}}", fraction)),
+        ]), 1..60)){
+            let mut game = Game::new_with_stacks_and_sb(500, 500, 5);
+            game.submit_action(Action::PostBlind(5), 0).unwrap();
+            game.submit_action(Action::PostBlind(10), 1).unwrap();
+            for (seat, command) in commands{
+                let seat = match seat{
+                    2 => 1 - turn(&game).0,
+                    3 => 2,
+                    _ => turn(&game).0,
+                };
+                let (response, _) = game.process_user_command(&command, seat);
+                prop_assert!(serde_json::from_str::<serde_json::Value>(&response).is_ok());
+                let hand = &game.current_hand;
+                prop_assert_eq!(hand.btn_stack + hand.bb_stack + hand.pot, 1000);
+            }
+        }
+    }
 }
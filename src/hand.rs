@@ -1,15 +1,22 @@
-use poker::{cards, Card, EvalClass, Evaluator, Rank, Eval};
+use poker::{Card, Eval};
 use serde::{Serialize, Deserialize};
-use crate::street::{Action, ActionOption, ActionResult, Street, StreetName};
-use crate::common::{Position, other_player};
-use serde::ser::SerializeStruct;
+use crate::street::{Action, ActionResult, Street, StreetName};
+use crate::common::{Position, other_player, card_serde};
+use crate::equity::{evaluator, heads_up_equity};
+use crate::error::ActionError;
+use crate::ranking::{deciding_kicker, MadeHand};
 
 // This struct represents the state of a single hand of poker
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Hand{
 
+    #[serde(with = "card_serde::pair")]
     pub btn_hole_cards: (Card, Card),
+    #[serde(with = "card_serde::pair")]
     pub bb_hole_cards: (Card, Card),
+    #[serde(with = "card_serde::vec")]
     pub board_cards: Vec<Card>,
+    #[serde(with = "card_serde::vec")]
     pub deck: Vec<Card>,
 
     pub sb_size: u64,
@@ -23,46 +30,170 @@ pub struct Hand{
 
     pub streets: Vec<Street>,
 
+    pub all_in: Option<AllIn>, // Set when the betting closes with a player all in before the river
+
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct HandResult{
     pub winner: Option<Position>, // None means split pot
     pub btn_stack: u64,
     pub bb_stack: u64,
     pub showdown: Option<Showdown>, // If someone folded, this is None
+    pub all_in: Option<AllIn>,
+    #[serde(default)]
+    pub shown: ShownCards, // At showdown, and the cards shown after the hand
+}
+
+// Equities at the moment the betting closed with a player all in
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AllIn{
+    pub street: StreetName,
+    pub pot: u64,
+    pub btn_equity: f64,
+    pub bb_equity: f64,
+    pub btn_expected_share: f64, // Equity times the pot
+    pub bb_expected_share: f64,
+    #[serde(default)]
+    pub btn_contribution: u64, // Chips put in the pot in the hand, missing in older logs
+    #[serde(default)]
+    pub bb_contribution: u64,
 }
 
-#[derive(Debug)]
+// Hole cards turned face up, by position
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ShownCards{
+    #[serde(with = "card_serde::vec")]
+    pub btn: Vec<Card>,
+    #[serde(with = "card_serde::vec")]
+    pub bb: Vec<Card>,
+}
+
+// The hands of a showdown. A hand that was mucked is None for everyone but its player.
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Showdown{
-    btn_eval: Eval,
-    bb_eval: Eval,
-    btn_hole_cards: (Card, Card),
-    bb_hole_cards: (Card, Card),
+    #[serde(default, with = "card_serde::option_pair")]
+    btn_hole_cards: Option<(Card, Card)>,
+    #[serde(default, with = "card_serde::option_pair")]
+    bb_hole_cards: Option<(Card, Card)>,
+    #[serde(default)]
+    btn_hand: Option<MadeHand>,
+    #[serde(default)]
+    bb_hand: Option<MadeHand>,
+    #[serde(with = "card_serde::vec")]
+    board_cards: Vec<Card>,
+    #[serde(default = "big_blind")]
+    first_to_show: Position,
+    #[serde(default, with = "card_serde::option")]
+    deciding_kicker: Option<Card>, // Of the winner, if only a kicker told the hands apart
+}
+
+fn big_blind() -> Position{
+    Position::BigBlind
+}
+
+impl ShownCards{
+    pub fn of(&self, position: Position) -> &[Card]{
+        match position{
+            Position::Button => &self.btn,
+            Position::BigBlind => &self.bb,
+        }
+    }
+
+    // Adds the cards that were not shown yet
+    pub fn show(&mut self, position: Position, cards: &[Card]){
+        let shown = match position{
+            Position::Button => &mut self.btn,
+            Position::BigBlind => &mut self.bb,
+        };
+        for card in cards{
+            if !shown.contains(card) {
+                shown.push(*card);
+            }
+        }
+    }
+
+    // Whether both hole cards of the position are face up
+    pub fn hand_shown(&self, position: Position) -> bool{
+        self.of(position).len() == 2
+    }
 }
 
-// Implement serialize for Showdown. We need to implement this manually because
-// the Eval and Card structs do not implement Serialize
-impl serde::Serialize for Showdown {
-    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
-    where
-        S: serde::Serializer,
-    {
+fn evaluate(hole_cards: (Card, Card), board_cards: &[Card]) -> Eval{
+    let mut cards = vec![hole_cards.0, hole_cards.1];
+    cards.extend_from_slice(board_cards);
+    evaluator().evaluate(&cards).unwrap()
+}
+
+impl Showdown{
+    pub fn new(btn_hole_cards: (Card, Card), bb_hole_cards: (Card, Card), board_cards: Vec<Card>, first_to_show: Position, winner: Option<Position>) -> Showdown{
+        let btn_hand = MadeHand::new(btn_hole_cards, &board_cards);
+        let bb_hand = MadeHand::new(bb_hole_cards, &board_cards);
+        let deciding_kicker = match winner{
+            Some(Position::Button) => deciding_kicker(&btn_hand, &bb_hand),
+            Some(Position::BigBlind) => deciding_kicker(&bb_hand, &btn_hand),
+            None => None,
+        };
+        Showdown{
+            btn_hole_cards: Some(btn_hole_cards),
+            bb_hole_cards: Some(bb_hole_cards),
+            btn_hand: Some(btn_hand),
+            bb_hand: Some(bb_hand),
+            board_cards,
+            first_to_show,
+            deciding_kicker,
+        }
+    }
+
+    // The player who turns up their hand first
+    pub fn first_to_show(&self) -> Position{
+        self.first_to_show
+    }
+
+    pub fn hole_cards(&self, position: Position) -> Option<(Card, Card)>{
+        match position{
+            Position::Button => self.btn_hole_cards,
+            Position::BigBlind => self.bb_hole_cards,
+        }
+    }
+
+    pub fn made_hand(&self, position: Position) -> Option<&MadeHand>{
+        match position{
+            Position::Button => self.btn_hand.as_ref(),
+            Position::BigBlind => self.bb_hand.as_ref(),
+        }
+    }
 
-        let btn_eval_string = format!("{:?}", self.btn_eval);
-        let bb_eval_string = format!("{:?}", self.bb_eval);
+    pub fn deciding_kicker(&self) -> Option<Card>{
+        self.deciding_kicker
+    }
 
-        let btn_hole_cards = (self.btn_hole_cards.0.rank_suit_string(), self.btn_hole_cards.1.rank_suit_string());
-        let bb_hole_cards = (self.bb_hole_cards.0.rank_suit_string(), self.bb_hole_cards.1.rank_suit_string());
+    // Leaves out a mucked hand. The deciding kicker would tell something about it.
+    pub fn hide(&mut self, position: Position){
+        match position{
+            Position::Button => (self.btn_hole_cards, self.btn_hand) = (None, None),
+            Position::BigBlind => (self.bb_hole_cards, self.bb_hand) = (None, None),
+        }
+        self.deciding_kicker = None;
+    }
+}
 
-        let mut state = serializer.serialize_struct("Showdown", 2)?;
-        state.serialize_field("btn_eval", &btn_eval_string)?;
-        state.serialize_field("bb_eval", &bb_eval_string)?;
-        state.serialize_field("btn_hole_cards", &btn_hole_cards)?;
-        state.serialize_field("bb_hole_cards", &bb_hole_cards)?;
-        state.end()
+impl HandResult{
+    // The result as the player in the position, or a spectator for None, may see it:
+    // hands of the showdown that were not shown are left out, except the viewer's own.
+    pub fn visible_to(&self, viewer: Option<Position>) -> HandResult{
+        let mut result = self.clone();
+        if let Some(showdown) = result.showdown.as_mut() {
+            for position in [Position::Button, Position::BigBlind]{
+                if viewer != Some(position) && !self.shown.hand_shown(position) {
+                    showdown.hide(position);
+                }
+            }
+        }
+        result
     }
 }
+
 impl Hand{
 
     // Assumes that both players have enough chips to post blinds
@@ -87,40 +218,52 @@ impl Hand{
              bb_start_stack: bb_stack,
              bb_stack, 
              pot, 
-             streets}
+             streets,
+             all_in: None}
 
     }
 
     // Returns Showdown and winner position. If the pot is split, then the winner position is None
     pub fn run_showdown(&mut self) -> (Showdown, Option<Position>){
 
-        let eval = Evaluator::new();
+        let btn_hand_eval = evaluate(self.btn_hole_cards, &self.board_cards);
+        let bb_hand_eval = evaluate(self.bb_hole_cards, &self.board_cards);
 
-        let mut btn_hand: Vec<Card> = vec![self.btn_hole_cards.0, self.btn_hole_cards.1];
-        let mut bb_hand: Vec<Card> = vec![self.bb_hole_cards.0, self.bb_hole_cards.1];
-        btn_hand.extend(self.board_cards.clone());
-        bb_hand.extend(self.board_cards.clone());
+        let winner = if btn_hand_eval.is_better_than(bb_hand_eval){
+            Some(Position::Button)
+        } else if bb_hand_eval.is_better_than(btn_hand_eval){
+            Some(Position::BigBlind)
+        } else {
+            None
+        };
+        let showdown = Showdown::new(self.btn_hole_cards, self.bb_hole_cards, self.board_cards.clone(), self.first_to_show(), winner);
+        (showdown, winner)
 
-        let btn_hand_eval = eval.evaluate(&btn_hand).unwrap();
-        let bb_hand_eval = eval.evaluate(&bb_hand).unwrap();
-        
-        dbg!(btn_hand_eval);
-        dbg!(bb_hand_eval);
+    }
 
-        let showdown = 
-            Showdown{btn_eval: btn_hand_eval, 
-                     bb_eval: bb_hand_eval,
-                     btn_hole_cards: self.btn_hole_cards,
-                     bb_hole_cards: self.bb_hole_cards};
+    // The last player to bet or raise on the river shows first. If the river was checked
+    // through, the big blind, who acts first after the flop, shows first.
+    fn first_to_show(&self) -> Position{
+        self.streets.last().unwrap().actions_with_players().into_iter()
+            .rfind(|(_, action)| matches!(action, Action::Bet(_) | Action::Raise(_)))
+            .map_or(Position::BigBlind, |(player, _)| player)
+    }
 
-        if btn_hand_eval.is_better_than(bb_hand_eval){
-            (showdown, Some(Position::Button))
-        } else if bb_hand_eval.is_better_than(btn_hand_eval){
-            (showdown, Some(Position::BigBlind))
-        } else {
-            (showdown, None)
+    // Cards turned up at showdown. When a player is all in, both hands are turned up. Otherwise
+    // the first player shows, and the other shows a hand that wins or splits the pot and mucks
+    // a losing one by default. A player who wants to show the losing hand does so with
+    // Game::show_cards after the hand.
+    fn shown_at_showdown(&self, showdown: &Showdown, winner: Option<Position>) -> ShownCards{
+        let all_in = self.btn_stack == 0 || self.bb_stack == 0;
+        let mut shown = ShownCards::default();
+        for position in [Position::Button, Position::BigBlind]{
+            let loses = winner == Some(other_player(position));
+            if all_in || position == showdown.first_to_show() || !loses {
+                let (first, second) = showdown.hole_cards(position).unwrap();
+                shown.show(position, &[first, second]);
+            }
         }
-
+        shown
     }
 
     pub fn goto_next_street(&mut self){
@@ -147,18 +290,46 @@ impl Hand{
                 panic!("Can't go to next street on river");
             },
             StreetName::End => (),
-            _ => panic!("Invalid street"),
         };
 
         self.streets.push(Street::new(next_street_name, self.sb_size*2, self.btn_stack, self.bb_stack));
     }
 
+    // Takes back the last action of the hand. If the current street has no actions yet,
+    // steps back to the previous street and puts its board cards back on the deck so that
+    // the same cards are dealt again.
+    pub fn undo_last_action(&mut self) -> Result<Action, ActionError>{
+
+        if self.streets.last().unwrap().actions.is_empty() && self.streets.len() > 1 {
+            let street = self.streets.pop().unwrap();
+            let dealt_cards = match street.street{
+                StreetName::Flop => 3,
+                StreetName::Turn | StreetName::River => 1,
+                StreetName::Preflop | StreetName::End => 0,
+            };
+            for _ in 0..dealt_cards{
+                let card = self.board_cards.pop().unwrap();
+                self.deck.push(card);
+            }
+
+            // The action being undone is the one that recorded the all in
+            let previous_street = self.streets.last().unwrap().street;
+            if self.all_in.as_ref().is_some_and(|all_in| all_in.street == previous_street) {
+                self.all_in = None;
+            }
+        }
+
+        let action = self.streets.last_mut().unwrap().undo_last_action().ok_or(ActionError::NothingToUndo)?;
+        self.update_pot_and_stacks();
+        Ok(action)
+    }
+
     pub fn update_pot_and_stacks(&mut self){
 
         // Initialize the pot and stacks
-        let mut pot = 0 as u64;
-        let mut btn_stack = self.btn_start_stack as u64;
-        let mut bb_stack = self.bb_start_stack as u64;
+        let mut pot = 0_u64;
+        let mut btn_stack = self.btn_start_stack;
+        let mut bb_stack = self.bb_start_stack;
 
         // Iterate over all streets and update the pot and stacks
         for street in self.streets.iter(){
@@ -189,15 +360,13 @@ impl Hand{
   
     // Returns Ok(None) if action was valid and hand did not finish yet
     // Returns Ok(HandResult) if action was valid and hand finished
-    // Otherwise returns an error message as Err(String)
-    pub fn submit_action(&mut self, action: Action) -> Result<Option<HandResult>, String>{
+    // Otherwise returns the reason as Err(ActionError)
+    pub fn submit_action(&mut self, action: Action) -> Result<Option<HandResult>, ActionError>{
 
         let street = self.streets.last_mut().unwrap();
         let streetname = street.street;
 
-        if !street.is_valid_action(action) {
-            return Err("Invalid action".to_string());
-        }
+        street.validate_action(action)?;
 
         // Apply the action
         let result = street.submit_action(action);
@@ -205,22 +374,39 @@ impl Hand{
         // Update the pot and stacks
         self.update_pot_and_stacks();
 
+        // Record the equities if the betting closed before the river with a player all in
+        if result == Ok(ActionResult::BettingClosed) && streetname != StreetName::River
+            && (self.btn_stack == 0 || self.bb_stack == 0) && self.all_in.is_none() {
+            let (btn_equity, bb_equity) = heads_up_equity(self.btn_hole_cards, self.bb_hole_cards, &self.board_cards);
+            self.all_in = Some(AllIn{
+                street: streetname,
+                pot: self.pot,
+                btn_equity,
+                bb_equity,
+                btn_expected_share: btn_equity * self.pot as f64,
+                bb_expected_share: bb_equity * self.pot as f64,
+                btn_contribution: self.btn_start_stack - self.btn_stack,
+                bb_contribution: self.bb_start_stack - self.bb_stack,
+            });
+        }
+
         // Advance the hand to the next stage, if required.
-        let ret_val: Result<Option<HandResult>, String> = match result{
+        let ret_val: Result<Option<HandResult>, ActionError> = match result{
             Ok(res) => match res{
                 ActionResult::BettingClosed => {
                     if streetname == StreetName::River{
                         let (showdown, winner) = self.run_showdown();
                         let (btn_new_stack, bb_new_stack) = self.get_stacks_after_hand(winner);
-
-                        dbg!(&showdown, winner, btn_new_stack, bb_new_stack);
+                        let shown = self.shown_at_showdown(&showdown, winner);
 
                         // Return result
                         let hand_result = 
                             HandResult{showdown: Some(showdown), 
                                        winner, 
                                        bb_stack: bb_new_stack,
-                                       btn_stack: btn_new_stack};
+                                       btn_stack: btn_new_stack,
+                                       all_in: self.all_in.clone(),
+                                       shown};
                         Ok(Some(hand_result))
                     } else {
                         self.goto_next_street();
@@ -235,7 +421,9 @@ impl Hand{
                     let res = HandResult{showdown: None, 
                               winner: Some(winner),
                               bb_stack: bb_new_stack,
-                              btn_stack: btn_new_stack};
+                              btn_stack: btn_new_stack,
+                              all_in: self.all_in.clone(),
+                              shown: ShownCards::default()};
                     Ok(Some(res))
                 },
             }
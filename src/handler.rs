@@ -1,14 +1,31 @@
-use crate::{ws, MyClient, MyClients, Result, Games};
+use crate::{lifecycle, lobby, sse, ws, ClientRole, Hub, Lobby, MyClient, MyClients, Result, Games, Settings, Store};
 use serde::{Deserialize, Serialize};
+use std::borrow::Borrow;
+use std::collections::hash_map::Entry;
 use uuid::Uuid;
 use crate::Game;
-use warp::{http::StatusCode, reply::json, ws::Message, Reply};
+use rust_poker::game::{ChatSettings, GameSettings, PreAction, ShowCards, SpectatorDelay};
+use rust_poker::storage::CloseReason;
+use rust_poker::error::{ActionError, ErrorResponse};
+use rust_poker::hand::HandResult;
+use rust_poker::history::{self, HandFilter};
+use rust_poker::protocol::{Envelope, ServerMessage};
+use rust_poker::common::unix_time;
+use rust_poker::street::{Action, ActionChoice};
+use std::time::Duration;
+use tokio::time::Instant;
+use warp::{http::StatusCode, reply::{json, with_header, with_status}, Reply};
 
 #[derive(Deserialize, Debug)]
 pub struct CreateGameRequest {
     id: u64,
-    sb_size: u64,
-    stacks: (u64, u64), // Seat 0, seat 1
+    sb_size: Option<u64>, // The defaults of the server config are used for missing settings
+    stacks: Option<(u64, u64)>, // Seat 0, seat 1
+    allow_undo: Option<bool>,
+    #[serde(default)]
+    invite_codes: [Option<String>; 2], // Needed by the first player to join each seat
+    spectator_delay: Option<SpectatorDelay>,
+    chat_to_spectators: Option<bool>, // Whether spectators see the chat of the seats
 }
 
 #[derive(Serialize, Debug)]
@@ -20,42 +37,197 @@ pub struct CreateGameResponse {
 pub struct JoinRequest {
     game_id: u64,
     seat: u64,
+    token: Option<String>, // Needed to join a seat that was claimed before
+    invite_code: Option<String>,
 }
 
 #[derive(Serialize, Debug)]
 pub struct JoinResponse {
     url: String,
+    token: String, // Keep this to join the seat again
 }
 
-pub async fn ws_handler(ws: warp::ws::Ws, id: String, clients: MyClients, games: Games) -> Result<impl Reply> {
+#[derive(Deserialize, Debug)]
+pub struct CloseRequest {
+    token: Option<String>, // Token of either seat, not needed if no seat was claimed
+}
+
+#[derive(Deserialize, Debug)]
+pub struct StateQuery {
+    seat: Option<u8>, // The state of a spectator if missing
+    token: Option<String>, // Of the seat
+    wait: Option<u64>, // Seconds to wait until it is the turn of the seat, or for spectators until the game changes
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ActionRequest {
+    seat: u8,
+    token: String,
+    action: ActionChoice, // Also a helper like "AllIn" or {"PotFraction": 0.5}
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ShowRequest {
+    seat: u8,
+    token: String,
+    cards: ShowCards,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct QueueRequest {
+    seat: u8,
+    token: String,
+    pre_action: Option<PreAction>, // null clears the queued action
+}
+
+#[derive(Serialize, Debug)]
+pub struct ActionResponse {
+    action_response: String, // Always "ok", errors are an ErrorResponse
+    action: Action, // The action taken, also for a helper
+    hand_result: Option<HandResult>, // If the action finished the hand
+}
+
+#[derive(Deserialize, Debug)]
+pub struct SeatQuery {
+    seat: Option<u8>, // What a spectator sees if missing
+    token: Option<String>, // Of the seat
+}
+
+#[derive(Deserialize, Debug)]
+pub struct PageQuery {
+    #[serde(default)]
+    offset: usize,
+    limit: Option<usize>,
+}
+
+// Longest wait of a state request
+const MAX_WAIT_SECONDS: u64 = 60;
+
+// Hands per page of the hand history
+const DEFAULT_HANDS_PER_PAGE: usize = 50;
+const MAX_HANDS_PER_PAGE: usize = 200;
+
+#[derive(Deserialize, Debug)]
+pub struct SpectateRequest {
+    game_id: u64,
+}
+
+#[derive(Serialize, Debug)]
+pub struct SpectateResponse {
+    url: String,
+}
+
+#[allow(clippy::too_many_arguments)]
+pub async fn ws_handler(ws: warp::ws::Ws, id: String, clients: MyClients, games: Games, store: Store, lobby: Lobby, hub: Hub) -> Result<impl Reply> {
     let client = clients.read().await.get(&id).cloned();
     match client {
-        Some(c) => Ok(ws.on_upgrade(move |socket| ws::client_connection(socket, id, clients, c, games))),
+        Some(c) => Ok(ws.on_upgrade(move |socket| ws::client_connection(socket, id, clients, c, games, store, lobby, hub))),
         None => Err(warp::reject::not_found()),
     }
 }
 
-pub async fn create_game_handler(body: CreateGameRequest, games: Games) -> Result<impl Reply> {
+pub async fn create_game_handler(body: CreateGameRequest, games: Games, store: Store, hub: Hub, lobby: Lobby, config: Settings) -> Result<Box<dyn Reply>> {
 
     let id = body.id;
-    let sb_size = body.sb_size;
-    let stacks = body.stacks;
+    let defaults = &config.defaults;
+    let sb_size = body.sb_size.unwrap_or(defaults.sb_size);
+    let stacks = body.stacks.unwrap_or((defaults.stack, defaults.stack));
+    let settings = GameSettings {
+        allow_undo: body.allow_undo.unwrap_or(defaults.allow_undo),
+        spectator_delay: body.spectator_delay.or(defaults.spectator_delay),
+        chat: ChatSettings {
+            spectators: body.chat_to_spectators.unwrap_or(defaults.chat.spectators),
+            ..defaults.chat.clone()
+        },
+    };
+
+    let max_stack = config.limits.max_stack;
+    if stacks.0 > max_stack || stacks.1 > max_stack {
+        let limit = ActionError::LimitReached{limit: "chips in a stack".to_string(), maximum: max_stack};
+        return Ok(error_reply(limit, StatusCode::BAD_REQUEST));
+    }
 
-    if games.read().await.contains_key(&id){
-        Ok(json(&CreateGameResponse {
+    let mut locked = games.write().await;
+    if locked.len() >= config.limits.max_games && !locked.contains_key(&id) {
+        let limit = ActionError::LimitReached{limit: "games".to_string(), maximum: config.limits.max_games as u64};
+        return Ok(error_reply(limit, StatusCode::SERVICE_UNAVAILABLE));
+    }
+    match locked.entry(id){
+        Entry::Occupied(_) => Ok(Box::new(json(&CreateGameResponse {
             message: format!("Game with id {} already exists", id),
-        }))
-    } else{
-        let newgame = Game::new_with_stacks_and_sb(stacks.0, stacks.1, sb_size);
-        games.write().await.insert(id, newgame);
-        Ok(json(&CreateGameResponse {
-            message: format!("Game created with id {id}, sb_size {sb_size}, stacks ({}, {})", stacks.0, stacks.1),
-        }))
+        }))),
+        Entry::Vacant(entry) => {
+            let mut newgame = Game::new_with_stacks_and_sb(stacks.0, stacks.1, sb_size);
+            newgame.set_settings(settings);
+            for (seat, invite_code) in body.invite_codes.into_iter().enumerate() {
+                newgame.set_invite_code(seat as u8, invite_code);
+            }
+            if let Err(e) = store.create_game(id, &newgame) {
+                eprintln!("could not store game {}: {}", id, e);
+                return Ok(Box::new(json(&CreateGameResponse {
+                    message: format!("Could not store game with id {}", id),
+                })));
+            }
+            entry.insert(newgame);
+            drop(locked);
+            lobby::publish(&lobby, id, &games, &hub).await;
+            Ok(Box::new(json(&CreateGameResponse {
+                message: format!("Game created with id {id}, sb_size {sb_size}, stacks ({}, {})", stacks.0, stacks.1),
+            })))
+        }
+    }
+}
+
+
+#[allow(clippy::too_many_arguments)]
+pub async fn join_handler(body: JoinRequest, clients: MyClients, games: Games, store: Store, lobby: Lobby, hub: Hub, config: Settings) -> Result<Box<dyn Reply>> {
+
+    let mut locked = games.write().await;
+    let game = match locked.get_mut(&body.game_id){
+        Some(game) => game,
+        None => return Ok(error_reply(ActionError::GameNotFound{game_id: body.game_id}, StatusCode::NOT_FOUND)),
+    };
+
+    let seat = body.seat.min(u8::MAX as u64) as u8;
+    let token = match game.claim_seat(seat, body.token.as_deref(), body.invite_code.as_deref()){
+        Ok(token) => token,
+        Err(e) => return Ok(error_reply(e, StatusCode::FORBIDDEN)),
+    };
+    if let Err(e) = store.append_events(body.game_id, game.take_events()) {
+        eprintln!("could not store events of game {}: {}", body.game_id, e);
     }
+    drop(locked);
+    lobby::publish(&lobby, body.game_id, &games, &hub).await;
+
+    // The websocket URL of a seat stays the same, so a player can reconnect to it
+    let client = MyClient { game_id: body.game_id, role: ClientRole::Seat(seat) };
+    let mut clients = clients.write().await;
+    let existing = clients.iter()
+        .find(|(_, c)| c.game_id == client.game_id && c.role == client.role)
+        .map(|(id, _)| id.clone());
+    let uuid = existing.unwrap_or_else(|| {
+        let uuid = Uuid::new_v4().as_simple().to_string(); // Websocket id
+        clients.insert(uuid.clone(), client);
+        uuid
+    });
+
+    Ok(Box::new(json(&JoinResponse {
+        url: config.ws_url(&format!("ws/{}", uuid)),
+        token,
+    })))
 }
 
+pub async fn spectate_handler(body: SpectateRequest, clients: MyClients, games: Games, hub: Hub, config: Settings) -> Result<Box<dyn Reply>> {
 
-pub async fn join_handler(body: JoinRequest, clients: MyClients) -> Result<impl Reply> {
+    if !games.read().await.contains_key(&body.game_id) {
+        return Ok(error_reply(ActionError::GameNotFound{game_id: body.game_id}, StatusCode::NOT_FOUND));
+    }
+
+    // Only connected spectators count, as a URL that is never used takes no place
+    if spectators(&hub, body.game_id).await >= config.limits.max_spectators_per_game {
+        let maximum = config.limits.max_spectators_per_game as u64;
+        return Ok(error_reply(ActionError::LimitReached{limit: "spectators".to_string(), maximum}, StatusCode::SERVICE_UNAVAILABLE));
+    }
 
     let uuid = Uuid::new_v4().as_simple().to_string(); // Websocket id
 
@@ -63,16 +235,258 @@ pub async fn join_handler(body: JoinRequest, clients: MyClients) -> Result<impl
         uuid.clone(),
         MyClient {
             game_id: body.game_id,
-            seat: body.seat,
-            sender: None,
+            role: ClientRole::Spectator,
         },
     );
 
-    Ok(json(&JoinResponse {
-        url: format!("ws://127.0.0.1:8000/ws/{}", uuid),
-    }))
+    Ok(Box::new(json(&SpectateResponse {
+        url: config.ws_url(&format!("ws/{}", uuid)),
+    })))
+}
+
+// Spectators connected to the game by websocket or server-sent events
+async fn spectators(hub: &Hub, game_id: u64) -> usize {
+    hub.roles().await.get(&game_id).map_or(0, |roles| roles.iter().filter(|r| **r == ClientRole::Spectator).count())
+}
+
+fn error_reply(error: ActionError, status: StatusCode) -> Box<dyn Reply> {
+    Box::new(with_status(json(&ErrorResponse::new(error)), status))
 }
 
+// The game looked up by the handler, if the token belongs to the seat, or without a seat as a
+// spectator sees it. Otherwise the reply for a missing game or a wrong token.
+fn seat_game<G: Borrow<Game>>(game: Option<G>, game_id: u64, seat: Option<u8>, token: Option<&str>) -> std::result::Result<G, Box<dyn Reply>> {
+    let game = game.ok_or_else(|| error_reply(ActionError::GameNotFound{game_id}, StatusCode::NOT_FOUND))?;
+    if let Some(seat) = seat {
+        game.borrow().check_seat_token(seat, token.unwrap_or_default()).map_err(|e| error_reply(e, StatusCode::FORBIDDEN))?;
+    }
+    Ok(game)
+}
+
+pub async fn games_handler(games: Games, hub: Hub) -> Result<impl Reply> {
+    Ok(json(&lobby::summaries(&games, &hub).await))
+}
+
+pub async fn game_handler(game_id: u64, games: Games, hub: Hub) -> Result<Box<dyn Reply>> {
+    let occupancy = lobby::occupancy(&hub).await.get(&game_id).copied().unwrap_or_default();
+    match games.read().await.get(&game_id){
+        Some(game) => Ok(Box::new(json(&lobby::summary(game_id, game, occupancy)))),
+        None => Ok(error_reply(ActionError::GameNotFound{game_id}, StatusCode::NOT_FOUND)),
+    }
+}
+
+pub async fn lobby_handler(ws: warp::ws::Ws, lobby: Lobby, games: Games, hub: Hub) -> Result<impl Reply> {
+    Ok(ws.on_upgrade(move |socket| lobby::lobby_connection(socket, lobby, games, hub)))
+}
+
+fn authorized(game: &Game, token: Option<&str>) -> bool {
+    let claimed = game.is_seat_claimed(0) || game.is_seat_claimed(1);
+    !claimed || token.and_then(|token| game.seat_for_token(token)).is_some()
+}
+
+#[allow(clippy::too_many_arguments)]
+pub async fn close_handler(game_id: u64, body: CloseRequest, games: Games, clients: MyClients, store: Store, lobby: Lobby, hub: Hub) -> Result<Box<dyn Reply>> {
+    match games.read().await.get(&game_id){
+        Some(game) if !authorized(game, body.token.as_deref()) => return Ok(error_reply(ActionError::Unauthorized, StatusCode::FORBIDDEN)),
+        Some(_) => (),
+        None => return Ok(error_reply(ActionError::GameNotFound{game_id}, StatusCode::NOT_FOUND)),
+    }
+
+    if !lifecycle::close_game(game_id, CloseReason::Closed, &games, &clients, &store, &lobby, &hub).await {
+        return Ok(error_reply(ActionError::GameNotFound{game_id}, StatusCode::NOT_FOUND)); // Closed meanwhile
+    }
+    Ok(Box::new(json(&CreateGameResponse {
+        message: format!("Game {} closed", game_id),
+    })))
+}
+
+// Archives a running or a closed game
+#[allow(clippy::too_many_arguments)]
+pub async fn archive_handler(game_id: u64, body: CloseRequest, games: Games, clients: MyClients, store: Store, lobby: Lobby, hub: Hub) -> Result<Box<dyn Reply>> {
+    let running = games.read().await.get(&game_id).map(|game| authorized(game, body.token.as_deref()));
+    let authorized = match running{
+        Some(authorized) => authorized,
+        None => match store.load_game(game_id){
+            Ok(game) => authorized(&game, body.token.as_deref()),
+            Err(_) => return Ok(error_reply(ActionError::GameNotFound{game_id}, StatusCode::NOT_FOUND)),
+        },
+    };
+    if !authorized {
+        return Ok(error_reply(ActionError::Unauthorized, StatusCode::FORBIDDEN));
+    }
+
+    if running.is_some() {
+        lifecycle::close_game(game_id, CloseReason::Archived, &games, &clients, &store, &lobby, &hub).await;
+    }
+    if let Err(e) = store.archive_game(game_id) {
+        eprintln!("could not archive game {}: {}", game_id, e);
+        return Ok(Box::new(with_status(json(&CreateGameResponse {
+            message: format!("Could not archive game {}", game_id),
+        }), StatusCode::INTERNAL_SERVER_ERROR)));
+    }
+    Ok(Box::new(json(&CreateGameResponse {
+        message: format!("Game {} archived", game_id),
+    })))
+}
+
+// The state of a seat or of a spectator. With wait, this is a long poll that answers as soon as
+// the seat has to act, or for spectators as soon as the game changes.
+pub async fn state_handler(game_id: u64, query: StateQuery, games: Games, hub: Hub) -> Result<Box<dyn Reply>> {
+    if !games.read().await.contains_key(&game_id) {
+        return Ok(error_reply(ActionError::GameNotFound{game_id}, StatusCode::NOT_FOUND));
+    }
+    let wait = Duration::from_secs(query.wait.unwrap_or(0).min(MAX_WAIT_SECONDS));
+    let deadline = Instant::now() + wait;
+
+    // Watch before looking at the game, so that no change is missed
+    let mut changes = hub.watch(game_id).await;
+    let mut changed = false;
+    loop {
+        {
+            let games = games.read().await;
+            let game = match seat_game(games.get(&game_id), game_id, query.seat, query.token.as_deref()){
+                Ok(game) => game,
+                Err(reply) => return Ok(reply), // Also when closed meanwhile
+            };
+            let ready = match query.seat{
+                Some(seat) => game.is_turn_of(seat),
+                None => changed,
+            };
+            if ready || Instant::now() >= deadline {
+                let state = match query.seat{
+                    Some(seat) => game.get_state_json(seat),
+                    None => game.get_spectator_state_json(),
+                };
+                return Ok(Box::new(with_header(state, "content-type", "application/json")));
+            }
+        }
+        changed = matches!(tokio::time::timeout_at(deadline, changes.changed()).await, Ok(Ok(())));
+    }
+}
+
+pub async fn action_handler(game_id: u64, body: ActionRequest, games: Games, store: Store, lobby: Lobby, hub: Hub) -> Result<Box<dyn Reply>> {
+    let mut locked = games.write().await;
+    let game = match seat_game(locked.get_mut(&game_id), game_id, Some(body.seat), Some(&body.token)){
+        Ok(game) => game,
+        Err(reply) => return Ok(reply),
+    };
+
+    let (action, hand_result) = match game.submit_choice(body.action, body.seat){
+        Ok(submitted) => submitted,
+        Err(e @ (ActionError::NotYourTurn{..} | ActionError::HandOver | ActionError::ChoiceNotAvailable{..})) => return Ok(error_reply(e, StatusCode::CONFLICT)),
+        Err(e) => return Ok(error_reply(e, StatusCode::BAD_REQUEST)),
+    };
+    let hand_finished = ws::publish_changes(game_id, game, hand_result.clone(), None, None, &store, &hub).await;
+    let hand_result = match (hand_result, game.hand_history().last()) {
+        (Some(result), Some(record)) => Some(result.visible_to(Some(record.position_of(body.seat)))),
+        _ => None,
+    };
+    drop(locked);
+    if hand_finished {
+        lobby::publish(&lobby, game_id, &games, &hub).await;
+    }
+
+    Ok(Box::new(json(&ActionResponse {
+        action_response: "ok".to_string(),
+        action,
+        hand_result,
+    })))
+}
+
+// Turns up hole cards of the seat in the last finished hand. Answers with all the cards the
+// seat has shown in that hand, which the other clients of the game get too.
+pub async fn show_handler(game_id: u64, body: ShowRequest, games: Games, store: Store, hub: Hub) -> Result<Box<dyn Reply>> {
+    let mut locked = games.write().await;
+    let game = match seat_game(locked.get_mut(&game_id), game_id, Some(body.seat), Some(&body.token)){
+        Ok(game) => game,
+        Err(reply) => return Ok(reply),
+    };
+    if let Err(e) = game.show_cards(body.seat, body.cards) {
+        return Ok(error_reply(e, StatusCode::CONFLICT));
+    }
+    ws::publish_changes(game_id, game, None, None, None, &store, &hub).await;
+    Ok(Box::new(json(&Envelope::event(ws::cards_shown(game, body.seat)))))
+}
+
+// Queues a decision of the seat for its next turn. Only the seat sees what it queued.
+pub async fn queue_handler(game_id: u64, body: QueueRequest, games: Games, store: Store, hub: Hub) -> Result<Box<dyn Reply>> {
+    let mut locked = games.write().await;
+    let game = match seat_game(locked.get_mut(&game_id), game_id, Some(body.seat), Some(&body.token)){
+        Ok(game) => game,
+        Err(reply) => return Ok(reply),
+    };
+    if let Err(e) = game.queue_pre_action(body.seat, body.pre_action) {
+        return Ok(error_reply(e, StatusCode::CONFLICT));
+    }
+    ws::publish_changes(game_id, game, None, None, None, &store, &hub).await;
+    Ok(Box::new(json(&Envelope::event(ServerMessage::ActionQueued{pre_action: body.pre_action}))))
+}
+
+// Server-sent events of the game, as a seat or as a spectator sees them. Resumes after the
+// Last-Event-ID that browsers send when they reconnect.
+#[allow(clippy::too_many_arguments)]
+pub async fn events_handler(game_id: u64, query: SeatQuery, last_event_id: Option<u64>, games: Games, lobby: Lobby, hub: Hub, config: Settings) -> Result<Box<dyn Reply>> {
+    let role = match games.read().await.get(&game_id).map(|game| role(game, &query)){
+        None => return Ok(error_reply(ActionError::GameNotFound{game_id}, StatusCode::NOT_FOUND)),
+        Some(Err(e)) => return Ok(error_reply(e, StatusCode::FORBIDDEN)),
+        Some(Ok(role)) => role,
+    };
+
+    if role == ClientRole::Spectator && spectators(&hub, game_id).await >= config.limits.max_spectators_per_game {
+        let maximum = config.limits.max_spectators_per_game as u64;
+        return Ok(error_reply(ActionError::LimitReached{limit: "spectators".to_string(), maximum}, StatusCode::SERVICE_UNAVAILABLE));
+    }
+
+    let stream = sse::event_stream(game_id, role, last_event_id, games, lobby, hub).await;
+    Ok(Box::new(warp::sse::reply(warp::sse::keep_alive().stream(stream))))
+}
+
+// The seat of the query, if its token is right, or a spectator without a seat
+fn role(game: &Game, query: &SeatQuery) -> std::result::Result<ClientRole, ActionError> {
+    match query.seat{
+        Some(seat) => game.check_seat_token(seat, query.token.as_deref().unwrap_or_default()).map(|_| ClientRole::Seat(seat)),
+        None => Ok(ClientRole::Spectator),
+    }
+}
+
+// A page of the finished hands that match the filter. Seats see their own hole cards, and
+// nobody sees the cards of the opponent unless they were shown.
+pub async fn hands_handler(game_id: u64, query: SeatQuery, page: PageQuery, filter: HandFilter, games: Games) -> Result<Box<dyn Reply>> {
+    let games = games.read().await;
+    let game = match seat_game(games.get(&game_id), game_id, query.seat, query.token.as_deref()){
+        Ok(game) => game,
+        Err(reply) => return Ok(reply),
+    };
+    let limit = page.limit.unwrap_or(DEFAULT_HANDS_PER_PAGE).min(MAX_HANDS_PER_PAGE);
+    Ok(Box::new(json(&history::hand_page(game, &filter, query.seat, page.offset, limit, unix_time()))))
+}
+
+// One finished hand with all its streets, to replay it
+pub async fn hand_handler(game_id: u64, hand_number: u64, query: SeatQuery, games: Games) -> Result<Box<dyn Reply>> {
+    let games = games.read().await;
+    let game = match seat_game(games.get(&game_id), game_id, query.seat, query.token.as_deref()){
+        Ok(game) => game,
+        Err(reply) => return Ok(reply),
+    };
+    match history::hand_replay(game, hand_number, query.seat, unix_time()){
+        Some(replay) => Ok(Box::new(json(&replay))),
+        None => Ok(error_reply(ActionError::HandNotFound{hand_number}, StatusCode::NOT_FOUND)),
+    }
+}
+
+pub async fn stats_handler(game_id: u64, games: Games) -> Result<impl Reply> {
+    match games.read().await.get(&game_id){
+        Some(game) => Ok(json(&game.get_stats())),
+        None => Err(warp::reject::not_found()),
+    }
+}
+
+pub async fn session_handler(game_id: u64, games: Games) -> Result<impl Reply> {
+    match games.read().await.get(&game_id){
+        Some(game) => Ok(json(&game.get_session_report())),
+        None => Err(warp::reject::not_found()),
+    }
+}
 
 pub async fn health_handler() -> Result<impl Reply> {
     Ok(StatusCode::OK)
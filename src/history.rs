@@ -0,0 +1,242 @@
+use crate::common::Position;
+use crate::game::{Game, HandRecord};
+use crate::hand::{HandResult, ShownCards};
+use crate::street::Street;
+use poker::Card;
+use serde::{Serialize, Deserialize};
+
+// Filters of the hand history. Only the hands that match all the given filters are listed.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HandFilter{
+    pub min_pot: Option<u64>,
+    pub max_pot: Option<u64>,
+    pub showdown: Option<bool>,
+    pub winner: Option<u8>, // Seat that won the pot, split pots match no seat
+    pub position: Option<Position>, // Of the seat asking
+    pub since: Option<u64>, // Seconds since the Unix epoch, when the hand finished
+    pub until: Option<u64>,
+}
+
+// A finished hand as one of the seats or a spectator may see it. Hole cards are None when hidden.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HandSummary{
+    pub hand_number: u64,
+    pub button_seat: u8,
+    pub finished_at: u64,
+    pub pot: u64,
+    pub showdown: bool,
+    pub winner: Option<u8>, // None means split pot
+    pub btn_hole_cards: Option<(String, String)>,
+    pub bb_hole_cards: Option<(String, String)>,
+    pub board_cards: Vec<String>,
+    pub shown: ShownCards, // Turned up at showdown or after the hand, also single cards
+}
+
+// Everything needed to replay a hand
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HandReplay{
+    #[serde(flatten)]
+    pub summary: HandSummary,
+    pub streets: Vec<Street>,
+    pub result: HandResult,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HandPage{
+    pub total: usize, // Hands that match the filter
+    pub offset: usize,
+    pub hands: Vec<HandSummary>,
+}
+
+impl HandRecord{
+    // Chips both players put in, without the part of a bet nobody called
+    pub fn pot(&self) -> u64{
+        let (first, last) = match (self.streets.first(), self.streets.last()){
+            (Some(first), Some(last)) => (first, last),
+            _ => return 0,
+        };
+        let btn_chips = first.btn_start_stack - last.btn_stack;
+        let bb_chips = first.bb_start_stack - last.bb_stack;
+        2 * btn_chips.min(bb_chips)
+    }
+
+    pub fn seat_of(&self, position: Position) -> u8{
+        match position{
+            Position::Button => self.button_seat,
+            Position::BigBlind => 1 - self.button_seat,
+        }
+    }
+
+    pub fn position_of(&self, seat: u8) -> Position{
+        match seat == self.button_seat{
+            true => Position::Button,
+            false => Position::BigBlind,
+        }
+    }
+
+    pub fn winner_seat(&self) -> Option<u8>{
+        self.result.winner.map(|position| self.seat_of(position))
+    }
+}
+
+impl HandFilter{
+    pub fn matches(&self, record: &HandRecord, seat: Option<u8>) -> bool{
+        let pot = record.pot();
+        let finished_at = record.finished_at;
+        self.min_pot.is_none_or(|min_pot| pot >= min_pot)
+            && self.max_pot.is_none_or(|max_pot| pot <= max_pot)
+            && self.showdown.is_none_or(|showdown| showdown == record.result.showdown.is_some())
+            && self.winner.is_none_or(|winner| record.winner_seat() == Some(winner))
+            && self.position.is_none_or(|position| seat == Some(record.seat_of(position)))
+            && self.since.is_none_or(|since| finished_at >= since)
+            && self.until.is_none_or(|until| finished_at <= until)
+    }
+}
+
+fn card_strings(cards: (Card, Card)) -> (String, String){
+    (cards.0.rank_suit_string(), cards.1.rank_suit_string())
+}
+
+impl HandSummary{
+    // A seat sees its own hole cards, and the hand of the opponent if it was shown. A spectator
+    // sees the hands that were shown, and all of them once the hand is revealed.
+    pub fn new(record: &HandRecord, viewer: Option<u8>, revealed: bool) -> HandSummary{
+        let showdown = record.result.showdown.is_some();
+        let shown = &record.result.shown;
+        let visible = |position| revealed || shown.hand_shown(position) || viewer == Some(record.seat_of(position));
+        HandSummary{
+            hand_number: record.hand_number,
+            button_seat: record.button_seat,
+            finished_at: record.finished_at,
+            pot: record.pot(),
+            showdown,
+            winner: record.winner_seat(),
+            btn_hole_cards: visible(Position::Button).then(|| card_strings(record.btn_hole_cards)),
+            bb_hole_cards: visible(Position::BigBlind).then(|| card_strings(record.bb_hole_cards)),
+            board_cards: record.board_cards.iter().map(|card| card.rank_suit_string()).collect(),
+            shown: shown.clone(),
+        }
+    }
+}
+
+// Whether the viewer may see all the cards of the hand: for spectators, once the spectator delay passed
+fn revealed(game: &Game, record: &HandRecord, viewer: Option<u8>, now: u64) -> bool{
+    viewer.is_none() && record.hand_number <= game.spectator_hands(now).len() as u64
+}
+
+// The hands that match the filter, oldest first
+pub fn hand_page(game: &Game, filter: &HandFilter, viewer: Option<u8>, offset: usize, limit: usize, now: u64) -> HandPage{
+    let matching: Vec<&HandRecord> = game.hand_history().iter().filter(|record| filter.matches(record, viewer)).collect();
+    HandPage{
+        total: matching.len(),
+        offset,
+        hands: matching.iter().skip(offset).take(limit)
+            .map(|record| HandSummary::new(record, viewer, revealed(game, record, viewer, now)))
+            .collect(),
+    }
+}
+
+// A hand by its number, starting from 1
+pub fn hand_replay(game: &Game, hand_number: u64, viewer: Option<u8>, now: u64) -> Option<HandReplay>{
+    let record = game.hand_history().get(hand_number.checked_sub(1)? as usize)?;
+    let revealed = revealed(game, record, viewer, now);
+    Some(HandReplay{
+        summary: HandSummary::new(record, viewer, revealed),
+        streets: record.streets.clone(),
+        result: match revealed{
+            true => record.result.clone(),
+            false => record.result.visible_to(viewer.map(|seat| record.position_of(seat))),
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests{
+
+    use super::*;
+    use crate::game::GameSettings;
+    use crate::game::ShowCards;
+    use crate::street::Action;
+    use poker::cards;
+
+    // Hand 1 is folded by the button, seat 0. Hand 2 goes to showdown, where the aces of
+    // seat 0 in the big blind beat the kings of seat 1.
+    fn played_game() -> Game{
+        let mut game = Game::new_with_stacks_and_sb(500, 500, 5);
+        game.submit_action_at(Action::PostBlind(5), 0, 100).unwrap();
+        game.submit_action_at(Action::PostBlind(10), 1, 100).unwrap();
+        game.submit_action_at(Action::Fold, 0, 100).unwrap();
+
+        game.deal_hand(cards!("2s 4h 6d 8d Ts Ah Ad Kc Ks").try_collect().unwrap());
+
+        game.submit_action_at(Action::PostBlind(5), 1, 200).unwrap();
+        game.submit_action_at(Action::PostBlind(10), 0, 200).unwrap();
+        game.submit_action_at(Action::Raise(40), 1, 200).unwrap();
+        game.submit_action_at(Action::Call(40), 0, 200).unwrap();
+        for _ in 0..3{
+            game.submit_action_at(Action::Check, 0, 200).unwrap();
+            game.submit_action_at(Action::Check, 1, 200).unwrap();
+        }
+        game
+    }
+
+    #[test]
+    fn test_filters(){
+        let game = played_game();
+        let numbers = |filter: HandFilter, seat| hand_page(&game, &filter, seat, 0, 10, 300).hands.iter().map(|h| h.hand_number).collect::<Vec<_>>();
+
+        assert_eq!(game.hand_history()[0].pot(), 10); // The uncalled part of the big blind goes back
+        assert_eq!(game.hand_history()[1].pot(), 80);
+        assert_eq!(numbers(HandFilter::default(), None), vec![1, 2]);
+        assert_eq!(numbers(HandFilter{min_pot: Some(20), ..Default::default()}, None), vec![2]);
+        assert_eq!(numbers(HandFilter{max_pot: Some(20), ..Default::default()}, None), vec![1]);
+        assert_eq!(numbers(HandFilter{showdown: Some(false), ..Default::default()}, None), vec![1]);
+        assert_eq!(numbers(HandFilter{winner: Some(1), showdown: Some(false), ..Default::default()}, None), vec![1]);
+        assert_eq!(numbers(HandFilter{position: Some(Position::Button), ..Default::default()}, Some(1)), vec![2]);
+        assert!(numbers(HandFilter{position: Some(Position::Button), ..Default::default()}, None).is_empty());
+        assert_eq!(numbers(HandFilter{since: Some(150), ..Default::default()}, None), vec![2]);
+        assert_eq!(numbers(HandFilter{until: Some(150), ..Default::default()}, None), vec![1]);
+
+        let page = hand_page(&game, &HandFilter::default(), None, 1, 10, 300);
+        assert_eq!((page.total, page.offset, page.hands.len()), (2, 1, 1));
+    }
+
+    #[test]
+    fn test_hidden_hole_cards(){
+        let mut game = played_game();
+
+        // Folded hand: only the seat itself sees its cards
+        let folded = |game: &Game, viewer| hand_replay(game, 1, viewer, 300).unwrap().summary;
+        let own = folded(&game, Some(0));
+        assert!(own.btn_hole_cards.is_some() && own.bb_hole_cards.is_none());
+        let own = folded(&game, Some(1));
+        assert!(own.btn_hole_cards.is_none() && own.bb_hole_cards.is_some());
+        let spectator = folded(&game, None);
+        assert!(spectator.btn_hole_cards.is_none() && spectator.bb_hole_cards.is_none());
+
+        // Showdown: the big blind shows first and wins, the kings of the button are mucked
+        let replay = hand_replay(&game, 2, Some(0), 300).unwrap();
+        assert!(replay.summary.btn_hole_cards.is_none() && replay.summary.bb_hole_cards.is_some());
+        assert!(replay.result.showdown.as_ref().unwrap().hole_cards(Position::Button).is_none());
+        assert_eq!(replay.streets.len(), 4);
+        let replay = hand_replay(&game, 2, Some(1), 300).unwrap();
+        assert!(replay.summary.btn_hole_cards.is_some() && replay.summary.bb_hole_cards.is_some());
+        assert!(hand_replay(&game, 3, None, 300).is_none() && hand_replay(&game, 0, None, 300).is_none());
+
+        // Cards shown after the hand
+        game.show_cards(1, ShowCards::First).unwrap();
+        let spectator = hand_replay(&game, 2, None, 300).unwrap().summary;
+        assert!(spectator.btn_hole_cards.is_none() && spectator.shown.btn.len() == 1);
+        game.show_cards(1, ShowCards::Both).unwrap();
+        let replay = hand_replay(&game, 2, None, 300).unwrap();
+        assert_eq!(replay.summary.btn_hole_cards, Some(("Ks".to_string(), "Kc".to_string())));
+        assert!(replay.result.showdown.unwrap().hole_cards(Position::Button).is_some());
+
+        // Spectators see all the cards once the delay has passed
+        let settings = GameSettings{spectator_delay: Some(crate::game::SpectatorDelay::Minutes(1)), ..Default::default()};
+        game.set_settings(settings);
+        assert!(hand_replay(&game, 1, None, 150).unwrap().summary.btn_hole_cards.is_none());
+        assert!(hand_replay(&game, 1, None, 160).unwrap().summary.btn_hole_cards.is_some());
+    }
+}
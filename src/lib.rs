@@ -0,0 +1,15 @@
+pub mod common;
+pub mod street;
+pub mod hand;
+pub mod game;
+pub mod cfr;
+pub mod abstraction;
+pub mod bot;
+pub mod stats;
+pub mod equity;
+pub mod session;
+pub mod error;
+pub mod storage;
+pub mod protocol;
+pub mod history;
+pub mod ranking;
@@ -0,0 +1,135 @@
+use crate::{lobby, ws, Games, Hub, Lobby, MyClients, Store};
+use rust_poker::common::unix_time;
+use rust_poker::protocol::{Envelope, ServerMessage};
+use rust_poker::storage::CloseReason;
+use std::time::Duration;
+
+// Removes the game from the server and marks it closed in storage. The clients of the game
+// are told why and disconnected. Returns false if there is no such game.
+pub async fn close_game(game_id: u64, reason: CloseReason, games: &Games, clients: &MyClients, store: &Store, lobby: &Lobby, hub: &Hub) -> bool {
+    if games.write().await.remove(&game_id).is_none() {
+        return false;
+    }
+    if let Err(e) = store.close_game(game_id, reason) {
+        eprintln!("could not store closing of game {}: {}", game_id, e);
+    }
+
+    let message = Envelope::event(ServerMessage::GameClosed{game_id, reason}).to_json();
+    hub.close(game_id, &message).await;
+    clients.write().await.retain(|_, client| client.game_id != game_id);
+
+    lobby::publish_removed(lobby, game_id);
+    println!("game {} closed: {:?}", game_id, reason);
+    true
+}
+
+// Sends the spectators of each game the finished hands whose spectator delay has passed. A delay
+// in minutes passes between hands, so it is not enough to check when a hand finishes.
+pub async fn reveal_hands(games: &Games, hub: &Hub) {
+    for (game_id, game) in games.read().await.iter() {
+        if game.settings().spectator_delay.is_some() {
+            ws::broadcast_revealed_hands(hub, game, *game_id).await;
+        }
+    }
+}
+
+// Once a minute, reveals the hands whose spectator delay has passed and closes the games in
+// which nobody has acted for longer than the limit
+pub fn spawn_lifecycle_tick(idle_limit: Duration, games: Games, clients: MyClients, store: Store, lobby: Lobby, hub: Hub) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(60));
+        loop {
+            interval.tick().await;
+            reveal_hands(&games, &hub).await;
+
+            let now = unix_time();
+            let idle: Vec<u64> = games.read().await.iter()
+                .filter(|(_, game)| now.saturating_sub(game.last_action_at()) > idle_limit.as_secs())
+                .map(|(game_id, _)| *game_id)
+                .collect();
+            for game_id in idle {
+                close_game(game_id, CloseReason::Idle, &games, &clients, &store, &lobby, &hub).await;
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::{ClientRole, MyClient};
+    use crate::pubsub::PubSub;
+    use rust_poker::game::{Game, GameSettings, SpectatorDelay};
+    use rust_poker::street::Action;
+    use rust_poker::storage::GameStore;
+    use std::collections::HashMap;
+    use std::sync::Arc;
+    use tokio::sync::{mpsc, RwLock};
+
+    #[tokio::test]
+    async fn test_close_game() {
+        let dir = std::env::temp_dir().join(format!("poker_lifecycle_{}", std::process::id()));
+        let store: Store = Arc::new(GameStore::open(&dir).unwrap());
+        let games: Games = Arc::new(RwLock::new(HashMap::new()));
+        for game_id in [1, 2] {
+            let game = Game::new();
+            store.create_game(game_id, &game).unwrap();
+            games.write().await.insert(game_id, game);
+        }
+
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        let clients: MyClients = Arc::new(RwLock::new(HashMap::from([
+            ("a".to_string(), MyClient { game_id: 1, role: ClientRole::Seat(0) }),
+            ("b".to_string(), MyClient { game_id: 2, role: ClientRole::Seat(0) }),
+        ])));
+        let hub: Hub = Arc::new(PubSub::default());
+        hub.subscribe(1, "a", ClientRole::Seat(0), sender.clone(), None).await;
+        hub.subscribe(2, "b", ClientRole::Seat(0), sender, None).await;
+        let lobby = lobby::new_lobby();
+        let mut lobby_updates = lobby.subscribe();
+
+        assert!(close_game(1, CloseReason::Idle, &games, &clients, &store, &lobby, &hub).await);
+        assert!(!close_game(1, CloseReason::Idle, &games, &clients, &store, &lobby, &hub).await);
+
+        let message = receiver.recv().await.unwrap().unwrap();
+        let message: serde_json::Value = serde_json::from_str(message.to_str().unwrap()).unwrap();
+        assert_eq!(message, serde_json::json!({"type": "game_closed", "game_id": 1, "reason": "idle"}));
+        assert!(receiver.recv().await.unwrap().unwrap().is_close());
+        assert!(receiver.try_recv().is_err()); // Nothing for the client of game 2
+
+        assert_eq!(clients.read().await.keys().collect::<Vec<_>>(), vec!["b"]);
+        assert_eq!(games.read().await.keys().collect::<Vec<_>>(), vec![&2]);
+        assert!(store.is_closed(1).unwrap());
+        assert_eq!(lobby_updates.recv().await.unwrap(), r#"{"type":"game_removed","game_id":1}"#);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_hands_are_revealed_once_the_delay_passes() {
+        let mut game = Game::new();
+        game.set_settings(GameSettings { spectator_delay: Some(SpectatorDelay::Minutes(2)), ..Default::default() });
+        let now = unix_time();
+        for (seat, action) in [(0, Action::PostBlind(5)), (1, Action::PostBlind(10)), (0, Action::Fold)] {
+            game.submit_action_at(action, seat, now - 150).unwrap();
+        }
+        for (seat, action) in [(1, Action::PostBlind(5)), (0, Action::PostBlind(10)), (1, Action::Fold)] {
+            game.submit_action_at(action, seat, now - 60).unwrap();
+        }
+        let games: Games = Arc::new(RwLock::new(HashMap::from([(1, game)])));
+
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        let hub: Hub = Arc::new(PubSub::default());
+        hub.subscribe(1, "a", ClientRole::Spectator, sender, None).await;
+
+        // Only the first hand finished more than two minutes ago, and it is sent once
+        reveal_hands(&games, &hub).await;
+        reveal_hands(&games, &hub).await;
+        let message = receiver.recv().await.unwrap().unwrap();
+        let message: serde_json::Value = serde_json::from_str(message.to_str().unwrap()).unwrap();
+        assert_eq!(message["type"], serde_json::json!("revealed_hands"));
+        assert_eq!(message["hands"].as_array().unwrap().len(), 1);
+        assert!(receiver.try_recv().is_err());
+    }
+}
@@ -0,0 +1,168 @@
+use crate::{ClientRole, Games, Hub};
+use futures::{SinkExt, StreamExt};
+use rust_poker::game::{Game, GameSettings, GameStatus};
+use serde::Serialize;
+use std::collections::HashMap;
+use tokio::sync::broadcast;
+use warp::ws::{Message, WebSocket};
+
+// Lobby updates are sent to every lobby websocket
+pub type Lobby = broadcast::Sender<String>;
+
+pub fn new_lobby() -> Lobby {
+    broadcast::channel(100).0
+}
+
+#[derive(Serialize, Debug, Clone, Copy, Default)]
+pub struct SeatSummary {
+    pub claimed: bool,
+    pub connections: usize, // Open websockets to the seat
+    pub stack: u64,
+}
+
+#[derive(Serialize, Debug)]
+pub struct GameSummary {
+    pub id: u64,
+    pub settings: GameSettings,
+    pub sb_size: u64,
+    pub bb_size: u64,
+    pub status: GameStatus,
+    pub hand_number: u64,
+    pub seats: [SeatSummary; 2],
+    pub spectators: usize,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum LobbyMessage {
+    Games { games: Vec<GameSummary> }, // Sent when connecting to the lobby
+    GameUpdated { game: GameSummary },
+    GameRemoved { game_id: u64 },
+}
+
+// Open websockets per game: the seats and the spectators
+#[derive(Default, Clone, Copy)]
+pub struct Occupancy {
+    seats: [usize; 2],
+    spectators: usize,
+}
+
+// Read the connections before locking the games, as the websocket handlers publish to
+// the connections while holding the games
+pub async fn occupancy(hub: &Hub) -> HashMap<u64, Occupancy> {
+    let mut occupancy = HashMap::<u64, Occupancy>::new();
+    for (game_id, roles) in hub.roles().await {
+        let entry = occupancy.entry(game_id).or_default();
+        for role in roles {
+            match role {
+                ClientRole::Seat(seat) if seat < 2 => entry.seats[seat as usize] += 1,
+                ClientRole::Seat(_) => (),
+                ClientRole::Spectator => entry.spectators += 1,
+            }
+        }
+    }
+    occupancy
+}
+
+pub fn summary(id: u64, game: &Game, occupancy: Occupancy) -> GameSummary {
+    let stacks = game.seat_stacks();
+    let seat = |seat: usize| SeatSummary {
+        claimed: game.is_seat_claimed(seat as u8),
+        connections: occupancy.seats[seat],
+        stack: stacks[seat],
+    };
+    GameSummary {
+        id,
+        settings: game.settings().clone(),
+        sb_size: game.current_hand().sb_size,
+        bb_size: game.current_hand().sb_size * 2,
+        status: game.status(),
+        hand_number: game.hand_number(),
+        seats: [seat(0), seat(1)],
+        spectators: occupancy.spectators,
+    }
+}
+
+pub async fn summaries(games: &Games, hub: &Hub) -> Vec<GameSummary> {
+    let occupancy = occupancy(hub).await;
+    let mut summaries: Vec<GameSummary> = games.read().await.iter()
+        .map(|(id, game)| summary(*id, game, occupancy.get(id).copied().unwrap_or_default()))
+        .collect();
+    summaries.sort_by_key(|summary| summary.id);
+    summaries
+}
+
+// Tell the lobby that a game changed. Must not be called while holding a lock on the games.
+pub async fn publish(lobby: &Lobby, game_id: u64, games: &Games, hub: &Hub) {
+    let occupancy = occupancy(hub).await.get(&game_id).copied().unwrap_or_default();
+    let message = match games.read().await.get(&game_id) {
+        Some(game) => LobbyMessage::GameUpdated { game: summary(game_id, game, occupancy) },
+        None => return,
+    };
+    let _ = lobby.send(serde_json::to_string(&message).unwrap()); // Fails only if nobody is listening
+}
+
+pub fn publish_removed(lobby: &Lobby, game_id: u64) {
+    let _ = lobby.send(serde_json::to_string(&LobbyMessage::GameRemoved { game_id }).unwrap());
+}
+
+// Send the list of games, then every update until the client disconnects
+pub async fn lobby_connection(ws: WebSocket, lobby: Lobby, games: Games, hub: Hub) {
+    let (mut ws_sender, mut ws_rcv) = ws.split();
+    let mut updates = lobby.subscribe();
+
+    let games = LobbyMessage::Games { games: summaries(&games, &hub).await };
+    if ws_sender.send(Message::text(serde_json::to_string(&games).unwrap())).await.is_err() {
+        return;
+    }
+
+    loop {
+        tokio::select! {
+            update = updates.recv() => match update {
+                Ok(update) => {
+                    if ws_sender.send(Message::text(update)).await.is_err() {
+                        break;
+                    }
+                },
+                Err(broadcast::error::RecvError::Lagged(_)) => (), // Missed some updates
+                Err(broadcast::error::RecvError::Closed) => break,
+            },
+            message = ws_rcv.next() => match message {
+                Some(Ok(_)) => (), // The lobby does not take commands
+                _ => break,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::pubsub::PubSub;
+    use std::sync::Arc;
+    use tokio::sync::{mpsc, RwLock};
+
+    #[tokio::test]
+    async fn test_summaries() {
+        let mut game = Game::new_with_stacks_and_sb(200, 300, 5);
+        game.claim_seat(0, None, None).unwrap();
+        let games: Games = Arc::new(RwLock::new(HashMap::from([(2, game), (1, Game::new())])));
+
+        let (sender, _receiver) = mpsc::unbounded_channel();
+        let hub: Hub = Arc::new(PubSub::default());
+        hub.subscribe(2, "a", ClientRole::Seat(0), sender.clone(), None).await;
+        hub.subscribe(2, "b", ClientRole::Seat(0), sender.clone(), None).await;
+        hub.subscribe(2, "c", ClientRole::Spectator, sender, None).await;
+
+        let summaries = summaries(&games, &hub).await;
+        assert_eq!(summaries.iter().map(|s| s.id).collect::<Vec<_>>(), vec![1, 2]);
+        let summary = &summaries[1];
+        assert_eq!(summary.status, GameStatus::WaitingForPlayers);
+        assert_eq!(summary.hand_number, 1);
+        assert!(summary.seats[0].claimed && !summary.seats[1].claimed);
+        assert_eq!([summary.seats[0].connections, summary.seats[1].connections], [2, 0]);
+        assert_eq!([summary.seats[0].stack, summary.seats[1].stack], [200, 300]);
+        assert_eq!(summary.spectators, 1);
+    }
+}
@@ -1,88 +1,354 @@
-use std::io::BufRead;
+use std::io::{self, BufRead, IsTerminal, Write};
 
-use poker::{cards, Card, EvalClass, Evaluator, Rank};
-mod street;
-mod common;
-mod hand;
-mod game;
+use clap::Parser;
+use rust_poker::common::{other_player, Position};
+use rust_poker::game::Game;
+use rust_poker::hand::HandResult;
+use rust_poker::street::{Action, ActionChoice, ActionOption};
 
-use street::{Action, ActionOption};
-use hand::Hand;
+#[derive(Parser, Debug)]
+#[command(name = "CLI", about = "Heads up poker for two players sharing a terminal")]
+struct Args {
+    /// Starting stack of each player
+    #[arg(long, default_value_t = 1000)]
+    stack: u64,
+    /// Small blind, the big blind is twice as much
+    #[arg(long, default_value_t = 5)]
+    small_blind: u64,
+    /// Stop after this many hands, else play until a player can not pay the blinds
+    #[arg(long)]
+    hands: Option<u64>,
+}
+
+// What a player typed at the prompt
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Command {
+    Act(ActionChoice),
+    Help,
+    Quit,
+}
+
+const HELP: &str = "\
+Commands:
+  f, fold             Fold
+  x, check            Check
+  c, call             Call
+  b, bet <to>         Bet, e.g. bet 40
+  r, raise <to>       Raise to the amount, e.g. raise 120
+  half, pot [times]   Bet or raise half the pot, the pot, or a fraction of it like pot 0.75
+  a, allin            Bet or raise all in
+  h, help             This help
+  q, quit             End the match";
+
+fn name(seat: u8) -> String {
+    format!("Player {}", seat + 1)
+}
+
+fn parse_amount(word: Option<&str>, command: &str) -> Result<u64, String> {
+    match word {
+        None => Err(format!("{} needs the amount to {}, e.g. {} 40", command, command, command)),
+        Some(word) => word.parse::<u64>().map_err(|_| format!("{} is not a number of chips", word)),
+    }
+}
+
+// Parses a line of input. Calls take their amount from the available actions.
+fn parse_command(input: &str, options: &[ActionOption]) -> Result<Command, String> {
+    let input = input.trim().to_lowercase();
+    let mut words = input.split_whitespace();
+    let command = match words.next() {
+        Some(command) => command,
+        None => return Err("Type an action, or help for the commands".to_string()),
+    };
+    let argument = words.next();
+    if words.next().is_some() {
+        return Err(format!("Too many words for {}", command));
+    }
+
+    let choice = match command {
+        "f" | "fold" => ActionChoice::Fold,
+        "x" | "k" | "check" => ActionChoice::Check,
+        "c" | "call" => match options.iter().find_map(|option| match option { ActionOption::Call(amount) => Some(*amount), _ => None }) {
+            Some(amount) => ActionChoice::Call(amount),
+            None => return Err("There is nothing to call".to_string()),
+        },
+        "b" | "bet" => ActionChoice::Bet(parse_amount(argument, "bet")?),
+        "r" | "raise" => ActionChoice::Raise(parse_amount(argument, "raise")?),
+        "half" => ActionChoice::PotFraction(0.5),
+        "pot" => match argument.map(|word| word.parse::<f64>()) {
+            None => ActionChoice::PotFraction(1.0),
+            Some(Ok(fraction)) if fraction > 0.0 && fraction.is_finite() => ActionChoice::PotFraction(fraction),
+            Some(_) => return Err(format!("{} is not a positive fraction of the pot", argument.unwrap())),
+        },
+        "a" | "allin" | "all-in" => ActionChoice::AllIn,
+        "h" | "help" | "?" => return Ok(Command::Help),
+        "q" | "quit" | "exit" => return Ok(Command::Quit),
+        _ => return Err(format!("Unknown command {}, type help for the commands", command)),
+    };
+    if argument.is_some() && !matches!(choice, ActionChoice::Bet(_) | ActionChoice::Raise(_) | ActionChoice::PotFraction(_)) {
+        return Err(format!("{} takes no amount", command));
+    }
+    Ok(Command::Act(choice))
+}
+
+fn describe(action: Action) -> String {
+    match action {
+        Action::Fold => "folds".to_string(),
+        Action::Check => "checks".to_string(),
+        Action::PostBlind(amount) => format!("posts {}", amount),
+        Action::Call(amount) => format!("calls {}", amount),
+        Action::Bet(amount) => format!("bets {}", amount),
+        Action::Raise(amount) => format!("raises to {}", amount),
+    }
+}
 
-fn play() {
+fn seat_of(game: &Game, position: Position) -> u8 {
+    match position {
+        Position::Button => game.button_seat(),
+        Position::BigBlind => 1 - game.button_seat(),
+    }
+}
 
-    let stdin = std::io::stdin();
-    let deck: Vec<Card> = Card::generate_shuffled_deck().to_vec();
-    let mut hand = Hand::new(deck, 1000, 1000, 5);
-    let options = hand.streets.last().unwrap().get_available_actions();
-    dbg!(&options);
+// The action taken for the player when there is nothing to decide: posting a blind, or
+// checking once a player is all in
+fn automatic_choice(game: &Game, options: &[ActionOption]) -> Option<ActionChoice> {
+    let hand = game.current_hand();
+    match options {
+        [ActionOption::PostBlind(amount)] => Some(ActionChoice::PostBlind(*amount)),
+        _ if (hand.btn_stack == 0 || hand.bb_stack == 0) && options.contains(&ActionOption::Check) => Some(ActionChoice::Check),
+        _ => None,
+    }
+}
 
+// Asks the player in turn for an action until there is one. None if the player quits or the
+// input ends.
+fn ask(game: &Game, seat: u8, options: &[ActionOption], lines: &mut impl Iterator<Item = io::Result<String>>) -> Option<ActionChoice> {
     loop {
-        let street = hand.streets.last().unwrap();
-        let (btn_added_chips,bb_added_chips,minimum_raise_size, active_player) = street.get_street_status();
-        println!("Pot, BB, BTN: {}, {}, {}", hand.pot, hand.bb_stack, hand.btn_stack);
-        println!("Button has: {} {}", hand.btn_hole_cards.0.to_string(), hand.btn_hole_cards.1.to_string());
-        println!("BB has: {} {}", hand.bb_hole_cards.0.to_string(), hand.bb_hole_cards.1.to_string());
-        println!("Street status (btn added, bb added, minraise, to act): {} {} {} {:?}", btn_added_chips, bb_added_chips, minimum_raise_size, active_player);
-        print!("Board: ");
-        for card in &hand.board_cards{
-            print!("{} ", card.to_string());
-        }
         println!();
+        print_table(game, seat);
+        print_options(game, seat, options);
+        print!("> ");
+        io::stdout().flush().unwrap();
+        match parse_command(&read_line(lines)?, options) {
+            Ok(Command::Act(choice)) => return Some(choice),
+            Ok(Command::Help) => println!("{}", HELP),
+            Ok(Command::Quit) => return None,
+            Err(e) => println!("{}", e),
+        }
+    }
+}
 
-        let options = street.get_available_actions();
-        let call_to_amount = match options.iter().find(|&x| match x{
-            ActionOption::Call(_) => true,
-            _ => false,
-        }) {
-            Some(ActionOption::Call(amount)) => *amount,
-            _ => 0, // Todo: make this None or something
+fn read_line(lines: &mut impl Iterator<Item = io::Result<String>>) -> Option<String> {
+    lines.next().and_then(Result::ok)
+}
+
+// Hides the cards of the last player before the next one comes to the keyboard
+fn clear_screen() {
+    if io::stdout().is_terminal() {
+        print!("\x1b[2J\x1b[H");
+    }
+}
+
+fn print_table(game: &Game, seat: u8) {
+    let hand = game.current_hand();
+    let position = |seat: u8| match seat == game.button_seat() {
+        true => "button",
+        false => "big blind",
+    };
+    let stack = |seat: u8| match seat == game.button_seat() {
+        true => hand.btn_stack,
+        false => hand.bb_stack,
+    };
+
+    println!("Hand {}", game.hand_number());
+    for player in 0..2 {
+        println!("  {} ({}): {} chips", name(player), position(player), stack(player));
+    }
+    for street in hand.streets.iter().filter(|street| !street.actions.is_empty()) {
+        let actions: Vec<String> = street.actions_with_players().iter()
+            .map(|(position, action)| format!("{} {}", name(seat_of(game, *position)), describe(*action)))
+            .collect();
+        println!("  {:?}: {}", street.street, actions.join(", "));
+    }
+    let board: Vec<String> = hand.board_cards.iter().map(|card| card.to_string()).collect();
+    println!("Board: {}", if board.is_empty() { "-".to_string() } else { board.join(" ") });
+    println!("Pot: {}", hand.pot);
+    let cards = match seat == game.button_seat() {
+        true => hand.btn_hole_cards,
+        false => hand.bb_hole_cards,
+    };
+    println!("{}, your cards: {} {}", name(seat), cards.0, cards.1);
+}
+
+// The available actions, with the sizes of the bet helpers
+fn print_options(game: &Game, seat: u8, options: &[ActionOption]) {
+    let size = |choice: ActionChoice| match game.resolve_choice(choice, seat) {
+        Ok(Action::Bet(amount) | Action::Raise(amount) | Action::Call(amount)) => amount.to_string(),
+        _ => "-".to_string(),
+    };
+    let sizes = format!("half pot {}, pot {}, all in {}", size(ActionChoice::PotFraction(0.5)), size(ActionChoice::PotFraction(1.0)), size(ActionChoice::AllIn));
+    let descriptions: Vec<String> = options.iter().map(|option| match option {
+        ActionOption::Fold => "fold".to_string(),
+        ActionOption::Check => "check".to_string(),
+        ActionOption::PostBlind(amount) => format!("post {}", amount),
+        ActionOption::Call(amount) => format!("call {}", amount),
+        ActionOption::Bet(minimum, maximum) => format!("bet {}-{} ({})", minimum, maximum, sizes),
+        ActionOption::Raise(minimum, maximum) => format!("raise to {}-{} ({})", minimum, maximum, sizes),
+    }).collect();
+    println!("You can {}", descriptions.join(", "));
+}
+
+// The result as both players may see it, without the hands that were mucked
+fn print_result(game: &Game, result: &HandResult) {
+    let record = game.hand_history().last().unwrap();
+    let result = result.visible_to(None);
+    let board: Vec<String> = record.board_cards.iter().map(|card| card.to_string()).collect();
+    println!();
+    println!("Hand {} is over. Board: {}", record.hand_number, if board.is_empty() { "-".to_string() } else { board.join(" ") });
+    if let Some(showdown) = &result.showdown {
+        for position in [showdown.first_to_show(), other_player(showdown.first_to_show())] {
+            let player = name(record.seat_of(position));
+            match (showdown.hole_cards(position), showdown.made_hand(position)) {
+                (Some(cards), Some(made_hand)) => println!("{} shows {} {}: {}", player, cards.0, cards.1, made_hand.description),
+                _ => println!("{} mucks", player),
+            }
+        }
+        if let Some(kicker) = showdown.deciding_kicker() {
+            println!("The {} kicker decides", kicker);
+        }
+    }
+    match record.winner_seat() {
+        Some(seat) => println!("{} wins a pot of {}", name(seat), record.pot()),
+        None => println!("The pot of {} is split", record.pot()),
+    }
+    let stacks = [(record.seat_of(Position::Button), result.btn_stack), (record.seat_of(Position::BigBlind), result.bb_stack)];
+    for (seat, stack) in stacks {
+        println!("  {}: {} chips", name(seat), stack);
+    }
+}
+
+fn print_summary(game: &Game) {
+    let hand = game.current_hand();
+    println!();
+    match game.hand_history().len() {
+        1 => println!("Match over after 1 hand"),
+        hands => println!("Match over after {} hands", hands),
+    }
+    for seat in 0..2 {
+        let stack = match seat == game.button_seat() {
+            true => hand.btn_start_stack,
+            false => hand.bb_start_stack,
         };
+        println!("  {}: {} chips", name(seat), stack);
+    }
+}
 
-        let input = stdin.lock().lines().next().unwrap().unwrap();
-        let tokens = input.split_whitespace().collect::<Vec<&str>>();
+fn play(args: &Args) {
+    let mut game = Game::new_with_stacks_and_sb(args.stack, args.stack, args.small_blind);
+    let stdin = io::stdin();
+    let mut lines = stdin.lock().lines();
+    let mut at_keyboard: Option<u8> = None; // The player whose cards are on the screen
 
-        if tokens.len() == 1 && tokens[0] == "hh"{
-            dbg!(&hand.streets);
+    loop {
+        if args.hands.is_some_and(|hands| game.hand_history().len() as u64 >= hands) {
+            break;
         }
-        let user_action =
-        if tokens.len() == 0{
-            None
-        } else if tokens.len() == 1 {
-            match tokens.first().unwrap(){
-                &"fold" => Some(Action::Fold),
-                &"check" => Some(Action::Check),
-                &"call" => Some(Action::Call(call_to_amount)),
-                &_ => None,
-            }
-        } else if tokens.len() == 2 {
-            // Actions that require an amount
-            let amount = tokens[1].parse::<u64>().unwrap();
-            match tokens.first().unwrap(){
-                &"bet" => Some(Action::Bet(amount)),
-                &"raise" => Some(Action::Raise(amount)),
-                &_ => None,
-            }
-        } else{ // Three or more tokens -> invalid
-            None
+        let street = game.current_hand().streets.last().unwrap();
+        let seat = seat_of(&game, street.get_street_status().3);
+        let options = street.get_available_actions();
+        if options.is_empty() {
+            println!("{} can not pay the blind anymore", name(seat));
+            break;
+        }
+
+        let choice = match automatic_choice(&game, &options) {
+            Some(choice) => choice,
+            None => {
+                if at_keyboard != Some(seat) {
+                    clear_screen();
+                    println!("{}, press Enter when the other player can not see the screen", name(seat));
+                    if read_line(&mut lines).is_none() {
+                        break;
+                    }
+                    clear_screen();
+                    at_keyboard = Some(seat);
+                }
+                match ask(&game, seat, &options, &mut lines) {
+                    Some(choice) => choice,
+                    None => break,
+                }
+            },
         };
 
-        if let Some(action) = user_action{
-            match hand.submit_action(action){
-                Ok(_) => {
-                    let options = hand.streets.last().unwrap().get_available_actions();
-                    dbg!(options);
-                },
-                Err(e) => println!("{}", e),
-            }
-        } else {
-            println!("Invalid action");
+        match game.submit_choice(choice, seat) {
+            Ok((action, hand_result)) => {
+                println!("{} {}", name(seat), describe(action));
+                if let Some(result) = hand_result {
+                    clear_screen();
+                    print_result(&game, &result);
+                    at_keyboard = None;
+                    println!("Press Enter for the next hand");
+                    if read_line(&mut lines).is_none() {
+                        break;
+                    }
+                }
+            },
+            Err(e) => println!("{}", e),
         }
     }
+
+    print_summary(&game);
 }
 
 fn main() {
 
-    play();
+    let args = Args::parse();
+    if args.small_blind == 0 || args.stack < 2 * args.small_blind {
+        eprintln!("The stacks must cover the big blind of {}", 2 * args.small_blind);
+        std::process::exit(1);
+    }
+    play(&args);
+
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_parse_command() {
+        let options = [ActionOption::Fold, ActionOption::Call(10), ActionOption::Raise(20, 1000)];
+        assert_eq!(parse_command("c", &options), Ok(Command::Act(ActionChoice::Call(10))));
+        assert_eq!(parse_command(" Raise 40 ", &options), Ok(Command::Act(ActionChoice::Raise(40))));
+        assert_eq!(parse_command("pot 0.75", &options), Ok(Command::Act(ActionChoice::PotFraction(0.75))));
+        assert_eq!(parse_command("half", &options), Ok(Command::Act(ActionChoice::PotFraction(0.5))));
+        assert_eq!(parse_command("allin", &options), Ok(Command::Act(ActionChoice::AllIn)));
+        assert_eq!(parse_command("?", &options), Ok(Command::Help));
 
+        // Errors instead of panics
+        assert_eq!(parse_command("raise lots", &options), Err("lots is not a number of chips".to_string()));
+        assert_eq!(parse_command("bet", &options), Err("bet needs the amount to bet, e.g. bet 40".to_string()));
+        assert!(parse_command("bet -5", &options).is_err());
+        assert!(parse_command("pot -1", &options).is_err());
+        assert!(parse_command("fold now", &options).is_err());
+        assert!(parse_command("raise 40 60", &options).is_err());
+        assert!(parse_command("", &options).is_err());
+        assert!(parse_command("dance", &options).is_err());
+        assert_eq!(parse_command("call", &[ActionOption::Check]), Err("There is nothing to call".to_string()));
+    }
+
+    #[test]
+    fn test_automatic_choices() {
+        let mut game = Game::new_with_stacks_and_sb(100, 100, 5);
+        let options = |game: &Game| game.current_hand().streets.last().unwrap().get_available_actions();
+        assert_eq!(automatic_choice(&game, &options(&game)), Some(ActionChoice::PostBlind(5)));
+        game.submit_action(Action::PostBlind(5), 0).unwrap();
+        game.submit_action(Action::PostBlind(10), 1).unwrap();
+        assert_eq!(automatic_choice(&game, &options(&game)), None);
+
+        // Once both are all in, the board is checked down
+        game.submit_action(Action::Raise(100), 0).unwrap();
+        game.submit_action(Action::Call(100), 1).unwrap();
+        assert_eq!(automatic_choice(&game, &options(&game)), Some(ActionChoice::Check));
+    }
 }
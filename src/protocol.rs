@@ -0,0 +1,169 @@
+use crate::common::card_serde;
+use crate::error::{ActionError, ErrorResponse};
+use crate::game::{ChatMessage, GameState, HandRecord, PreAction, ShowCards, UndoStatus};
+use crate::hand::HandResult;
+use crate::session::SessionReport;
+use crate::stats::StatsReport;
+use crate::storage::CloseReason;
+use crate::street::{Action, ActionChoice};
+use poker::Card;
+use serde::{Serialize, Deserialize};
+
+// Version of the websocket protocol. Bumped whenever a message changes in a way that
+// existing clients can not handle.
+//
+// 2: The showdown of a hand result describes each hand as btn_hand and bb_hand instead of the
+//    btn_eval and bb_eval strings, and its hole cards are null when a hand was mucked.
+//    The hello of a seat carries the token of the seat.
+pub const PROTOCOL_VERSION: u32 = 2;
+
+// Messages from the clients, tagged by their "type"
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClientMessage{
+    Hello{
+        version: u32,
+        #[serde(default)]
+        token: Option<String>, // Of the seat, needed to connect as a seat unless resuming
+        #[serde(default)]
+        resume: Option<Resume>, // After a disconnect
+    }, // Must come first, the server answers with Welcome
+    GetState,
+    GetStats,
+    GetSession,
+    Action{action: ActionChoice}, // A concrete action, or a helper like AllIn
+    QueueAction{pre_action: Option<PreAction>}, // Before the turn of the seat, None clears it
+    Undo, // Request an undo, or agree to the request of the opponent
+    Show{cards: ShowCards}, // Turn up hole cards of the last finished hand
+    Chat{text: String},
+    MuteChat{muted: bool}, // Hide the chat of the opponent, or show it again
+    GetChat,
+}
+
+// Sent by a seat that reconnects, to get the events it missed
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Resume{
+    pub token: String, // Of the seat
+    pub last_seq: u64, // Of the last event the client saw
+}
+
+// Messages from the server, tagged by their "type"
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerMessage{
+    Welcome{
+        version: u32,
+        seq: u64, // Of the latest event of the game
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        replayed: Option<bool>, // When resuming, false if some missed events were no longer kept
+    },
+    State{state: GameState},
+    Stats{stats: StatsReport},
+    Session{session: SessionReport},
+    ActionAccepted{action: Action}, // The action taken, also for a helper
+    ActionQueued{pre_action: Option<PreAction>},
+    Undo{status: UndoStatus},
+    HandResult{result: HandResult},
+    CardsShown{
+        hand_number: u64,
+        seat: u8,
+        #[serde(with = "card_serde::vec")]
+        cards: Vec<Card>, // All the cards the seat has shown in the hand
+    },
+    RevealedHands{hands: Vec<HandRecord>}, // To spectators, once the spectator delay has passed
+    GameClosed{game_id: u64, reason: CloseReason},
+    Chat{message: ChatMessage},
+    ChatMuted{muted: bool},
+    ChatHistory{messages: Vec<ChatMessage>},
+    Error{error: ActionError, message: String},
+}
+
+// A message with the request ID chosen by the client. The answer to a request carries the
+// same request ID. The events of a game, e.g. a new state after the opponent acted, carry
+// instead a sequence number, which grows with every event of the game.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Envelope<M>{
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seq: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+    #[serde(flatten)]
+    pub message: M,
+}
+
+impl ServerMessage{
+    pub fn error(error: ActionError) -> ServerMessage{
+        let ErrorResponse{error, message, ..} = ErrorResponse::new(error);
+        ServerMessage::Error{error, message}
+    }
+}
+
+impl<M: Serialize> Envelope<M>{
+    pub fn new(request_id: Option<String>, message: M) -> Envelope<M>{
+        Envelope{seq: None, request_id, message}
+    }
+
+    pub fn to_json(&self) -> String{
+        serde_json::to_string(self).unwrap()
+    }
+}
+
+impl Envelope<ServerMessage>{
+    // A message that does not answer a request
+    pub fn event(message: ServerMessage) -> Envelope<ServerMessage>{
+        Envelope::new(None, message)
+    }
+
+    pub fn sequenced(seq: u64, message: ServerMessage) -> Envelope<ServerMessage>{
+        Envelope{seq: Some(seq), ..Envelope::event(message)}
+    }
+}
+
+impl Envelope<ClientMessage>{
+    // Parses a message of a client. If it is malformed, returns the error together with
+    // the request ID, if the message had one, to answer it with.
+    pub fn parse(text: &str) -> Result<Envelope<ClientMessage>, (Option<String>, ActionError)>{
+        let malformed = |e: serde_json::Error| ActionError::MalformedCommand{message: e.to_string()};
+        let value: serde_json::Value = serde_json::from_str(text).map_err(|e| (None, malformed(e)))?;
+        let request_id = value.get("request_id").and_then(|id| id.as_str()).map(str::to_string);
+        serde_json::from_value(value).map_err(|e| (request_id, malformed(e)))
+    }
+}
+
+#[cfg(test)]
+mod tests{
+
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_envelopes(){
+        let envelope = Envelope::parse(r#"{"type": "action", "action": {"Raise": 40}, "request_id": "r1"}"#).unwrap();
+        assert_eq!(envelope.request_id.as_deref(), Some("r1"));
+        assert_eq!(envelope.message, ClientMessage::Action{action: ActionChoice::Raise(40)});
+        let helper = Envelope::parse(r#"{"type": "action", "action": {"PotFraction": 0.5}}"#).unwrap().message;
+        assert_eq!(helper, ClientMessage::Action{action: ActionChoice::PotFraction(0.5)});
+        assert_eq!(Envelope::parse(r#"{"type": "action", "action": "CheckOrFold"}"#).unwrap().message, ClientMessage::Action{action: ActionChoice::CheckOrFold});
+        let queue = Envelope::parse(r#"{"type": "queue_action", "pre_action": {"Call": 20}}"#).unwrap().message;
+        assert_eq!(queue, ClientMessage::QueueAction{pre_action: Some(PreAction::Call(20))});
+        assert_eq!(Envelope::parse(r#"{"type": "queue_action", "pre_action": null}"#).unwrap().message, ClientMessage::QueueAction{pre_action: None});
+        assert_eq!(Envelope::parse(r#"{"type": "get_state"}"#).unwrap().message, ClientMessage::GetState);
+
+        // Errors keep the request ID when there is one
+        let (request_id, error) = Envelope::parse(r#"{"type": "dance", "request_id": "r2"}"#).unwrap_err();
+        assert_eq!(request_id.as_deref(), Some("r2"));
+        assert!(matches!(error, ActionError::MalformedCommand{..}));
+        assert!(Envelope::parse("state").unwrap_err().0.is_none());
+
+        let error: serde_json::Value = serde_json::from_str(&Envelope::event(ServerMessage::error(ActionError::HandshakeRequired)).to_json()).unwrap();
+        assert_eq!(error, json!({"type": "error", "error": {"error": "handshake_required"}, "message": ActionError::HandshakeRequired.to_string()}));
+
+        let hello = Envelope::parse(r#"{"type": "hello", "version": 2, "resume": {"token": "abc", "last_seq": 7}}"#).unwrap().message;
+        assert_eq!(hello, ClientMessage::Hello{version: 2, token: None, resume: Some(Resume{token: "abc".to_string(), last_seq: 7})});
+        let welcome = Envelope::event(ServerMessage::Welcome{version: PROTOCOL_VERSION, seq: 3, replayed: None}).to_json();
+        assert_eq!(welcome, format!(r#"{{"type":"welcome","version":{},"seq":3}}"#, PROTOCOL_VERSION));
+        let accepted = Envelope::new(Some("r3".to_string()), ServerMessage::ActionAccepted{action: Action::Call(20)}).to_json();
+        assert!(matches!(serde_json::from_str(&accepted).unwrap(), Envelope{request_id: Some(id), message: ServerMessage::ActionAccepted{action: Action::Call(20)}, ..} if id == "r3"));
+        assert_eq!(Envelope::sequenced(4, ServerMessage::ActionAccepted{action: Action::Check}).to_json(), r#"{"seq":4,"type":"action_accepted","action":"Check"}"#);
+    }
+}
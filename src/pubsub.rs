@@ -0,0 +1,219 @@
+use crate::ClientRole;
+use rust_poker::protocol::{Envelope, ServerMessage};
+use std::collections::{HashMap, VecDeque};
+use tokio::sync::{mpsc, watch, RwLock};
+use warp::ws::Message;
+
+pub type ClientSender = mpsc::UnboundedSender<std::result::Result<Message, warp::Error>>;
+
+// Events kept per game for clients that resume, unless configured otherwise
+pub const DEFAULT_REPLAY_EVENTS: usize = 200;
+
+const ROLES: [ClientRole; 3] = [ClientRole::Seat(0), ClientRole::Seat(1), ClientRole::Spectator];
+
+// A connected websocket
+pub struct Subscriber {
+    pub role: ClientRole,
+    pub sender: ClientSender,
+    pub revealed_hands: usize, // Finished hands already sent to a spectator
+}
+
+// An event as each role saw it
+struct ReplayEntry {
+    seq: u64,
+    messages: Vec<(ClientRole, String)>,
+}
+
+struct Topic {
+    subscribers: HashMap<String, Subscriber>,
+    last_seq: u64, // Of the latest event, 0 before the first one
+    replay: VecDeque<ReplayEntry>, // The latest events, oldest first
+    changes: watch::Sender<u64>, // The last sequence number, for clients that poll over HTTP
+}
+
+impl Default for Topic {
+    fn default() -> Self {
+        Topic { subscribers: HashMap::new(), last_seq: 0, replay: VecDeque::new(), changes: watch::channel(0).0 }
+    }
+}
+
+// Every game is a topic, and the websockets connected to a game subscribe to it. Messages
+// published to a game reach only its own subscribers.
+pub struct PubSub {
+    topics: RwLock<HashMap<u64, Topic>>,
+    replay_events: usize,
+}
+
+impl Default for PubSub {
+    fn default() -> Self {
+        PubSub::new(DEFAULT_REPLAY_EVENTS)
+    }
+}
+
+impl PubSub {
+
+    pub fn new(replay_events: usize) -> PubSub {
+        PubSub { topics: RwLock::new(HashMap::new()), replay_events }
+    }
+
+    // Subscribes the websocket to the game. A client that resumes after a disconnect first gets
+    // the events after the last one it saw. Returns the sequence number of the latest event, and
+    // when resuming, whether all the missed events were still kept.
+    pub async fn subscribe(&self, game_id: u64, client_id: &str, role: ClientRole, sender: ClientSender, last_seq: Option<u64>) -> (u64, Option<bool>) {
+        let mut topics = self.topics.write().await;
+        let topic = topics.entry(game_id).or_default();
+
+        let replayed = last_seq.map(|last_seq| {
+            let oldest = topic.replay.front().map_or(topic.last_seq + 1, |entry| entry.seq);
+            let missed = topic.replay.iter().filter(|entry| entry.seq > last_seq);
+            for (_, message) in missed.flat_map(|entry| entry.messages.iter().filter(|(r, _)| *r == role)) {
+                let _ = sender.send(Ok(Message::text(message)));
+            }
+            last_seq + 1 >= oldest
+        });
+
+        let subscriber = Subscriber { role, sender, revealed_hands: 0 };
+        topic.subscribers.insert(client_id.to_string(), subscriber);
+        (topic.last_seq, replayed)
+    }
+
+    // The topic stays, with its events, until the game is closed
+    pub async fn unsubscribe(&self, game_id: u64, client_id: &str) {
+        if let Some(topic) = self.topics.write().await.get_mut(&game_id) {
+            topic.subscribers.remove(client_id);
+        }
+    }
+
+    // Publishes an event of the game with the next sequence number, leaving out one subscriber, e.g.
+    // the one whose request was answered already. Each role gets its own message, if any, e.g. to
+    // show only their hole cards, and the messages are kept for the clients that resume.
+    pub async fn publish_event<F: FnMut(ClientRole) -> Option<ServerMessage>>(&self, game_id: u64, except: Option<&str>, mut render: F) {
+        let mut topics = self.topics.write().await;
+        let topic = topics.entry(game_id).or_default();
+        topic.last_seq += 1;
+        let seq = topic.last_seq;
+
+        let messages: Vec<(ClientRole, String)> = ROLES.iter()
+            .filter_map(|role| render(*role).map(|message| (*role, Envelope::sequenced(seq, message).to_json())))
+            .collect();
+        for (client_id, subscriber) in topic.subscribers.iter() {
+            if Some(client_id.as_str()) == except {
+                continue;
+            }
+            if let Some((_, message)) = messages.iter().find(|(role, _)| *role == subscriber.role) {
+                let _ = subscriber.sender.send(Ok(Message::text(message)));
+            }
+        }
+
+        topic.replay.push_back(ReplayEntry { seq, messages });
+        if topic.replay.len() > self.replay_events {
+            topic.replay.pop_front();
+        }
+        topic.changes.send_replace(seq);
+    }
+
+    // Notifies about every event of the game that comes after the call. The sender is
+    // dropped when the game is closed.
+    pub async fn watch(&self, game_id: u64) -> watch::Receiver<u64> {
+        self.topics.write().await.entry(game_id).or_default().changes.subscribe()
+    }
+
+    // Sends each subscriber of the game its own message, if any, without a sequence number
+    pub async fn publish_with<F: FnMut(&mut Subscriber) -> Option<String>>(&self, game_id: u64, mut render: F) {
+        if let Some(topic) = self.topics.write().await.get_mut(&game_id) {
+            for subscriber in topic.subscribers.values_mut() {
+                if let Some(message) = render(subscriber) {
+                    let _ = subscriber.sender.send(Ok(Message::text(message)));
+                }
+            }
+        }
+    }
+
+    // Sends a last message to the subscribers of the game and closes their websockets
+    pub async fn close(&self, game_id: u64, message: &str) {
+        if let Some(topic) = self.topics.write().await.remove(&game_id) {
+            for subscriber in topic.subscribers.values() {
+                let _ = subscriber.sender.send(Ok(Message::text(message)));
+                let _ = subscriber.sender.send(Ok(Message::close()));
+            }
+        }
+    }
+
+    // Roles of the connected websockets of every game that has any
+    pub async fn roles(&self) -> HashMap<u64, Vec<ClientRole>> {
+        self.topics.read().await.iter()
+            .filter(|(_, topic)| !topic.subscribers.is_empty())
+            .map(|(game_id, topic)| (*game_id, topic.subscribers.values().map(|s| s.role).collect()))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use rust_poker::storage::CloseReason;
+
+    fn text(receiver: &mut mpsc::UnboundedReceiver<std::result::Result<Message, warp::Error>>) -> Option<String> {
+        receiver.try_recv().ok().map(|message| message.unwrap().to_str().unwrap().to_string())
+    }
+
+    // Any message will do, the game ID tells them apart
+    fn event(game_id: u64) -> Option<ServerMessage> {
+        Some(ServerMessage::GameClosed { game_id, reason: CloseReason::Closed })
+    }
+
+    #[tokio::test]
+    async fn test_messages_stay_in_their_game() {
+        let pubsub = PubSub::default();
+        let (sender_1, mut receiver_1) = mpsc::unbounded_channel();
+        let (sender_2, mut receiver_2) = mpsc::unbounded_channel();
+        pubsub.subscribe(1, "a", ClientRole::Seat(0), sender_1, None).await;
+        pubsub.subscribe(2, "b", ClientRole::Spectator, sender_2, None).await;
+
+        pubsub.publish_with(1, |_| Some("for game 1".to_string())).await;
+        pubsub.publish_with(2, |s| (s.role == ClientRole::Seat(0)).then(|| "for seat 0".to_string())).await;
+        pubsub.publish_event(1, Some("a"), |_| event(1)).await;
+        assert_eq!(text(&mut receiver_1).unwrap(), "for game 1");
+        assert!(text(&mut receiver_1).is_none());
+        assert!(text(&mut receiver_2).is_none());
+
+        assert_eq!(pubsub.roles().await[&2], vec![ClientRole::Spectator]);
+        pubsub.unsubscribe(2, "b").await;
+        assert!(!pubsub.roles().await.contains_key(&2));
+
+        let mut changes = pubsub.watch(1).await;
+        pubsub.publish_event(1, Some("a"), |_| event(1)).await;
+        assert!(changes.has_changed().unwrap());
+        assert_eq!(*changes.borrow_and_update(), 2);
+
+        pubsub.close(1, "closed").await;
+        assert!(changes.changed().await.is_err());
+        assert_eq!(text(&mut receiver_1).unwrap(), "closed");
+        assert!(receiver_1.recv().await.unwrap().unwrap().is_close());
+        assert!(pubsub.roles().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_resume() {
+        let pubsub = PubSub::new(3);
+        for n in 1..=4 {
+            pubsub.publish_event(1, None, |role| (role != ClientRole::Seat(1) || n != 3).then(|| event(n)).flatten()).await;
+        }
+
+        // Events 2 to 4 are kept, and seat 1 did not see event 3
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        assert_eq!(pubsub.subscribe(1, "a", ClientRole::Seat(1), sender, Some(1)).await, (4, Some(true)));
+        assert_eq!(text(&mut receiver).unwrap(), r#"{"seq":2,"type":"game_closed","game_id":2,"reason":"closed"}"#);
+        assert_eq!(text(&mut receiver).unwrap(), r#"{"seq":4,"type":"game_closed","game_id":4,"reason":"closed"}"#);
+        assert!(text(&mut receiver).is_none());
+
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        assert_eq!(pubsub.subscribe(1, "b", ClientRole::Seat(0), sender.clone(), Some(0)).await, (4, Some(false)));
+        assert_eq!(text(&mut receiver).unwrap(), r#"{"seq":2,"type":"game_closed","game_id":2,"reason":"closed"}"#);
+        assert!(receiver.try_recv().is_ok() && receiver.try_recv().is_ok());
+        assert_eq!(pubsub.subscribe(1, "b", ClientRole::Seat(0), sender.clone(), Some(4)).await, (4, Some(true)));
+        assert_eq!(pubsub.subscribe(1, "b", ClientRole::Seat(0), sender, None).await, (4, None));
+        assert!(text(&mut receiver).is_none());
+    }
+}
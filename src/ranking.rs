@@ -0,0 +1,200 @@
+use crate::common::card_serde;
+use crate::equity::evaluator;
+use poker::{Card, Eval, EvalClass, Rank};
+use serde::{Serialize, Deserialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HandCategory{
+    HighCard,
+    Pair,
+    TwoPair,
+    ThreeOfAKind,
+    Straight,
+    Flush,
+    FullHouse,
+    FourOfAKind,
+    StraightFlush,
+}
+
+// The best five cards of a player, e.g. to highlight them at showdown
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MadeHand{
+    pub category: HandCategory,
+    pub description: String, // Like "Two pair, Aces and Kings, Queen kicker"
+    #[serde(with = "card_serde::vec")]
+    pub best_five: Vec<Card>, // The cards that make the category first, then the kickers from high to low
+}
+
+impl HandCategory{
+    fn from_eval(eval: Eval) -> HandCategory{
+        match eval.class(){
+            EvalClass::HighCard{..} => HandCategory::HighCard,
+            EvalClass::Pair{..} => HandCategory::Pair,
+            EvalClass::TwoPair{..} => HandCategory::TwoPair,
+            EvalClass::ThreeOfAKind{..} => HandCategory::ThreeOfAKind,
+            EvalClass::Straight{..} => HandCategory::Straight,
+            EvalClass::Flush{..} => HandCategory::Flush,
+            EvalClass::FullHouse{..} => HandCategory::FullHouse,
+            EvalClass::FourOfAKind{..} => HandCategory::FourOfAKind,
+            EvalClass::StraightFlush{..} => HandCategory::StraightFlush,
+        }
+    }
+
+    // Number of cards of the best five that make the category, the rest are kickers
+    fn made_cards(self) -> usize{
+        match self{
+            HandCategory::HighCard => 1,
+            HandCategory::Pair => 2,
+            HandCategory::ThreeOfAKind => 3,
+            HandCategory::TwoPair | HandCategory::FourOfAKind => 4,
+            HandCategory::Straight | HandCategory::Flush | HandCategory::FullHouse | HandCategory::StraightFlush => 5,
+        }
+    }
+}
+
+fn rank_name(rank: Rank) -> &'static str{
+    match rank{
+        Rank::Two => "Two",
+        Rank::Three => "Three",
+        Rank::Four => "Four",
+        Rank::Five => "Five",
+        Rank::Six => "Six",
+        Rank::Seven => "Seven",
+        Rank::Eight => "Eight",
+        Rank::Nine => "Nine",
+        Rank::Ten => "Ten",
+        Rank::Jack => "Jack",
+        Rank::Queen => "Queen",
+        Rank::King => "King",
+        Rank::Ace => "Ace",
+    }
+}
+
+fn plural(rank: Rank) -> String{
+    match rank{
+        Rank::Six => "Sixes".to_string(),
+        rank => format!("{}s", rank_name(rank)),
+    }
+}
+
+// Sorts five cards the way they are read: the biggest group of a rank first, higher ranks first
+// among groups of the same size. The ace of a five-high straight goes last.
+fn sort_five(cards: &mut [Card], category: HandCategory){
+    let count = |rank: Rank| cards.iter().filter(|card| card.rank() == rank).count();
+    let mut keyed: Vec<(usize, Rank, Card)> = cards.iter().map(|card| (count(card.rank()), card.rank(), *card)).collect();
+    keyed.sort_by(|a, b| b.cmp(a));
+    let wheel = matches!(category, HandCategory::Straight | HandCategory::StraightFlush)
+        && keyed[0].1 == Rank::Ace && keyed[1].1 == Rank::Five;
+    if wheel {
+        keyed.rotate_left(1);
+    }
+    for (card, (_, _, sorted)) in cards.iter_mut().zip(keyed){
+        *card = sorted;
+    }
+}
+
+fn describe(category: HandCategory, five: &[Card]) -> String{
+    let rank = |index: usize| five[index].rank();
+    let kicker = |index: usize| format!(", {} kicker", rank_name(rank(index)));
+    match category{
+        HandCategory::HighCard => format!("High card, {}{}", rank_name(rank(0)), kicker(1)),
+        HandCategory::Pair => format!("Pair of {}{}", plural(rank(0)), kicker(2)),
+        HandCategory::TwoPair => format!("Two pair, {} and {}{}", plural(rank(0)), plural(rank(2)), kicker(4)),
+        HandCategory::ThreeOfAKind => format!("Three of a kind, {}{}", plural(rank(0)), kicker(3)),
+        HandCategory::Straight => format!("Straight, {}-high", rank_name(rank(0))),
+        HandCategory::Flush => format!("Flush, {}-high", rank_name(rank(0))),
+        HandCategory::FullHouse => format!("Full house, {} over {}", plural(rank(0)), plural(rank(3))),
+        HandCategory::FourOfAKind => format!("Four of a kind, {}{}", plural(rank(0)), kicker(4)),
+        HandCategory::StraightFlush if rank(0) == Rank::Ace => "Royal flush".to_string(),
+        HandCategory::StraightFlush => format!("Straight flush, {}-high", rank_name(rank(0))),
+    }
+}
+
+// All the ways to pick five of the cards
+fn five_card_combinations(cards: &[Card]) -> Vec<Vec<Card>>{
+    let mut combinations = Vec::new();
+    let n = cards.len();
+    for mask in 0_u32..(1 << n){
+        if mask.count_ones() == 5 {
+            combinations.push((0..n).filter(|i| mask & (1 << i) != 0).map(|i| cards[i]).collect());
+        }
+    }
+    combinations
+}
+
+impl MadeHand{
+    // The best hand out of the hole cards and at least three board cards
+    pub fn new(hole_cards: (Card, Card), board_cards: &[Card]) -> MadeHand{
+        let mut cards = vec![hole_cards.0, hole_cards.1];
+        cards.extend_from_slice(board_cards);
+        let (eval, mut best_five) = five_card_combinations(&cards).into_iter()
+            .map(|five| (evaluator().evaluate(&five).unwrap(), five))
+            .reduce(|best, next| if next.0.is_better_than(best.0) { next } else { best })
+            .unwrap();
+        let category = HandCategory::from_eval(eval);
+        sort_five(&mut best_five, category);
+        MadeHand{category, description: describe(category, &best_five), best_five}
+    }
+
+    pub fn kickers(&self) -> &[Card]{
+        &self.best_five[self.category.made_cards()..]
+    }
+}
+
+// The kicker of the winning hand that decided the showdown, if both hands made the same
+// category with the same ranks and only the kickers differ
+pub fn deciding_kicker(winner: &MadeHand, loser: &MadeHand) -> Option<Card>{
+    if winner.category != loser.category {
+        return None;
+    }
+    let made = winner.category.made_cards();
+    let differ = winner.best_five.iter().zip(&loser.best_five).position(|(a, b)| a.rank() != b.rank())?;
+    (differ >= made).then(|| winner.best_five[differ])
+}
+
+#[cfg(test)]
+mod tests{
+
+    use super::*;
+    use poker::cards;
+
+    fn made_hand(hole_cards: &str, board_cards: &str) -> MadeHand{
+        let hole: Vec<Card> = cards!(hole_cards).try_collect().unwrap();
+        let board: Vec<Card> = cards!(board_cards).try_collect().unwrap();
+        MadeHand::new((hole[0], hole[1]), &board)
+    }
+
+    fn strings(cards: &[Card]) -> Vec<String>{
+        cards.iter().map(|card| card.rank_suit_string()).collect()
+    }
+
+    #[test]
+    fn test_descriptions(){
+        let hand = made_hand("As Qd", "Ah Kc Ks 7d 2c");
+        assert_eq!(hand.category, HandCategory::TwoPair);
+        assert_eq!(hand.description, "Two pair, Aces and Kings, Queen kicker");
+        assert_eq!(strings(&hand.best_five), vec!["As", "Ah", "Kc", "Ks", "Qd"]);
+        assert_eq!(strings(hand.kickers()), vec!["Qd"]);
+
+        let hand = made_hand("As 2d", "3h 4c 5s 9d Kc");
+        assert_eq!(hand.description, "Straight, Five-high");
+        assert_eq!(strings(&hand.best_five), vec!["5s", "4c", "3h", "2d", "As"]);
+
+        assert_eq!(made_hand("6s 6d", "6h 9c 9s 2d Kc").description, "Full house, Sixes over Nines");
+        assert_eq!(made_hand("Ts Js", "Qs Ks As 2d 2c").description, "Royal flush");
+        assert_eq!(made_hand("7h 2d", "As Jc 9s 5d 4c").description, "High card, Ace, Jack kicker");
+        assert_eq!(made_hand("7h 7d", "As Jc 9s 5d 4c").description, "Pair of Sevens, Ace kicker");
+    }
+
+    #[test]
+    fn test_deciding_kicker(){
+        let winner = made_hand("As Qd", "Ah Kc 8s 7d 2c");
+        let loser = made_hand("Ac Jd", "Ah Kc 8s 7d 2c");
+        assert_eq!(deciding_kicker(&winner, &loser).map(|card| card.rank_suit_string()), Some("Qd".to_string()));
+
+        // A higher pair is not decided by a kicker
+        let loser = made_hand("Kd Qh", "Ah Kc 8s 7d 2c");
+        assert_eq!(deciding_kicker(&winner, &loser), None);
+    }
+}
@@ -1,29 +1,54 @@
 use std::collections::HashMap;
 use std::convert::Infallible;
 use std::sync::Arc;
-use tokio::sync::{mpsc, RwLock};
-use warp::{ws::Message, Filter, Rejection};
+use std::time::Duration;
+use tokio::sync::RwLock;
+use warp::{Filter, Rejection, Reply};
 
+mod config;
 mod handler;
+mod lifecycle;
+mod lobby;
+mod pubsub;
+mod sse;
 mod ws;
 
 type Result<T> = std::result::Result<T, Rejection>;
 type MyClients = Arc<RwLock<HashMap<String, MyClient>>>;
 
-mod street;
-mod common;
-mod hand;
-mod game;
-
-use game::Game;
+use rust_poker::game::Game;
+use rust_poker::storage::GameStore;
+use rust_poker::history::HandFilter;
 
 type Games = Arc<RwLock<HashMap<u64, Game>>>;
+type Store = Arc<GameStore>;
+type Settings = Arc<Config>;
+type Hub = Arc<pubsub::PubSub>;
+use clap::Parser;
+use config::{Args, Config};
+use lobby::Lobby;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClientRole {
+    Seat(u8),
+    Spectator, // Sees no hole cards until showdown or the spectator delay, and can not act
+}
+
+impl ClientRole {
+    // The seat, or None for spectators
+    pub fn seat(&self) -> Option<u8> {
+        match self {
+            ClientRole::Seat(seat) => Some(*seat),
+            ClientRole::Spectator => None,
+        }
+    }
+}
 
+// A client that joined a game. The websocket connections are in the Hub.
 #[derive(Debug, Clone)]
 pub struct MyClient {
     pub game_id: u64,
-    pub seat: u64,
-    pub sender: Option<mpsc::UnboundedSender<std::result::Result<Message, warp::Error>>>,
+    pub role: ClientRole,
 }
 
 // https://github.com/zupzup/warp-websockets-example
@@ -31,14 +56,46 @@ pub struct MyClient {
 #[tokio::main]
 async fn main() {
 
-    let games = Arc::new(RwLock::new(HashMap::<u64, Game>::new()));
+    let config = match Config::load(Args::parse()) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+
+    // Games are logged to disk and rebuilt from the logs on startup
+    let store = GameStore::open(&config.data_dir).expect("could not open the data directory");
+    let stored_games = store.load_games().expect("could not read the stored games");
+    println!("loaded {} games from storage", stored_games.len());
+    let store: Store = Arc::new(store);
+    let games = Arc::new(RwLock::new(stored_games));
 
     let myclients: MyClients = Arc::new(RwLock::new(HashMap::new()));
 
+    let lobby = lobby::new_lobby();
+    let hub: Hub = Arc::new(pubsub::PubSub::new(config.limits.replay_events));
+
+    let idle_limit = Duration::from_secs(config.limits.idle_timeout_minutes * 60);
+    lifecycle::spawn_lifecycle_tick(idle_limit, games.clone(), myclients.clone(), store.clone(), lobby.clone(), hub.clone());
+
+    let bind_address = config.bind_address;
+    let routes = routes(games, myclients, store, lobby, hub, Arc::new(config));
+
+    println!("listening on {}", bind_address);
+    warp::serve(routes).run(bind_address).await;
+}
+
+fn routes(games: Games, myclients: MyClients, store: Store, lobby: Lobby, hub: Hub, settings: Settings)
+    -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+
     let health_route = warp::path!("health").and_then(handler::health_handler);
 
-    let cors = warp::cors()
-    .allow_any_origin()
+    let cors = match settings.cors_origins.is_empty() {
+        true => warp::cors().allow_any_origin(),
+        false => warp::cors().allow_origins(settings.cors_origins.iter().map(String::as_str)),
+    };
+    let cors = cors
     .allow_headers(vec![
         "Content-Type",
         "Content-Length",
@@ -60,29 +117,168 @@ async fn main() {
         .and(warp::post())
         .and(warp::body::json())
         .and(with_clients(myclients.clone()))
+        .and(with_games(games.clone()))
+        .and(with_store(store.clone()))
+        .and(with_lobby(lobby.clone()))
+        .and(with_hub(hub.clone()))
+        .and(with_config(settings.clone()))
         .and_then(handler::join_handler);
 
+    let spectate_routes = warp::path("spectate")
+        .and(warp::post())
+        .and(warp::body::json())
+        .and(with_clients(myclients.clone()))
+        .and(with_games(games.clone()))
+        .and(with_hub(hub.clone()))
+        .and(with_config(settings.clone()))
+        .and_then(handler::spectate_handler);
+
     let create_game = warp::path("create_game");
     let create_game_routes = create_game
         .and(warp::post())
         .and(warp::body::json())
         .and(with_games(games.clone()))
+        .and(with_store(store.clone()))
+        .and(with_hub(hub.clone()))
+        .and(with_lobby(lobby.clone()))
+        .and(with_config(settings.clone()))
         .and_then(handler::create_game_handler);
 
+    let games_route = warp::path!("games")
+        .and(warp::get())
+        .and(with_games(games.clone()))
+        .and(with_hub(hub.clone()))
+        .and_then(handler::games_handler);
+
+    let game_route = warp::path!("games" / u64)
+        .and(warp::get())
+        .and(with_games(games.clone()))
+        .and(with_hub(hub.clone()))
+        .and_then(handler::game_handler);
+
+    let lobby_route = warp::path("lobby")
+        .and(warp::ws())
+        .and(with_lobby(lobby.clone()))
+        .and(with_games(games.clone()))
+        .and(with_hub(hub.clone()))
+        .and_then(handler::lobby_handler);
+
+    let close_route = warp::path!("games" / u64 / "close")
+        .and(warp::post())
+        .and(warp::body::json())
+        .and(with_games(games.clone()))
+        .and(with_clients(myclients.clone()))
+        .and(with_store(store.clone()))
+        .and(with_lobby(lobby.clone()))
+        .and(with_hub(hub.clone()))
+        .and_then(handler::close_handler);
+
+    let archive_route = warp::path!("games" / u64 / "archive")
+        .and(warp::post())
+        .and(warp::body::json())
+        .and(with_games(games.clone()))
+        .and(with_clients(myclients.clone()))
+        .and(with_store(store.clone()))
+        .and(with_lobby(lobby.clone()))
+        .and(with_hub(hub.clone()))
+        .and_then(handler::archive_handler);
+
+    let state_route = warp::path!("games" / u64 / "state")
+        .and(warp::get())
+        .and(warp::query::<handler::StateQuery>())
+        .and(with_games(games.clone()))
+        .and(with_hub(hub.clone()))
+        .and_then(handler::state_handler);
+
+    let actions_route = warp::path!("games" / u64 / "actions")
+        .and(warp::post())
+        .and(warp::body::json())
+        .and(with_games(games.clone()))
+        .and(with_store(store.clone()))
+        .and(with_lobby(lobby.clone()))
+        .and(with_hub(hub.clone()))
+        .and_then(handler::action_handler);
+
+    let show_route = warp::path!("games" / u64 / "show")
+        .and(warp::post())
+        .and(warp::body::json())
+        .and(with_games(games.clone()))
+        .and(with_store(store.clone()))
+        .and(with_hub(hub.clone()))
+        .and_then(handler::show_handler);
+
+    let queue_route = warp::path!("games" / u64 / "queue")
+        .and(warp::post())
+        .and(warp::body::json())
+        .and(with_games(games.clone()))
+        .and(with_store(store.clone()))
+        .and(with_hub(hub.clone()))
+        .and_then(handler::queue_handler);
+
+    let events_route = warp::path!("games" / u64 / "events")
+        .and(warp::get())
+        .and(warp::query::<handler::SeatQuery>())
+        .and(warp::sse::last_event_id::<u64>())
+        .and(with_games(games.clone()))
+        .and(with_lobby(lobby.clone()))
+        .and(with_hub(hub.clone()))
+        .and(with_config(settings.clone()))
+        .and_then(handler::events_handler);
+
+    let hands_route = warp::path!("games" / u64 / "hands")
+        .and(warp::get())
+        .and(warp::query::<handler::SeatQuery>())
+        .and(warp::query::<handler::PageQuery>())
+        .and(warp::query::<HandFilter>())
+        .and(with_games(games.clone()))
+        .and_then(handler::hands_handler);
+
+    let hand_route = warp::path!("games" / u64 / "hands" / u64)
+        .and(warp::get())
+        .and(warp::query::<handler::SeatQuery>())
+        .and(with_games(games.clone()))
+        .and_then(handler::hand_handler);
+
+    let stats_route = warp::path!("games" / u64 / "stats")
+        .and(warp::get())
+        .and(with_games(games.clone()))
+        .and_then(handler::stats_handler);
+
+    let session_route = warp::path!("games" / u64 / "session")
+        .and(warp::get())
+        .and(with_games(games.clone()))
+        .and_then(handler::session_handler);
+
     let ws_route = warp::path("ws")
         .and(warp::ws())
         .and(warp::path::param())
         .and(with_clients(myclients.clone()))
         .and(with_games(games.clone()))
+        .and(with_store(store.clone()))
+        .and(with_lobby(lobby.clone()))
+        .and(with_hub(hub.clone()))
         .and_then(handler::ws_handler);
 
-    let routes = health_route
+    health_route
         .or(create_game_routes)
         .or(join_routes)
+        .or(spectate_routes)
+        .or(games_route)
+        .or(game_route)
+        .or(lobby_route)
+        .or(close_route)
+        .or(archive_route)
+        .or(state_route)
+        .or(actions_route)
+        .or(show_route)
+        .or(queue_route)
+        .or(events_route)
+        .or(hands_route)
+        .or(hand_route)
+        .or(stats_route)
+        .or(session_route)
         .or(ws_route)
-        .with(cors);
-
-    warp::serve(routes).run(([127, 0, 0, 1], 8000)).await;
+        .with(cors)
 }
 
 fn with_clients(clients: MyClients) -> impl Filter<Extract = (MyClients,), Error = Infallible> + Clone {
@@ -91,4 +287,397 @@ fn with_clients(clients: MyClients) -> impl Filter<Extract = (MyClients,), Error
 
 fn with_games(games: Games) -> impl Filter<Extract = (Games,), Error = Infallible> + Clone {
     warp::any().map(move || games.clone())
-}
\ No newline at end of file
+}
+
+fn with_store(store: Store) -> impl Filter<Extract = (Store,), Error = Infallible> + Clone {
+    warp::any().map(move || store.clone())
+}
+
+fn with_lobby(lobby: Lobby) -> impl Filter<Extract = (Lobby,), Error = Infallible> + Clone {
+    warp::any().map(move || lobby.clone())
+}
+
+fn with_config(config: Settings) -> impl Filter<Extract = (Settings,), Error = Infallible> + Clone {
+    warp::any().map(move || config.clone())
+}
+
+fn with_hub(hub: Hub) -> impl Filter<Extract = (Hub,), Error = Infallible> + Clone {
+    warp::any().map(move || hub.clone())
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use serde_json::{json, Value};
+    use std::path::PathBuf;
+    use rust_poker::protocol::PROTOCOL_VERSION;
+    use warp::test::WsClient;
+
+    struct TestServer {
+        routes: warp::filters::BoxedFilter<(Box<dyn Reply>,)>,
+        dir: PathBuf,
+    }
+
+    impl TestServer {
+        fn new(name: &str) -> TestServer {
+            let dir = std::env::temp_dir().join(format!("poker_server_{}_{}", name, std::process::id()));
+            let _ = std::fs::remove_dir_all(&dir);
+            let store: Store = Arc::new(GameStore::open(&dir).unwrap());
+            let routes = routes(Arc::new(RwLock::new(HashMap::new())), Arc::new(RwLock::new(HashMap::new())), store,
+                lobby::new_lobby(), Arc::new(pubsub::PubSub::default()), Arc::new(Config::default()));
+            TestServer { routes: routes.map(|reply| Box::new(reply) as Box<dyn Reply>).boxed(), dir }
+        }
+
+        async fn post(&self, path: &str, body: Value) -> Value {
+            let response = warp::test::request().method("POST").path(path).json(&body).reply(&self.routes).await;
+            serde_json::from_slice(response.body()).unwrap()
+        }
+
+        // Joins the seat, or spectates for None, and connects to the websocket
+        async fn connect(&self, game_id: u64, seat: Option<u8>) -> WsClient {
+            let joined = match seat {
+                Some(seat) => self.post("/join", json!({"game_id": game_id, "seat": seat})).await,
+                None => self.post("/spectate", json!({"game_id": game_id})).await,
+            };
+            let url = joined["url"].as_str().unwrap();
+            let path = &url[url.find("/ws/").unwrap()..];
+            let mut client = warp::test::ws().path(path).handshake(self.routes.clone()).await.unwrap();
+
+            // The client is subscribed to the game once it is welcomed
+            client.send_text(json!({"type": "hello", "version": PROTOCOL_VERSION, "token": joined["token"]}).to_string()).await;
+            let welcome = recv(&mut client).await;
+            assert_eq!((&welcome["type"], &welcome["version"]), (&json!("welcome"), &json!(PROTOCOL_VERSION)));
+            client
+        }
+    }
+
+    impl Drop for TestServer {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    async fn recv(client: &mut WsClient) -> Value {
+        let message = tokio::time::timeout(Duration::from_secs(5), client.recv()).await.unwrap().unwrap();
+        serde_json::from_str(message.to_str().unwrap()).unwrap()
+    }
+
+    // All the messages until the client has been silent for a while
+    async fn drain(client: &mut WsClient) -> Vec<Value> {
+        let mut messages = Vec::new();
+        while let Ok(message) = tokio::time::timeout(Duration::from_millis(200), client.recv()).await {
+            messages.push(serde_json::from_str(message.unwrap().to_str().unwrap()).unwrap());
+        }
+        messages
+    }
+
+    // Sends the request and waits for its answer, skipping the messages sent in the meantime
+    async fn request(client: &mut WsClient, request_id: &str, mut message: Value) -> Value {
+        message["request_id"] = json!(request_id);
+        client.send_text(message.to_string()).await;
+        loop {
+            let message = recv(client).await;
+            if message["request_id"] == json!(request_id) {
+                return message;
+            }
+        }
+    }
+
+    // Sends the action and waits until it is accepted. Returns the action taken.
+    async fn act(client: &mut WsClient, action: Value) -> Value {
+        let answer = request(client, "act", json!({"type": "action", "action": action})).await;
+        assert_eq!((&answer["type"], &answer["request_id"]), (&json!("action_accepted"), &json!("act")));
+        answer["action"].clone()
+    }
+
+    fn hand_results(messages: &[Value]) -> Vec<&Value> {
+        messages.iter().filter(|message| message["type"] == json!("hand_result")).map(|message| &message["result"]).collect()
+    }
+
+    #[tokio::test]
+    async fn test_events_reach_only_their_game() {
+        let server = TestServer::new("isolation");
+        for id in [1, 2] {
+            server.post("/create_game", json!({"id": id, "sb_size": 5, "stacks": [100, 100]})).await;
+        }
+
+        let mut game_1 = [server.connect(1, Some(0)).await, server.connect(1, Some(1)).await, server.connect(1, None).await];
+        let mut game_2 = [server.connect(2, Some(0)).await, server.connect(2, Some(1)).await, server.connect(2, None).await];
+
+        // Game 1 ends with a fold
+        act(&mut game_1[0], json!({"PostBlind": 5})).await;
+        act(&mut game_1[1], json!({"PostBlind": 10})).await;
+        act(&mut game_1[0], json!("Fold")).await;
+
+        for client in game_1.iter_mut() {
+            let messages = drain(client).await;
+            let results = hand_results(&messages);
+            assert_eq!(results.len(), 1);
+            assert_eq!(results[0]["winner"], json!("BigBlind"));
+        }
+        let spectator_messages = drain(&mut game_1[2]).await;
+        assert!(spectator_messages.is_empty()); // Already drained above
+
+        // The winner shows its cards to everyone in game 1
+        let answer = request(&mut game_1[1], "show", json!({"type": "show", "cards": "both"})).await;
+        assert_eq!((&answer["type"], answer["cards"].as_array().unwrap().len()), (&json!("cards_shown"), 2));
+        for index in [0, 2] {
+            let shown = recv(&mut game_1[index]).await;
+            assert_eq!((&shown["type"], &shown["seat"], &shown["cards"]), (&json!("cards_shown"), &json!(1), &answer["cards"]));
+        }
+        for client in game_2.iter_mut() {
+            assert!(drain(client).await.is_empty());
+        }
+
+        // Game 2 goes to showdown
+        act(&mut game_2[0], json!({"PostBlind": 5})).await;
+        act(&mut game_2[1], json!({"PostBlind": 10})).await;
+        assert_eq!(act(&mut game_2[0], json!("CheckOrCall")).await, json!({"Call": 10}));
+        act(&mut game_2[1], json!("Check")).await;
+        for _ in 0..2 {
+            act(&mut game_2[1], json!("Check")).await;
+            act(&mut game_2[0], json!("Check")).await;
+        }
+
+        // On the river, the button checks from its queue right after the big blind
+        let answer = request(&mut game_2[0], "queue", json!({"type": "queue_action", "pre_action": "CheckOrFold"})).await;
+        assert_eq!(answer, json!({"type": "action_queued", "pre_action": "CheckOrFold", "request_id": "queue"}));
+        act(&mut game_2[1], json!("Check")).await;
+
+        for client in game_2.iter_mut() {
+            let messages = drain(client).await;
+            let results = hand_results(&messages);
+            assert_eq!(results.len(), 1);
+            assert!(results[0]["showdown"].is_object());
+            assert!(!results[0]["shown"]["btn"].as_array().unwrap().is_empty() || !results[0]["shown"]["bb"].as_array().unwrap().is_empty());
+        }
+        for client in game_1.iter_mut() {
+            assert!(drain(client).await.is_empty());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_spectators_see_no_hole_cards() {
+        let server = TestServer::new("spectator");
+        server.post("/create_game", json!({"id": 5})).await;
+        let mut seat = server.connect(5, Some(0)).await;
+        let mut spectator = server.connect(5, None).await;
+
+        act(&mut seat, json!({"PostBlind": 5})).await;
+        let states = drain(&mut spectator).await;
+        assert_eq!(states.len(), 1);
+        assert_eq!(states[0]["type"], json!("state"));
+        assert!(states[0]["state"]["btn_hole_cards"].is_null() && states[0]["state"]["bb_hole_cards"].is_null());
+
+        let answer = request(&mut spectator, "fold", json!({"type": "action", "action": "Fold"})).await;
+        assert_eq!(answer["error"]["error"], json!("spectator_cannot_act"));
+    }
+
+    #[tokio::test]
+    async fn test_handshake() {
+        let server = TestServer::new("handshake");
+        server.post("/create_game", json!({"id": 6})).await;
+        let joined = server.post("/join", json!({"game_id": 6, "seat": 1})).await;
+        let url = joined["url"].as_str().unwrap();
+        let path = &url[url.find("/ws/").unwrap()..];
+
+        let mut client = warp::test::ws().path(path).handshake(server.routes.clone()).await.unwrap();
+        let answer = request(&mut client, "1", json!({"type": "get_state"})).await;
+        assert_eq!(answer["error"]["error"], json!("handshake_required"));
+        let answer = request(&mut client, "2", json!({"type": "hello", "version": PROTOCOL_VERSION + 1})).await;
+        assert_eq!(answer["error"], json!({"error": "unsupported_version", "version": PROTOCOL_VERSION + 1, "supported": PROTOCOL_VERSION}));
+        client.recv_closed().await.unwrap();
+
+        let mut client = server.connect(6, Some(0)).await;
+        let answer = request(&mut client, "3", json!({"type": "get_state"})).await;
+        assert_eq!(answer["type"], json!("state"));
+        assert_eq!(answer["state"]["available_actions"].as_array().unwrap().len(), 1); // The small blind
+    }
+
+    #[tokio::test]
+    async fn test_chat() {
+        let server = TestServer::new("chat");
+        server.post("/create_game", json!({"id": 7})).await;
+        let mut seats = [server.connect(7, Some(0)).await, server.connect(7, Some(1)).await];
+        let mut spectator = server.connect(7, None).await;
+
+        let answer = request(&mut seats[0], "hi", json!({"type": "chat", "text": "hi"})).await;
+        assert_eq!(answer["message"]["text"], json!("hi"));
+        assert_eq!(answer["message"]["seat"], json!(0));
+        let messages = drain(&mut seats[1]).await;
+        assert_eq!(messages, vec![json!({"seq": 1, "type": "chat", "message": answer["message"]})]);
+        assert!(drain(&mut spectator).await.is_empty());
+        assert!(drain(&mut seats[0]).await.is_empty());
+
+        let answer = request(&mut seats[1], "mute", json!({"type": "mute_chat", "muted": true})).await;
+        assert_eq!(answer["type"], json!("chat_muted"));
+        request(&mut seats[0], "again", json!({"type": "chat", "text": "hello?"})).await;
+        assert!(drain(&mut seats[1]).await.is_empty());
+
+        let history = request(&mut seats[0], "history", json!({"type": "get_chat"})).await;
+        assert_eq!(history["messages"].as_array().unwrap().len(), 2);
+        let answer = request(&mut spectator, "chat", json!({"type": "chat", "text": "hi"})).await;
+        assert_eq!(answer["error"]["error"], json!("spectator_cannot_act"));
+    }
+
+    #[tokio::test]
+    async fn test_reconnect() {
+        let server = TestServer::new("reconnect");
+        server.post("/create_game", json!({"id": 8})).await;
+        let mut seat_0 = server.connect(8, Some(0)).await;
+        let joined = server.post("/join", json!({"game_id": 8, "seat": 1})).await;
+        let url = joined["url"].as_str().unwrap();
+        let path = &url[url.find("/ws/").unwrap()..];
+        let token = joined["token"].as_str().unwrap();
+
+        let mut seat_1 = warp::test::ws().path(path).handshake(server.routes.clone()).await.unwrap();
+
+        // Knowing the URL of the seat is not enough without its token
+        let answer = request(&mut seat_1, "no token", json!({"type": "hello", "version": PROTOCOL_VERSION})).await;
+        assert_eq!(answer["error"]["error"], json!("invalid_seat_token"));
+        let answer = request(&mut seat_1, "chat", json!({"type": "chat", "text": "hi"})).await;
+        assert_eq!(answer["error"]["error"], json!("handshake_required"));
+
+        let welcome = request(&mut seat_1, "hello", json!({"type": "hello", "version": PROTOCOL_VERSION, "token": token})).await;
+        let last_seq = welcome["seq"].as_u64().unwrap();
+        act(&mut seat_0, json!({"PostBlind": 5})).await;
+        assert_eq!(recv(&mut seat_1).await["seq"], json!(last_seq + 1));
+        drop(seat_1);
+
+        // Seat 1 misses two chat messages, and gets them when it is back with the same URL
+        for text in ["still there?", "hello?"] {
+            request(&mut seat_0, text, json!({"type": "chat", "text": text})).await;
+        }
+        let joined = server.post("/join", json!({"game_id": 8, "seat": 1, "token": token})).await;
+        assert_eq!(joined["url"].as_str().unwrap(), url);
+
+        let mut seat_1 = warp::test::ws().path(path).handshake(server.routes.clone()).await.unwrap();
+        let resume = |token: &str| json!({"type": "hello", "version": PROTOCOL_VERSION, "resume": {"token": token, "last_seq": last_seq + 1}});
+        let answer = request(&mut seat_1, "guess", resume("guess")).await;
+        assert_eq!(answer["error"]["error"], json!("invalid_seat_token"));
+
+        seat_1.send_text(resume(token).to_string()).await;
+        for (seq, text) in [(last_seq + 2, "still there?"), (last_seq + 3, "hello?")] {
+            let missed = recv(&mut seat_1).await;
+            assert_eq!((&missed["seq"], &missed["message"]["text"]), (&json!(seq), &json!(text)));
+        }
+        let welcome = recv(&mut seat_1).await;
+        assert_eq!((&welcome["seq"], &welcome["replayed"]), (&json!(last_seq + 3), &json!(true)));
+        act(&mut seat_1, json!({"PostBlind": 10})).await;
+    }
+
+    #[tokio::test]
+    async fn test_rest_actions_and_long_poll() {
+        let server = TestServer::new("rest");
+        server.post("/create_game", json!({"id": 9})).await;
+        let token_0 = server.post("/join", json!({"game_id": 9, "seat": 0})).await["token"].clone();
+        let token_1 = server.post("/join", json!({"game_id": 9, "seat": 1})).await["token"].clone();
+        let mut spectator = server.connect(9, None).await;
+
+        for endpoint in ["state", "events"] {
+            let path = format!("/games/9/{}?seat=1&token={}", endpoint, token_0.as_str().unwrap());
+            assert_eq!(warp::test::request().path(&path).reply(&server.routes).await.status(), 403);
+        }
+
+        // Seat 1 waits for its turn, which comes when seat 0 posts the small blind
+        let routes = server.routes.clone();
+        let path = format!("/games/9/state?seat=1&token={}&wait=5", token_1.as_str().unwrap());
+        let poll = tokio::spawn(async move { warp::test::request().path(&path).reply(&routes).await });
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(!poll.is_finished());
+
+        let act = |seat: u8, token: &Value, action: Value| {
+            let body = json!({"seat": seat, "token": token, "action": action});
+            warp::test::request().method("POST").path("/games/9/actions").json(&body).reply(&server.routes)
+        };
+        let response = act(1, &token_1, json!({"PostBlind": 10})).await;
+        assert_eq!(response.status(), 409);
+        let response = act(0, &token_0, json!({"PostBlind": 5})).await;
+        assert_eq!(serde_json::from_slice::<Value>(response.body()).unwrap(), json!({"action_response": "ok", "action": {"PostBlind": 5}, "hand_result": null}));
+
+        let response = poll.await.unwrap();
+        let state: Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(state["available_actions"], json!([{"PostBlind": 10}]));
+        assert_eq!(recv(&mut spectator).await["state"]["pot_size"], json!(5)); // The websockets see it too
+
+        // Without wait, the state comes right away
+        let response = warp::test::request().path("/games/9/state").reply(&server.routes).await;
+        let state: Value = serde_json::from_slice(response.body()).unwrap();
+        assert!(state["btn_hole_cards"].is_null() && state["bb_hole_cards"].is_null());
+
+        // Helpers answer with the action they stood for
+        act(1, &token_1, json!({"PostBlind": 10})).await;
+        let response = act(0, &token_0, json!("CheckOrFold")).await;
+        assert_eq!(serde_json::from_slice::<Value>(response.body()).unwrap()["action"], json!("Fold"));
+        let response = act(0, &token_0, json!("AllIn")).await;
+        assert_eq!(response.status(), 409);
+    }
+
+    #[tokio::test]
+    async fn test_hand_history() {
+        let server = TestServer::new("hands");
+        server.post("/create_game", json!({"id": 10})).await;
+        let token_0 = server.post("/join", json!({"game_id": 10, "seat": 0})).await["token"].clone();
+        let token_1 = server.post("/join", json!({"game_id": 10, "seat": 1})).await["token"].clone();
+        for (seat, token, action) in [(0, &token_0, json!({"PostBlind": 5})), (1, &token_1, json!({"PostBlind": 10})), (0, &token_0, json!("Fold"))] {
+            let body = json!({"seat": seat, "token": token, "action": action});
+            warp::test::request().method("POST").path("/games/10/actions").json(&body).reply(&server.routes).await;
+        }
+        let routes = &server.routes;
+        let get = |path: String| async move {
+            let response = warp::test::request().path(&path).reply(routes).await;
+            (response.status(), serde_json::from_slice::<Value>(response.body()).unwrap())
+        };
+
+        // The button folded, so only seat 0 sees its own hole cards
+        let (_, page) = get(format!("/games/10/hands?seat=0&token={}&showdown=false&winner=1", token_0.as_str().unwrap())).await;
+        assert_eq!((&page["total"], &page["hands"][0]["pot"]), (&json!(1), &json!(10)));
+        assert!(page["hands"][0]["btn_hole_cards"].is_array() && page["hands"][0]["bb_hole_cards"].is_null());
+        let (_, replay) = get(format!("/games/10/hands/1?seat=1&token={}", token_1.as_str().unwrap())).await;
+        assert!(replay["btn_hole_cards"].is_null() && replay["bb_hole_cards"].is_array());
+        assert_eq!(replay["streets"][0]["actions"].as_array().unwrap().len(), 3);
+
+        assert_eq!(get("/games/10/hands?position=Button&offset=0&limit=5".to_string()).await.1["total"], json!(0));
+        assert_eq!(get("/games/10/hands?min_pot=11".to_string()).await.1["total"], json!(0));
+        assert_eq!(get("/games/10/hands/2".to_string()).await.0, 404);
+        assert_eq!(get(format!("/games/10/hands/1?seat=1&token={}", token_0.as_str().unwrap())).await.0, 403);
+    }
+
+    #[tokio::test]
+    async fn test_spectator_limit_counts_connections() {
+        let server = TestServer::new("spectator_limit");
+        server.post("/create_game", json!({"id": 10})).await;
+        let limit = Config::default().limits.max_spectators_per_game;
+
+        // URLs that are never connected to do not lock out other spectators
+        for _ in 0..limit + 5 {
+            assert!(server.post("/spectate", json!({"game_id": 10})).await["url"].is_string());
+        }
+
+        let mut spectators = Vec::new();
+        for _ in 0..limit {
+            spectators.push(server.connect(10, None).await);
+        }
+        let answer = server.post("/spectate", json!({"game_id": 10})).await;
+        assert_eq!(answer["error"]["error"], json!("limit_reached"));
+    }
+
+    #[tokio::test]
+    async fn test_lobby_lists_games() {
+        let server = TestServer::new("lobby");
+        for id in [3, 4] {
+            server.post("/create_game", json!({"id": id, "sb_size": id})).await;
+        }
+        let _seat = server.connect(4, Some(1)).await;
+
+        let response = warp::test::request().path("/games").reply(&server.routes).await;
+        let games: Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(games.as_array().unwrap().len(), 2);
+        assert_eq!(games[1]["id"], json!(4));
+        assert_eq!((&games[1]["sb_size"], &games[1]["bb_size"]), (&json!(4), &json!(8)));
+        assert_eq!(games[1]["seats"][1]["claimed"], json!(true));
+        assert_eq!(games[1]["seats"][1]["connections"], json!(1));
+        assert_eq!(games[1]["seats"][0]["stack"], json!(Config::default().defaults.stack));
+    }
+}
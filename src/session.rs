@@ -0,0 +1,81 @@
+use serde::{Serialize, Deserialize};
+
+use crate::game::HandRecord;
+
+// Results of one seat over a session. Adjusted results replace the outcome of
+// hands that ended in an all in before the river with the all-in equity share of the pot.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SeatSession{
+    pub seat: u8,
+    pub net_chips: i64,
+    pub adjusted_net_chips: f64,
+    pub bb_per_100: Option<f64>,
+    pub adjusted_bb_per_100: Option<f64>,
+    pub all_in_hands: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SessionReport{
+    pub hands: u64,
+    pub seats: Vec<SeatSession>,
+}
+
+pub fn session_report(history: &[HandRecord]) -> SessionReport{
+
+    let mut net_chips = [0_i64; 2];
+    let mut adjusted_net_chips = [0.0; 2];
+    let mut net_bb = [0.0; 2];
+    let mut adjusted_net_bb = [0.0; 2];
+    let mut all_in_hands = [0_u64; 2];
+
+    for record in history{
+        let preflop = &record.streets[0];
+        let bb_size = preflop.min_open_raise as f64;
+        let btn_seat = record.button_seat as usize;
+        let bb_seat = 1 - btn_seat;
+
+        let btn_net = record.result.btn_stack as i64 - preflop.btn_start_stack as i64;
+        let bb_net = record.result.bb_stack as i64 - preflop.bb_start_stack as i64;
+
+        // Each player gets the expected share of the pot, less what they put in. Hands from older
+        // logs have no contributions, and both players put in half of the pot.
+        let (btn_adjusted, bb_adjusted) = match &record.result.all_in{
+            Some(all_in) => {
+                all_in_hands[btn_seat] += 1;
+                all_in_hands[bb_seat] += 1;
+                let (btn_contribution, bb_contribution) = match all_in.btn_contribution + all_in.bb_contribution{
+                    0 => (all_in.pot as f64 / 2.0, all_in.pot as f64 / 2.0),
+                    _ => (all_in.btn_contribution as f64, all_in.bb_contribution as f64),
+                };
+                (all_in.btn_expected_share - btn_contribution, all_in.bb_expected_share - bb_contribution)
+            },
+            None => (btn_net as f64, bb_net as f64),
+        };
+
+        net_chips[btn_seat] += btn_net;
+        net_chips[bb_seat] += bb_net;
+        adjusted_net_chips[btn_seat] += btn_adjusted;
+        adjusted_net_chips[bb_seat] += bb_adjusted;
+        net_bb[btn_seat] += btn_net as f64 / bb_size;
+        net_bb[bb_seat] += bb_net as f64 / bb_size;
+        adjusted_net_bb[btn_seat] += btn_adjusted / bb_size;
+        adjusted_net_bb[bb_seat] += bb_adjusted / bb_size;
+    }
+
+    let hands = history.len() as u64;
+    let per_100 = |bb: f64| match hands{
+        0 => None,
+        _ => Some(100.0 * bb / hands as f64),
+    };
+
+    let seats = (0..2).map(|seat| SeatSession{
+        seat: seat as u8,
+        net_chips: net_chips[seat],
+        adjusted_net_chips: adjusted_net_chips[seat],
+        bb_per_100: per_100(net_bb[seat]),
+        adjusted_bb_per_100: per_100(adjusted_net_bb[seat]),
+        all_in_hands: all_in_hands[seat],
+    }).collect();
+
+    SessionReport{hands, seats}
+}
@@ -0,0 +1,38 @@
+use std::path::Path;
+
+use rust_poker::abstraction::{solve, AbstractionConfig};
+use rust_poker::cfr::CfrVariant;
+use rust_poker::street::StreetName;
+
+// Solves a small abstracted heads-up game and writes the strategy to a JSON file
+// that can be loaded with Bot::load.
+fn main() {
+
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() < 2 {
+        eprintln!("Usage: {} <output.json> [iterations]", args[0]);
+        std::process::exit(1);
+    }
+
+    let iterations = match args.get(2).map(|s| s.parse::<u64>()){
+        None => 1000,
+        Some(Ok(n)) => n,
+        Some(Err(e)) => {
+            eprintln!("Invalid number of iterations: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let config = AbstractionConfig{
+        sb_size: 5,
+        stack: 1000,
+        bet_sizes: vec![0.5, 1.0],
+        buckets: 8,
+        last_street: StreetName::Flop,
+        max_raises_per_street: 2,
+    };
+
+    let solution = solve(config, CfrVariant::Plus, iterations);
+    solution.save(Path::new(&args[1])).unwrap();
+    println!("Wrote a strategy with {} information sets to {}", solution.strategy.info_sets.len(), args[1]);
+}
@@ -0,0 +1,98 @@
+use crate::{lobby, ClientRole, Games, Hub, Lobby};
+use futures::{future, Stream, StreamExt};
+use std::convert::Infallible;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::UnboundedReceiverStream;
+use uuid::Uuid;
+use warp::sse::Event;
+
+// Unsubscribes the stream from the game when the client goes away
+struct Subscription {
+    game_id: u64,
+    connection_id: String,
+    games: Games,
+    lobby: Lobby,
+    hub: Hub,
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        let (game_id, connection_id) = (self.game_id, std::mem::take(&mut self.connection_id));
+        let (games, lobby, hub) = (self.games.clone(), self.lobby.clone(), self.hub.clone());
+        tokio::spawn(async move {
+            hub.unsubscribe(game_id, &connection_id).await;
+            lobby::publish(&lobby, game_id, &games, &hub).await;
+        });
+    }
+}
+
+// The messages a websocket of the role would get, as server-sent events named by their type
+// and with their sequence number as ID. Ends when the game is closed.
+pub async fn event_stream(game_id: u64, role: ClientRole, last_seq: Option<u64>, games: Games, lobby: Lobby, hub: Hub) -> impl Stream<Item = Result<Event, Infallible>> {
+    let (sender, receiver) = mpsc::unbounded_channel();
+    let connection_id = Uuid::new_v4().as_simple().to_string();
+    hub.subscribe(game_id, &connection_id, role, sender, last_seq).await;
+    lobby::publish(&lobby, game_id, &games, &hub).await;
+
+    let subscription = Subscription { game_id, connection_id, games, lobby, hub };
+    UnboundedReceiverStream::new(receiver)
+        .take_while(|message| future::ready(message.as_ref().is_ok_and(|message| !message.is_close())))
+        .map(move |message| {
+            let _subscription = &subscription; // Lives as long as the stream
+            let message = message.unwrap();
+            Ok(event(message.to_str().unwrap_or_default()))
+        })
+}
+
+fn event(text: &str) -> Event {
+    let value: serde_json::Value = serde_json::from_str(text).unwrap_or_default();
+    let mut event = Event::default().data(text);
+    if let Some(kind) = value["type"].as_str() {
+        event = event.event(kind);
+    }
+    if let Some(seq) = value["seq"].as_u64() {
+        event = event.id(seq.to_string());
+    }
+    event
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use rust_poker::game::Game;
+    use rust_poker::protocol::ServerMessage;
+    use std::collections::HashMap;
+    use std::sync::Arc;
+    use tokio::sync::RwLock;
+
+    #[tokio::test]
+    async fn test_event_stream() {
+        let games: Games = Arc::new(RwLock::new(HashMap::from([(1, Game::new())])));
+        let hub: Hub = Arc::new(crate::pubsub::PubSub::default());
+        let game = Game::new();
+        let publish_state = || hub.publish_event(1, None, |role| Some(ServerMessage::State { state: match role {
+            ClientRole::Seat(seat) => game.get_state(seat),
+            ClientRole::Spectator => game.get_spectator_state(),
+        }}));
+        publish_state().await;
+
+        // Seat 0 sees its own hole cards, also in the event it missed
+        let stream = event_stream(1, ClientRole::Seat(0), Some(0), games.clone(), lobby::new_lobby(), hub.clone()).await;
+        let mut stream = Box::pin(stream);
+        publish_state().await;
+        for id in ["1", "2"] {
+            let event = stream.next().await.unwrap().unwrap().to_string();
+            let lines: Vec<&str> = event.lines().collect();
+            assert_eq!((lines[0], lines[2]), ("event:state", format!("id:{}", id).as_str()));
+            let data: serde_json::Value = serde_json::from_str(lines[1].strip_prefix("data:").unwrap()).unwrap();
+            let hole_cards = [&data["state"]["btn_hole_cards"], &data["state"]["bb_hole_cards"]];
+            assert_eq!(hole_cards.iter().filter(|cards| cards.is_null()).count(), 1);
+        }
+        assert_eq!(hub.roles().await[&1], vec![ClientRole::Seat(0)]);
+
+        hub.close(1, r#"{"type":"game_closed","game_id":1,"reason":"closed"}"#).await;
+        assert!(stream.next().await.unwrap().unwrap().to_string().starts_with("event:game_closed\ndata:"));
+        assert!(stream.next().await.is_none());
+    }
+}
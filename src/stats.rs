@@ -0,0 +1,253 @@
+use serde::{Serialize, Deserialize};
+
+use crate::common::Position;
+use crate::hand::HandResult;
+use crate::street::{Action, Street, StreetName};
+
+// Counters for one player in one position. Percentages are computed from these.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct PositionStats{
+    pub hands: u64,
+    pub vpip: u64, // Hands where the player voluntarily put chips in preflop
+    pub pfr: u64, // Hands where the player raised preflop
+    pub three_bet_opportunities: u64, // Hands where the player faced a single preflop raise
+    pub three_bets: u64,
+    pub postflop_bets_and_raises: u64,
+    pub postflop_calls: u64,
+    pub saw_flop: u64,
+    pub went_to_showdown: u64,
+    pub won_at_showdown: u64,
+}
+
+// Human-readable summary of PositionStats. Percentages are None if there is no data yet.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StatLine{
+    pub hands: u64,
+    pub vpip: Option<f64>,
+    pub pfr: Option<f64>,
+    pub three_bet: Option<f64>,
+    pub af: Option<f64>, // Aggression factor: postflop (bets + raises) / calls
+    pub wtsd: Option<f64>, // Went to showdown, percentage of the flops seen
+    pub wsd: Option<f64>, // Won at showdown, percentage of the showdowns
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct PlayerStats{
+    pub button: PositionStats,
+    pub big_blind: PositionStats,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SeatStatsReport{
+    pub seat: u8,
+    pub button: StatLine,
+    pub big_blind: StatLine,
+    pub total: StatLine,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StatsReport{
+    pub hands: u64,
+    pub seats: Vec<SeatStatsReport>,
+}
+
+fn percentage(count: u64, total: u64) -> Option<f64>{
+    match total{
+        0 => None,
+        _ => Some(100.0 * count as f64 / total as f64),
+    }
+}
+
+impl PositionStats{
+
+    pub fn add(&self, other: &PositionStats) -> PositionStats{
+        PositionStats{
+            hands: self.hands + other.hands,
+            vpip: self.vpip + other.vpip,
+            pfr: self.pfr + other.pfr,
+            three_bet_opportunities: self.three_bet_opportunities + other.three_bet_opportunities,
+            three_bets: self.three_bets + other.three_bets,
+            postflop_bets_and_raises: self.postflop_bets_and_raises + other.postflop_bets_and_raises,
+            postflop_calls: self.postflop_calls + other.postflop_calls,
+            saw_flop: self.saw_flop + other.saw_flop,
+            went_to_showdown: self.went_to_showdown + other.went_to_showdown,
+            won_at_showdown: self.won_at_showdown + other.won_at_showdown,
+        }
+    }
+
+    pub fn stat_line(&self) -> StatLine{
+        StatLine{
+            hands: self.hands,
+            vpip: percentage(self.vpip, self.hands),
+            pfr: percentage(self.pfr, self.hands),
+            three_bet: percentage(self.three_bets, self.three_bet_opportunities),
+            af: match self.postflop_calls{
+                0 => None,
+                calls => Some(self.postflop_bets_and_raises as f64 / calls as f64),
+            },
+            wtsd: percentage(self.went_to_showdown, self.saw_flop),
+            wsd: percentage(self.won_at_showdown, self.went_to_showdown),
+        }
+    }
+}
+
+impl PlayerStats{
+    pub fn position(&self, position: Position) -> &PositionStats{
+        match position{
+            Position::Button => &self.button,
+            Position::BigBlind => &self.big_blind,
+        }
+    }
+
+    fn position_mut(&mut self, position: Position) -> &mut PositionStats{
+        match position{
+            Position::Button => &mut self.button,
+            Position::BigBlind => &mut self.big_blind,
+        }
+    }
+
+    pub fn total(&self) -> PositionStats{
+        self.button.add(&self.big_blind)
+    }
+}
+
+// Builds per-seat HUD statistics from finished hands
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct StatsTracker{
+    pub hands: u64,
+    pub seats: [PlayerStats; 2],
+}
+
+impl StatsTracker{
+
+    pub fn new() -> StatsTracker{
+        StatsTracker::default()
+    }
+
+    // Updates the counters with a finished hand
+    pub fn record_hand(&mut self, streets: &[Street], result: &HandResult, button_seat: u8){
+        self.hands += 1;
+        let saw_flop = streets.len() > 1;
+        let showdown = result.showdown.is_some();
+
+        for position in [Position::Button, Position::BigBlind]{
+            let seat = match position{
+                Position::Button => button_seat,
+                Position::BigBlind => 1 - button_seat,
+            };
+            let stats = self.seats[seat as usize].position_mut(position);
+            stats.hands += 1;
+
+            // Preflop
+            let mut raises_before = 0;
+            let mut vpip = false;
+            let mut pfr = false;
+            let mut three_bet_opportunity = false;
+            let mut three_bet = false;
+            if let Some(preflop) = streets.iter().find(|s| s.street == StreetName::Preflop){
+                for (player, action) in preflop.actions_with_players(){
+                    if player == position {
+                        if raises_before == 1 {
+                            three_bet_opportunity = true;
+                            three_bet |= matches!(action, Action::Raise(_));
+                        }
+                        match action{
+                            Action::Call(_) | Action::Bet(_) => vpip = true,
+                            Action::Raise(_) => {
+                                vpip = true;
+                                pfr = true;
+                            },
+                            _ => (),
+                        }
+                    }
+                    if matches!(action, Action::Raise(_)) {
+                        raises_before += 1;
+                    }
+                }
+            }
+            stats.vpip += vpip as u64;
+            stats.pfr += pfr as u64;
+            stats.three_bet_opportunities += three_bet_opportunity as u64;
+            stats.three_bets += three_bet as u64;
+
+            // Postflop
+            for street in streets.iter().filter(|s| s.street != StreetName::Preflop){
+                for (player, action) in street.actions_with_players(){
+                    if player == position {
+                        match action{
+                            Action::Bet(_) | Action::Raise(_) => stats.postflop_bets_and_raises += 1,
+                            Action::Call(_) => stats.postflop_calls += 1,
+                            _ => (),
+                        }
+                    }
+                }
+            }
+
+            stats.saw_flop += saw_flop as u64;
+            stats.went_to_showdown += showdown as u64;
+            stats.won_at_showdown += (showdown && result.winner == Some(position)) as u64;
+        }
+    }
+
+    pub fn report(&self) -> StatsReport{
+        let seats = self.seats.iter().enumerate().map(|(seat, stats)| SeatStatsReport{
+            seat: seat as u8,
+            button: stats.button.stat_line(),
+            big_blind: stats.big_blind.stat_line(),
+            total: stats.total().stat_line(),
+        }).collect();
+        StatsReport{hands: self.hands, seats}
+    }
+}
+
+#[cfg(test)]
+mod tests{
+
+    use super::*;
+
+    fn preflop(actions: &[Action]) -> Street{
+        let mut street = Street::new(StreetName::Preflop, 10, 1000, 1000);
+        for action in actions{
+            street.submit_action(*action).unwrap();
+        }
+        street
+    }
+
+    fn fold_result(winner: Position) -> HandResult{
+        HandResult{winner: Some(winner), btn_stack: 0, bb_stack: 0, showdown: None, all_in: None, shown: Default::default()}
+    }
+
+    #[test]
+    fn test_open_raise_and_three_bet(){
+        let mut tracker = StatsTracker::new();
+
+        // Seat 0 on the button opens, seat 1 three-bets, button folds
+        let street = preflop(&[Action::PostBlind(5), Action::PostBlind(10), Action::Raise(30), Action::Raise(90), Action::Fold]);
+        tracker.record_hand(&[street], &fold_result(Position::BigBlind), 0);
+
+        let button = &tracker.seats[0].button;
+        assert_eq!((button.hands, button.vpip, button.pfr, button.three_bet_opportunities), (1, 1, 1, 0));
+        let big_blind = &tracker.seats[1].big_blind;
+        assert_eq!((big_blind.hands, big_blind.vpip, big_blind.pfr), (1, 1, 1));
+        assert_eq!((big_blind.three_bet_opportunities, big_blind.three_bets), (1, 1));
+        assert_eq!(big_blind.saw_flop, 0);
+
+        // Next hand seat 1 is on the button and limps, seat 0 checks
+        let preflop_street = preflop(&[Action::PostBlind(5), Action::PostBlind(10), Action::Call(10), Action::Check]);
+        let mut flop = Street::new(StreetName::Flop, 10, 990, 990);
+        flop.submit_action(Action::Bet(10)).unwrap();
+        flop.submit_action(Action::Fold).unwrap();
+        tracker.record_hand(&[preflop_street, flop], &fold_result(Position::BigBlind), 1);
+
+        let report = tracker.report();
+        assert_eq!(report.hands, 2);
+        assert_eq!(report.seats[1].button.vpip, Some(100.0));
+        assert_eq!(report.seats[1].button.pfr, Some(0.0));
+        assert_eq!(report.seats[0].big_blind.vpip, Some(0.0)); // Checking the big blind is not voluntary
+        assert_eq!(report.seats[0].big_blind.af, None); // One bet, no calls
+        assert_eq!(tracker.seats[0].big_blind.postflop_bets_and_raises, 1);
+        assert_eq!(report.seats[0].total.hands, 2);
+        assert_eq!(report.seats[0].total.vpip, Some(50.0));
+        assert_eq!(report.seats[0].total.wtsd, Some(0.0));
+    }
+}
@@ -0,0 +1,348 @@
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use serde::{Serialize, Deserialize};
+use crate::common::unix_time;
+use crate::game::{Game, GameEvent};
+
+// Games are stored as append-only logs, one JSON line per entry and one file per game.
+// The first line holds the game as it was created, the other lines hold its events.
+// A closed game ends with a closed entry and is not rebuilt on startup. Archived games
+// are moved to the archive directory, next to a file with their hand history.
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LogEntry{
+    pub time: u64, // Seconds since the Unix epoch
+    #[serde(flatten)]
+    pub record: LogRecord,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LogRecord{
+    Created(Box<Game>),
+    Event(GameEvent),
+    Closed(CloseReason),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CloseReason{
+    Closed, // Closed by a player
+    Archived,
+    Idle, // Nobody acted for too long
+}
+
+pub struct GameStore{
+    dir: PathBuf,
+}
+
+fn invalid_data(message: String) -> io::Error{
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+// Cuts off a last line that was not written completely, e.g. when the server stopped while
+// writing it. Otherwise the next entry would be appended to the broken line.
+fn cut_incomplete_line(file: &mut fs::File) -> io::Result<()>{
+    let len = file.metadata()?.len();
+    if len == 0 {
+        return Ok(());
+    }
+    let mut last = [0u8];
+    file.seek(SeekFrom::End(-1))?;
+    file.read_exact(&mut last)?;
+    if last[0] == b'\n' {
+        return Ok(());
+    }
+    let mut bytes = Vec::new();
+    file.seek(SeekFrom::Start(0))?;
+    file.read_to_end(&mut bytes)?;
+    let complete = bytes.iter().rposition(|byte| *byte == b'\n').map_or(0, |i| i + 1);
+    file.set_len(complete as u64)
+}
+
+// Rebuilds a game from its log entries
+pub fn replay(entries: Vec<LogEntry>) -> io::Result<Game>{
+    let mut entries = entries.into_iter();
+    let mut game = match entries.next().map(|entry| entry.record){
+        Some(LogRecord::Created(game)) => *game,
+        _ => return Err(invalid_data("log does not start with the created game".to_string())),
+    };
+
+    for entry in entries{
+        match entry.record{
+            LogRecord::Created(_) => return Err(invalid_data("game created twice".to_string())),
+            LogRecord::Closed(_) => break,
+            LogRecord::Event(GameEvent::Action{seat, action, time}) => {
+                game.replay_action_at(action, seat, time)
+                    .map_err(|e| invalid_data(format!("could not replay {:?} from seat {}: {}", action, seat, e)))?;
+            },
+            LogRecord::Event(GameEvent::UndoRequest{seat}) => {
+                game.request_undo(seat)
+                    .map_err(|e| invalid_data(format!("could not replay undo from seat {}: {}", seat, e)))?;
+            },
+            LogRecord::Event(GameEvent::Chat{message}) => {
+                game.send_chat_at(&message.text, message.seat, message.time)
+                    .map_err(|e| invalid_data(format!("could not replay chat from seat {}: {}", message.seat, e)))?;
+            },
+            LogRecord::Event(GameEvent::ChatMuted{seat, muted}) => {
+                game.mute_chat(seat, muted)
+                    .map_err(|e| invalid_data(format!("could not replay muting from seat {}: {}", seat, e)))?;
+            },
+            LogRecord::Event(GameEvent::HandFinished{result}) => game.restore_hand_result(*result), // Finished by the last action
+            LogRecord::Event(GameEvent::CardsShown{seat, cards}) => {
+                game.show_cards(seat, cards)
+                    .map_err(|e| invalid_data(format!("could not replay shown cards of seat {}: {}", seat, e)))?;
+            },
+            LogRecord::Event(GameEvent::PreActionQueued{seat, pre_action}) => {
+                game.queue_pre_action(seat, pre_action)
+                    .map_err(|e| invalid_data(format!("could not replay queued action of seat {}: {}", seat, e)))?;
+            },
+            LogRecord::Event(GameEvent::NewHand{deck, ..}) => game.deal_hand(deck),
+            LogRecord::Event(GameEvent::SeatClaimed{seat, token}) => game.assign_seat_token(seat, token),
+        }
+    }
+    game.take_events();
+    Ok(game)
+}
+
+impl GameStore{
+
+    pub fn open(dir: &Path) -> io::Result<GameStore>{
+        fs::create_dir_all(dir)?;
+        Ok(GameStore{dir: dir.to_path_buf()})
+    }
+
+    fn path(&self, game_id: u64) -> PathBuf{
+        self.dir.join(format!("game_{}.jsonl", game_id))
+    }
+
+    fn archive_dir(&self) -> PathBuf{
+        self.dir.join("archive")
+    }
+
+    fn append(&self, game_id: u64, records: Vec<LogRecord>, create: bool) -> io::Result<()>{
+        let mut lines = String::new();
+        for record in records{
+            lines.push_str(&serde_json::to_string(&LogEntry{time: unix_time(), record})?);
+            lines.push('\n');
+        }
+        let mut file = OpenOptions::new().read(true).append(true).create_new(create).open(self.path(game_id))?;
+        cut_incomplete_line(&mut file)?;
+        file.write_all(lines.as_bytes())
+    }
+
+    // Starts the log of a new game. Fails if the game already has a log.
+    pub fn create_game(&self, game_id: u64, game: &Game) -> io::Result<()>{
+        self.append(game_id, vec![LogRecord::Created(Box::new(game.clone()))], true)
+    }
+
+    pub fn append_events(&self, game_id: u64, events: Vec<GameEvent>) -> io::Result<()>{
+        if events.is_empty() {
+            return Ok(());
+        }
+        self.append(game_id, events.into_iter().map(LogRecord::Event).collect(), false)
+    }
+
+    pub fn close_game(&self, game_id: u64, reason: CloseReason) -> io::Result<()>{
+        self.append(game_id, vec![LogRecord::Closed(reason)], false)
+    }
+
+    // Moves the log of a game to the archive, and writes its hand history next to it
+    pub fn archive_game(&self, game_id: u64) -> io::Result<()>{
+        let game = self.load_game(game_id)?;
+        fs::create_dir_all(self.archive_dir())?;
+        let history = serde_json::to_string(game.hand_history())?;
+        fs::write(self.archive_dir().join(format!("game_{}_hands.json", game_id)), history)?;
+        fs::rename(self.path(game_id), self.archive_dir().join(format!("game_{}.jsonl", game_id)))
+    }
+
+    pub fn is_closed(&self, game_id: u64) -> io::Result<bool>{
+        Ok(matches!(self.read_log(game_id)?.last(), Some(LogEntry{record: LogRecord::Closed(_), ..})))
+    }
+
+    pub fn read_log(&self, game_id: u64) -> io::Result<Vec<LogEntry>>{
+        let reader = BufReader::new(fs::File::open(self.path(game_id))?);
+        let lines: Vec<String> = reader.lines().collect::<io::Result<_>>()?;
+        let mut entries = Vec::new();
+        for (i, line) in lines.iter().enumerate(){
+            match serde_json::from_str(line){
+                Ok(entry) => entries.push(entry),
+                // The last line is cut short if the server stopped while writing it
+                Err(_) if i == lines.len() - 1 => eprintln!("ignoring incomplete last line in log of game {}", game_id),
+                Err(e) => return Err(invalid_data(format!("line {} of the log of game {}: {}", i + 1, game_id, e))),
+            }
+        }
+        Ok(entries)
+    }
+
+    pub fn load_game(&self, game_id: u64) -> io::Result<Game>{
+        replay(self.read_log(game_id)?)
+    }
+
+    // Rebuilds every stored game that is not closed. Logs that cannot be replayed are reported and skipped.
+    pub fn load_games(&self) -> io::Result<HashMap<u64, Game>>{
+        let mut games = HashMap::new();
+        for entry in fs::read_dir(&self.dir)?{
+            let name = entry?.file_name().to_string_lossy().to_string();
+            let game_id = match name.strip_prefix("game_").and_then(|n| n.strip_suffix(".jsonl")).map(|n| n.parse::<u64>()){
+                Some(Ok(game_id)) => game_id,
+                _ => continue,
+            };
+            match self.read_log(game_id){
+                Ok(entries) if matches!(entries.last(), Some(LogEntry{record: LogRecord::Closed(_), ..})) => (),
+                Ok(entries) => match replay(entries){
+                    Ok(game) => { games.insert(game_id, game); },
+                    Err(e) => eprintln!("could not load game {}: {}", game_id, e),
+                },
+                Err(e) => eprintln!("could not read game {}: {}", game_id, e),
+            }
+        }
+        Ok(games)
+    }
+}
+
+#[cfg(test)]
+mod tests{
+
+    use super::*;
+    use crate::game::{GameSettings, HandRecord, PreAction, ShowCards};
+    use crate::street::{Action, StreetName};
+
+    fn temp_store(name: &str) -> (PathBuf, GameStore){
+        let dir = std::env::temp_dir().join(format!("poker_store_{}_{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let store = GameStore::open(&dir).unwrap();
+        (dir, store)
+    }
+
+    #[test]
+    fn test_games_are_rebuilt_from_logs(){
+        let (dir, store) = temp_store("rebuild");
+
+        let mut game = Game::new_with_stacks_and_sb(500, 600, 5);
+        game.set_settings(GameSettings{allow_undo: true, ..Default::default()});
+        store.create_game(7, &game).unwrap();
+        assert!(store.create_game(7, &game).is_err());
+        let token = game.claim_seat(0, None, None).unwrap();
+
+        // First hand folded preflop, second hand goes to the flop and an action is undone
+        game.submit_action(Action::PostBlind(5), 0).unwrap();
+        game.submit_action(Action::PostBlind(10), 1).unwrap();
+        game.submit_action(Action::Fold, 0).unwrap();
+        game.show_cards(1, ShowCards::Both).unwrap();
+        store.append_events(7, game.take_events()).unwrap();
+
+        game.submit_action(Action::PostBlind(5), 1).unwrap();
+        game.submit_action(Action::PostBlind(10), 0).unwrap();
+        game.submit_action(Action::Call(10), 1).unwrap();
+        game.submit_action(Action::Check, 0).unwrap();
+        game.submit_action(Action::Bet(20), 0).unwrap();
+        game.request_undo(0).unwrap();
+        game.request_undo(1).unwrap();
+        game.send_chat("nice hand", 1).unwrap();
+        game.mute_chat(0, true).unwrap();
+        store.append_events(7, game.take_events()).unwrap();
+
+        let games = store.load_games().unwrap();
+        assert_eq!(games.len(), 1);
+        assert_eq!(games[&7].to_snapshot_json(), game.to_snapshot_json());
+        assert_eq!(games[&7].current_hand().board_cards.len(), 3);
+        assert!(games[&7].clone().claim_seat(0, Some(&token), None).is_ok());
+
+        // Seat 1 checks from the queue, and its next queued action waits for the turn
+        game.queue_pre_action(1, Some(PreAction::CheckOrFold)).unwrap();
+        game.submit_action(Action::Check, 0).unwrap();
+        game.queue_pre_action(1, Some(PreAction::CallAny)).unwrap();
+        store.append_events(7, game.take_events()).unwrap();
+        let games = store.load_games().unwrap();
+        assert_eq!(games[&7].to_snapshot_json(), game.to_snapshot_json());
+        assert_eq!(games[&7].current_hand().board_cards.len(), 4);
+        assert_eq!(games[&7].pre_action(1), Some(PreAction::CallAny));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_preflop_all_in_is_rebuilt_with_its_logged_equity(){
+        let (dir, store) = temp_store("all_in");
+
+        let mut game = Game::new_with_stacks_and_sb(500, 500, 5);
+        store.create_game(3, &game).unwrap();
+        game.submit_action(Action::PostBlind(5), 0).unwrap();
+        game.submit_action(Action::PostBlind(10), 1).unwrap();
+        game.submit_action(Action::Raise(500), 0).unwrap();
+        game.submit_action(Action::Call(500), 1).unwrap();
+        for _ in 0..3 { // The board is run out
+            game.submit_action(Action::Check, 1).unwrap();
+            game.submit_action(Action::Check, 0).unwrap();
+        }
+        store.append_events(3, game.take_events()).unwrap();
+        assert_eq!(game.hand_history()[0].result.all_in.as_ref().unwrap().street, StreetName::Preflop);
+
+        // The sampled equity and the adjusted session report are the ones from before the restart
+        let games = store.load_games().unwrap();
+        assert_eq!(games[&3].to_snapshot_json(), game.to_snapshot_json());
+        assert_eq!(serde_json::to_string(&games[&3].get_session_report()).unwrap(), serde_json::to_string(&game.get_session_report()).unwrap());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_closed_and_archived_games(){
+        let (dir, store) = temp_store("closed");
+
+        for game_id in [1, 2, 3]{
+            store.create_game(game_id, &Game::new()).unwrap();
+        }
+        let mut game = Game::new_with_stacks_and_sb(500, 500, 5);
+        store.create_game(4, &game).unwrap();
+        game.submit_action(Action::PostBlind(5), 0).unwrap();
+        game.submit_action(Action::PostBlind(10), 1).unwrap();
+        game.submit_action(Action::Fold, 0).unwrap();
+        store.append_events(4, game.take_events()).unwrap();
+
+        store.close_game(1, CloseReason::Idle).unwrap();
+        store.close_game(4, CloseReason::Archived).unwrap();
+        store.archive_game(4).unwrap();
+        assert!(store.is_closed(1).unwrap());
+        assert!(!store.is_closed(2).unwrap());
+
+        let mut ids: Vec<u64> = store.load_games().unwrap().into_keys().collect();
+        ids.sort();
+        assert_eq!(ids, vec![2, 3]);
+
+        assert!(!store.path(4).exists());
+        assert!(dir.join("archive/game_4.jsonl").exists());
+        let history: Vec<HandRecord> = serde_json::from_str(&fs::read_to_string(dir.join("archive/game_4_hands.json")).unwrap()).unwrap();
+        assert_eq!(history.len(), 1);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_incomplete_last_line_is_ignored(){
+        let (dir, store) = temp_store("incomplete");
+
+        let mut game = Game::new_with_stacks_and_sb(500, 500, 5);
+        store.create_game(1, &game).unwrap();
+        game.submit_action(Action::PostBlind(5), 0).unwrap();
+        store.append_events(1, game.take_events()).unwrap();
+
+        let mut file = OpenOptions::new().append(true).open(store.path(1)).unwrap();
+        file.write_all(b"{\"time\": 1, \"event\": {\"ev").unwrap();
+
+        assert_eq!(store.load_game(1).unwrap().to_snapshot_json(), game.to_snapshot_json());
+
+        // The broken line is cut off when the game goes on, so the next events are kept
+        game.submit_action(Action::PostBlind(10), 1).unwrap();
+        game.submit_action(Action::Fold, 0).unwrap();
+        store.append_events(1, game.take_events()).unwrap();
+        let games = store.load_games().unwrap();
+        assert_eq!(games[&1].to_snapshot_json(), game.to_snapshot_json());
+        assert_eq!(games[&1].hand_history().len(), 1);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
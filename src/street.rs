@@ -3,6 +3,7 @@ use std::cmp::min as min;
 use serde::{Serialize,Deserialize};
 
 use crate::common::{Position, other_player};
+use crate::error::ActionError;
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub enum Action{
@@ -14,6 +15,24 @@ pub enum Action{
     Raise(u64), // Raise *to*, not *by*
 }
 
+// What a player asks for: an Action, or a helper that is turned into one depending on the
+// situation. Helper amounts are "by" amounts or a fraction of the pot.
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum ActionChoice{
+    Fold,
+    Check,
+    PostBlind(u64),
+    Call(u64),
+    Bet(u64),
+    Raise(u64),
+    AllIn, // The largest bet or raise, or a call if raising is not possible
+    BetBy(u64),
+    RaiseBy(u64), // Raise by this much more than the bet of the opponent
+    PotFraction(f64), // Bet or raise by this fraction of the pot after calling, e.g. 0.5 for half the pot
+    CheckOrFold,
+    CheckOrCall,
+}
+
 #[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub enum StreetName{
     Preflop,
@@ -102,13 +121,16 @@ impl Street{
                     minimum_raise_size = 2 * amount;
                 }
                 Action::Bet(amount) => {
-                    minimum_raise_size = 2 * amount;
+                    // An all in bet below the big blind still needs a raise by the big blind
+                    minimum_raise_size = amount + max(*amount, self.min_open_raise);
                     *active_player_added_chips = *amount;
                 },
                 Action::Raise(amount) => {
-                    // Minimum raise size can not be None because there can only be a raise if there is has been a bet
+                    // Minimum raise size can not be None because there can only be a raise if there is has been a bet.
+                    // An all in raise by less than the last raise does not lower the next minimum raise.
                     let raise_by_amount = amount - bigger_added_chips_before_action;
-                    minimum_raise_size = bigger_added_chips_before_action + 2 * raise_by_amount;
+                    let last_raise_by_amount = minimum_raise_size - bigger_added_chips_before_action;
+                    minimum_raise_size = amount + max(raise_by_amount, last_raise_by_amount);
                     *active_player_added_chips = *amount;
                 },
             }
@@ -129,6 +151,17 @@ impl Street{
         (btn_added_chips, bb_added_chips, minimum_raise_size, active_player)
     }
 
+    // Returns the actions of the street together with the player who took each one
+    pub fn actions_with_players(&self) -> Vec<(Position, Action)>{
+        let mut player = self.get_first_to_act();
+        let mut result = Vec::new();
+        for action in &self.actions{
+            result.push((player, *action));
+            player = other_player(player);
+        }
+        result
+    }
+
     // Returns the valid actions for the player in turn.
     // For bets, raises, and allins, return the minimum and maximum amounts.
     pub fn get_available_actions(&self) -> Vec<ActionOption>{
@@ -174,9 +207,10 @@ impl Street{
         valid_actions.push(ActionOption::Fold);
 
         // Can we bet?
-        if btn_added_chips == 0 && bb_added_chips == 0{
-            // Bet must be possible if no chips have been added yet and the hand has not ended yet
-            valid_actions.push(ActionOption::Bet(minimum_raise_size, active_player_stack));
+        if btn_added_chips == 0 && bb_added_chips == 0 && active_player_stack > 0{
+            // Bet must be possible if no chips have been added yet and the hand has not ended yet,
+            // unless the player is all in. A short stack can still bet all in.
+            valid_actions.push(ActionOption::Bet(min(minimum_raise_size, active_player_stack), active_player_stack));
         }
 
         // Can we call?
@@ -187,7 +221,8 @@ impl Street{
 
         // Can we raise?
         if btn_added_chips + bb_added_chips > 0 && active_player_stack > max(btn_added_chips, bb_added_chips){
-            valid_actions.push(ActionOption::Raise(minimum_raise_size, active_player_initial_stack));
+            // A short stack can still raise all in
+            valid_actions.push(ActionOption::Raise(min(minimum_raise_size, active_player_initial_stack), active_player_initial_stack));
         }
         
         // Can we check?
@@ -199,11 +234,9 @@ impl Street{
         valid_actions
     }
 
-    pub fn submit_action(&mut self, action: Action) -> Result<ActionResult, String>{
+    pub fn submit_action(&mut self, action: Action) -> Result<ActionResult, ActionError>{
 
-        if !self.is_valid_action(action) {
-            return Err("Invalid action".to_string());
-        }
+        self.validate_action(action)?;
 
         // Get status before applying the action
         let (_, _, _, active_player) = self.get_street_status();
@@ -217,10 +250,8 @@ impl Street{
         // Determine if this action closes the betting round
         match action{
             Action::Fold => result = ActionResult::Fold(active_player),
-            Action::Check => {
-                if active_player == last_to_act{
-                    result = ActionResult::BettingClosed;
-                }
+            Action::Check if active_player == last_to_act => {
+                result = ActionResult::BettingClosed;
             },
             Action::Call(amount) => {
                 // Betting is closed after a call unless we are before the flop
@@ -243,25 +274,114 @@ impl Street{
 
     }
 
+    // Removes the last action and restores the stacks. Returns the removed action.
+    pub fn undo_last_action(&mut self) -> Option<Action>{
+        let action = self.actions.pop()?;
+        let (btn_added_chips, bb_added_chips, _, _) = self.get_street_status();
+        self.btn_stack = self.btn_start_stack - btn_added_chips;
+        self.bb_stack = self.bb_start_stack - bb_added_chips;
+        Some(action)
+    }
+
+    // Turns the choice into the concrete action for the player in turn. The pot includes the chips
+    // of the earlier streets. Bets and raises are not validated, except that a pot fraction is
+    // kept between the minimum and the stack.
+    pub fn resolve_choice(&self, choice: ActionChoice, pot: u64) -> Result<Action, ActionError>{
+        let available_actions = self.get_available_actions();
+        if available_actions.is_empty() {
+            return Err(ActionError::HandOver);
+        }
+        let (btn_added_chips, bb_added_chips, _, active_player) = self.get_street_status();
+        let (added_chips, opponent_added_chips) = match active_player{
+            Position::Button => (btn_added_chips, bb_added_chips),
+            Position::BigBlind => (bb_added_chips, btn_added_chips),
+        };
+
+        let bet = available_actions.iter().find_map(|x| match x { ActionOption::Bet(minimum, maximum) => Some((*minimum, *maximum)), _ => None });
+        let raise = available_actions.iter().find_map(|x| match x { ActionOption::Raise(minimum, maximum) => Some((*minimum, *maximum)), _ => None });
+        let call = available_actions.iter().find_map(|x| match x { ActionOption::Call(amount) => Some(*amount), _ => None });
+        let check = available_actions.contains(&ActionOption::Check);
+        let not_available = || ActionError::ChoiceNotAvailable{choice: format!("{:?}", choice), available_actions: available_actions.clone()};
+
+        match choice{
+            ActionChoice::Fold => Ok(Action::Fold),
+            ActionChoice::Check => Ok(Action::Check),
+            ActionChoice::PostBlind(amount) => Ok(Action::PostBlind(amount)),
+            ActionChoice::Call(amount) => Ok(Action::Call(amount)),
+            ActionChoice::Bet(amount) => Ok(Action::Bet(amount)),
+            ActionChoice::Raise(amount) => Ok(Action::Raise(amount)),
+            ActionChoice::AllIn => match (bet, raise, call){
+                (Some((_, maximum)), _, _) => Ok(Action::Bet(maximum)),
+                (_, Some((_, maximum)), _) => Ok(Action::Raise(maximum)),
+                (_, _, Some(amount)) => Ok(Action::Call(amount)),
+                _ => Err(not_available()),
+            },
+            ActionChoice::BetBy(amount) => bet.map(|_| Action::Bet(added_chips.saturating_add(amount))).ok_or_else(not_available),
+            ActionChoice::RaiseBy(amount) => raise.map(|_| Action::Raise(max(added_chips, opponent_added_chips).saturating_add(amount))).ok_or_else(not_available),
+            ActionChoice::PotFraction(fraction) => {
+                if !fraction.is_finite() || fraction <= 0.0 {
+                    return Err(ActionError::MalformedCommand{message: format!("pot fraction {} must be positive", fraction)});
+                }
+                let to_call = opponent_added_chips.saturating_sub(added_chips);
+                let size = (fraction * (pot + to_call) as f64).round() as u64;
+                match (bet, raise){
+                    (Some((minimum, maximum)), _) => Ok(Action::Bet(size.max(minimum).min(maximum))),
+                    (_, Some((minimum, maximum))) => Ok(Action::Raise(opponent_added_chips.saturating_add(size).max(minimum).min(maximum))),
+                    _ => Err(not_available()),
+                }
+            },
+            ActionChoice::CheckOrFold => match (check, available_actions.contains(&ActionOption::Fold)){
+                (true, _) => Ok(Action::Check),
+                (false, true) => Ok(Action::Fold),
+                _ => Err(not_available()),
+            },
+            ActionChoice::CheckOrCall => match (check, call){
+                (true, _) => Ok(Action::Check),
+                (false, Some(amount)) => Ok(Action::Call(amount)),
+                _ => Err(not_available()),
+            },
+        }
+    }
+
     pub fn is_valid_action(&self, action: Action) -> bool{
+        self.validate_action(action).is_ok()
+    }
+
+    // Returns Ok if the action is valid for the player in turn, and otherwise the reason why not
+    pub fn validate_action(&self, action: Action) -> Result<(), ActionError>{
         let available_actions = self.get_available_actions();
-        match action{
-            Action::Fold => available_actions.contains(&ActionOption::Fold),
-            Action::Check => available_actions.contains(&ActionOption::Check),
-            Action::Call(amount) => available_actions.contains(&ActionOption::Call(amount)),
-            Action::PostBlind(amount) => available_actions.contains(&ActionOption::PostBlind(amount)),
-            Action::Bet(amount) => {
-                available_actions.iter().any(|x| match x{
-                    ActionOption::Bet(minimum, maximum) => amount >= *minimum && amount <= *maximum,
-                    _ => false,
-                })
+        if available_actions.is_empty() {
+            return Err(ActionError::HandOver);
+        }
+
+        let not_available = || ActionError::ActionNotAvailable{action, available_actions: available_actions.clone()};
+
+        // Finds the option of the same kind as the action
+        let option = available_actions.iter().find(|x| matches!((action, x),
+            (Action::Fold, ActionOption::Fold) |
+            (Action::Check, ActionOption::Check) |
+            (Action::Call(_), ActionOption::Call(_)) |
+            (Action::PostBlind(_), ActionOption::PostBlind(_)) |
+            (Action::Bet(_), ActionOption::Bet(_, _)) |
+            (Action::Raise(_), ActionOption::Raise(_, _))
+        )).ok_or_else(not_available)?;
+
+        match (action, *option){
+            (Action::Call(amount), ActionOption::Call(expected)) |
+            (Action::PostBlind(amount), ActionOption::PostBlind(expected)) if amount != expected => {
+                Err(ActionError::WrongAmount{amount, expected})
             },
-            Action::Raise(amount) => {
-                available_actions.iter().any(|x| match x{
-                    ActionOption::Raise(minimum, maximum) => amount >= *minimum && amount <= *maximum,
-                    _ => false,
-                })
+            (Action::Bet(amount), ActionOption::Bet(minimum, maximum)) |
+            (Action::Raise(amount), ActionOption::Raise(minimum, maximum)) => {
+                if amount < minimum {
+                    Err(ActionError::AmountBelowMinimum{amount, minimum, maximum})
+                } else if amount > maximum {
+                    Err(ActionError::AmountAboveStack{amount, minimum, maximum})
+                } else {
+                    Ok(())
+                }
             },
+            _ => Ok(()),
         }
     }
 
@@ -385,7 +505,7 @@ mod tests {
 
         match street.submit_action(Action::Bet(10)){
             Ok(ActionResult::BettingOpen) => (),
-            _ => assert!(false),
+            _ => panic!(),
         }
 
         // Button's turn
@@ -404,7 +524,7 @@ mod tests {
 
         match street.submit_action(Action::Raise(100)){
             Ok(ActionResult::BettingOpen) => (),
-            _ => assert!(false),
+            _ => panic!(),
         }
 
         // Big blinds's turn
@@ -423,7 +543,7 @@ mod tests {
 
         match street.submit_action(Action::Raise(2000)){ // All in
             Ok(ActionResult::BettingOpen) => (),
-            _ => assert!(false),
+            _ => panic!(),
         }
 
         // Button's turn
@@ -441,7 +561,7 @@ mod tests {
 
         match street.submit_action(Action::Call(1000)){
             Ok(ActionResult::BettingClosed) => (),
-            _ => assert!(false),
+            _ => panic!(),
         }
 
         // Check the final state
@@ -474,6 +594,100 @@ mod tests {
         assert_eq!(street.bb_stack, 2000);
     }
 
+    #[test]
+    fn test_validation_errors(){
+        let mut street = Street::new(StreetName::Preflop, 10, 1000, 2000);
+        assert_eq!(street.submit_action(Action::PostBlind(10)), Err(ActionError::WrongAmount{amount: 10, expected: 5}));
+        street.submit_action(Action::PostBlind(5)).unwrap();
+        street.submit_action(Action::PostBlind(10)).unwrap();
+
+        assert_eq!(street.submit_action(Action::Raise(15)), Err(ActionError::AmountBelowMinimum{amount: 15, minimum: 20, maximum: 1000}));
+        assert_eq!(street.submit_action(Action::Raise(1001)), Err(ActionError::AmountAboveStack{amount: 1001, minimum: 20, maximum: 1000}));
+        assert!(matches!(street.submit_action(Action::Check), Err(ActionError::ActionNotAvailable{action: Action::Check, ..})));
+        assert_eq!(street.actions.len(), 2); // Nothing was applied
+    }
+
+    #[test]
+    fn test_short_all_in_options(){
+        let mut street = Street::new(StreetName::Flop, 10, 1000, 150);
+        street.submit_action(Action::Check).unwrap();
+        street.submit_action(Action::Bet(100)).unwrap();
+
+        // The big blind has less than a full raise to 200, but can raise all in
+        assert!(street.get_available_actions().contains(&ActionOption::Raise(150, 150)));
+        assert_eq!(street.submit_action(Action::Raise(150)), Ok(ActionResult::BettingOpen));
+
+        // A stack smaller than the big blind can still bet all in
+        let street = Street::new(StreetName::Flop, 10, 1000, 5);
+        assert_eq!(street.get_available_actions(), vec![ActionOption::Fold, ActionOption::Bet(5, 5), ActionOption::Check]);
+
+        // A player who is all in can only check on the next street
+        let street = Street::new(StreetName::Turn, 10, 1000, 0);
+        assert_eq!(street.get_available_actions(), vec![ActionOption::Fold, ActionOption::Check]);
+    }
+
+    #[test]
+    fn test_short_all_in_raise(){
+        let mut street = Street::new(StreetName::Flop, 10, 1000, 150);
+        street.submit_action(Action::Check).unwrap();
+        street.submit_action(Action::Bet(100)).unwrap();
+        street.submit_action(Action::Raise(150)).unwrap();
+
+        // The short raise by 50 does not lower the next minimum raise by 100
+        assert!(street.get_available_actions().contains(&ActionOption::Raise(250, 1000)));
+
+        // A short all in bet of 5 still needs a raise by the big blind
+        let mut street = Street::new(StreetName::Flop, 10, 1000, 5);
+        street.submit_action(Action::Bet(5)).unwrap();
+        assert!(street.get_available_actions().contains(&ActionOption::Raise(15, 1000)));
+    }
+
+    #[test]
+    fn test_resolve_choices(){
+        let mut street = Street::new(StreetName::Preflop, 10, 1000, 500);
+        assert_eq!(street.resolve_choice(ActionChoice::CheckOrFold, 0).unwrap_err(), ActionError::ChoiceNotAvailable{
+            choice: "CheckOrFold".to_string(), available_actions: vec![ActionOption::PostBlind(5)]});
+        street.submit_action(Action::PostBlind(5)).unwrap();
+        street.submit_action(Action::PostBlind(10)).unwrap();
+
+        // The button can call 10 or raise to 20 or more, with 15 in the pot
+        assert_eq!(street.resolve_choice(ActionChoice::CheckOrFold, 15), Ok(Action::Fold));
+        assert_eq!(street.resolve_choice(ActionChoice::CheckOrCall, 15), Ok(Action::Call(10)));
+        assert_eq!(street.resolve_choice(ActionChoice::RaiseBy(30), 15), Ok(Action::Raise(40)));
+        assert_eq!(street.resolve_choice(ActionChoice::PotFraction(1.0), 15), Ok(Action::Raise(30)));
+        assert_eq!(street.resolve_choice(ActionChoice::PotFraction(0.1), 15), Ok(Action::Raise(20))); // The minimum
+        assert_eq!(street.resolve_choice(ActionChoice::AllIn, 15), Ok(Action::Raise(1000)));
+        assert!(street.resolve_choice(ActionChoice::BetBy(20), 15).is_err());
+        assert!(street.resolve_choice(ActionChoice::PotFraction(-1.0), 15).is_err());
+
+        // The big blind can only call the all in
+        street.submit_action(Action::Raise(1000)).unwrap();
+        assert_eq!(street.resolve_choice(ActionChoice::AllIn, 1010), Ok(Action::Call(500)));
+        assert_eq!(street.resolve_choice(ActionChoice::Raise(600), 1010), Ok(Action::Raise(600))); // Validated when submitted
+
+        // Bets after the flop
+        let street = Street::new(StreetName::Flop, 10, 400, 400);
+        assert_eq!(street.resolve_choice(ActionChoice::CheckOrCall, 200), Ok(Action::Check));
+        assert_eq!(street.resolve_choice(ActionChoice::BetBy(50), 200), Ok(Action::Bet(50)));
+        assert_eq!(street.resolve_choice(ActionChoice::PotFraction(0.5), 200), Ok(Action::Bet(100)));
+        assert_eq!(street.resolve_choice(ActionChoice::PotFraction(3.0), 200), Ok(Action::Bet(400)));
+        assert!(street.resolve_choice(ActionChoice::RaiseBy(50), 200).is_err());
+    }
+
+    #[test]
+    fn test_choice_amounts_do_not_overflow(){
+        let mut street = Street::new(StreetName::Preflop, 10, 1000, 1000);
+        street.submit_action(Action::PostBlind(5)).unwrap();
+        street.submit_action(Action::PostBlind(10)).unwrap();
+
+        // Client supplied amounts near u64::MAX saturate and are rejected when submitted
+        assert_eq!(street.resolve_choice(ActionChoice::RaiseBy(u64::MAX), 15), Ok(Action::Raise(u64::MAX)));
+        assert!(street.submit_action(Action::Raise(u64::MAX)).is_err());
+
+        let street = Street::new(StreetName::Flop, 10, 1000, 1000);
+        assert_eq!(street.resolve_choice(ActionChoice::BetBy(u64::MAX), 200), Ok(Action::Bet(u64::MAX)));
+    }
+
     #[test]
     fn test_not_enough_chips_to_post_sb(){
         let mut street = Street::new(StreetName::Preflop, 10, 1, 2);
@@ -481,7 +695,7 @@ mod tests {
         let actions = street.get_available_actions();
         assert_eq!(actions.len(), 0); // Can not do anything
 
-        assert!(street.submit_action(Action::PostBlind(5)).is_err()); // Should fail
+        assert_eq!(street.submit_action(Action::PostBlind(5)), Err(ActionError::HandOver)); // Should fail
     }
 
     #[test]
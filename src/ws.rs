@@ -1,14 +1,22 @@
-
-use crate::{MyClient, MyClients};
+use crate::{lobby, ClientRole, Hub, Lobby, MyClient, MyClients};
+use crate::pubsub::ClientSender;
 use futures::{FutureExt, StreamExt};
 use tokio::sync::mpsc;
 use tokio_stream::wrappers::UnboundedReceiverStream;
 use warp::ws::{Message, WebSocket};
 use crate::Game;
+use rust_poker::game::{ChatMessage, GameEvent, GameState};
+use rust_poker::hand::HandResult;
 use crate::Games;
+use crate::Store;
+use rust_poker::common::unix_time;
+use rust_poker::error::ActionError;
+use rust_poker::protocol::{ClientMessage, Envelope, Resume, ServerMessage, PROTOCOL_VERSION};
+use uuid::Uuid;
 
 // Create a new task to handle message from/to the client
-pub async fn client_connection(ws: WebSocket, id: String, clients: MyClients, mut client: MyClient, games: Games) {
+#[allow(clippy::too_many_arguments)]
+pub async fn client_connection(ws: WebSocket, id: String, clients: MyClients, client: MyClient, games: Games, store: Store, lobby: Lobby, hub: Hub) {
     let (client_ws_sender, mut client_ws_rcv) = ws.split();
     let (client_sender, client_rcv) = mpsc::unbounded_channel();
 
@@ -19,14 +27,16 @@ pub async fn client_connection(ws: WebSocket, id: String, clients: MyClients, mu
         }
     }));
 
-    let seat = client.seat as u8;
+    let role = client.role;
     let game_id = client.game_id;
 
-    client.sender = Some(client_sender);
-    clients.write().await.insert(id.clone(), client);
-
+    // A seat can have several websockets at once, each subscribed on its own
+    let connection_id = Uuid::new_v4().as_simple().to_string();
     println!("{} connected", id);
 
+    // The client is subscribed to the game after the handshake
+    let mut subscribed = false;
+
     // The main loop that processes each message to the client
     while let Some(result) = client_ws_rcv.next().await {
         let msg = match result {
@@ -36,74 +46,220 @@ pub async fn client_connection(ws: WebSocket, id: String, clients: MyClients, mu
                 break;
             }
         };
-        client_msg(&id, game_id, seat, msg, &clients, &games).await;
+        let text = match msg.to_str() {
+            Ok(v) => v,
+            Err(_) => continue,
+        };
+        let Envelope{request_id, message, ..} = match Envelope::parse(text) {
+            Ok(envelope) => envelope,
+            Err((request_id, error)) => {
+                send(&client_sender, request_id, ServerMessage::error(error));
+                continue;
+            }
+        };
+
+        match message {
+            ClientMessage::Hello{version, ..} if version != PROTOCOL_VERSION => {
+                let error = ActionError::UnsupportedVersion{version, supported: PROTOCOL_VERSION};
+                send(&client_sender, request_id, ServerMessage::error(error));
+                let _ = client_sender.send(Ok(Message::close()));
+                break;
+            },
+            ClientMessage::Hello{token, resume, ..} => {
+                // The URL of a seat does not change, so a seat proves itself with its token.
+                // Only the seat itself may resume, as the missed events show its hole cards.
+                if role != ClientRole::Spectator || resume.is_some() {
+                    let token = token.or_else(|| resume.as_ref().map(|Resume{token, ..}| token.clone()));
+                    let seat = role.seat().unwrap_or(u8::MAX);
+                    let checked = match games.read().await.get(&game_id){
+                        Some(game) => game.check_seat_token(seat, token.as_deref().unwrap_or_default()),
+                        None => Err(ActionError::GameNotFound{game_id}),
+                    };
+                    if let Err(e) = checked {
+                        send(&client_sender, request_id, ServerMessage::error(e));
+                        continue;
+                    }
+                }
+                let last_seq = resume.map(|Resume{last_seq, ..}| last_seq);
+                let (seq, replayed) = hub.subscribe(game_id, &connection_id, role, client_sender.clone(), last_seq).await;
+                if !subscribed {
+                    lobby::publish(&lobby, game_id, &games, &hub).await;
+                    subscribed = true;
+                }
+                send(&client_sender, request_id, ServerMessage::Welcome{version: PROTOCOL_VERSION, seq, replayed});
+            },
+            _ if !subscribed => send(&client_sender, request_id, ServerMessage::error(ActionError::HandshakeRequired)),
+            message => {
+                if client_msg(&connection_id, game_id, role, request_id, message, &client_sender, &games, &store, &hub).await {
+                    lobby::publish(&lobby, game_id, &games, &hub).await;
+                }
+            },
+        }
     }
 
-    clients.write().await.remove(&id);
+    // The seats keep their websocket URL to reconnect, spectators ask for a new one
+    hub.unsubscribe(game_id, &connection_id).await;
+    if role == ClientRole::Spectator {
+        clients.write().await.remove(&id);
+    }
+    lobby::publish(&lobby, game_id, &games, &hub).await;
     println!("{} disconnected", id);
 }
 
-// Broadcast a message to all clients
-async fn broadcast_message(clients: &MyClients, message: &String){
-    
-    for (_, client) in clients.write().await.iter_mut() {
-        if let Some(sender) = &client.sender {
-            let _ = sender.send(Ok(Message::text(message)));
-        }
+fn send(sender: &ClientSender, request_id: Option<String>, message: ServerMessage){
+    let _ = sender.send(Ok(Message::text(Envelope::new(request_id, message).to_json())));
+}
+
+fn state(game: &Game, role: ClientRole) -> GameState{
+    match role{
+        ClientRole::Seat(seat) => game.get_state(seat),
+        ClientRole::Spectator => game.get_spectator_state(),
     }
 }
 
-// Broadcast the state of the game to all clients, such that
+// Broadcast the state of the game to its clients, such that
 // each client sees only their own hole cards
-async fn broadcast_state(clients: &MyClients, game: &Game, game_id: u64){
-    for (_, client) in clients.write().await.iter_mut() {
-        if client.game_id == game_id {
-            if let Some(sender) = &client.sender {
-                let state = game.get_state_json(client.seat as u8);
-                let _ = sender.send(Ok(Message::text(state)));
-            }
+async fn broadcast_state(hub: &Hub, game: &Game, game_id: u64){
+    hub.publish_event(game_id, None, |role| Some(ServerMessage::State{state: state(game, role)})).await;
+}
+
+// Send the spectators the finished hands whose spectator delay has passed
+pub async fn broadcast_revealed_hands(hub: &Hub, game: &Game, game_id: u64){
+    let hands = game.spectator_hands(unix_time());
+    hub.publish_with(game_id, |subscriber| {
+        if subscriber.role != ClientRole::Spectator || subscriber.revealed_hands >= hands.len() {
+            return None;
         }
-    }
+        let message = ServerMessage::RevealedHands{hands: hands[subscriber.revealed_hands..].to_vec()};
+        subscriber.revealed_hands = hands.len();
+        Some(Envelope::event(message).to_json())
+    }).await;
 }
 
-async fn client_msg(websocket_id: &str, game_id: u64, seat: u8, msg: Message, clients: &MyClients, games: &Games) {
+// Answers a request of a client that has done the handshake. Returns true if a hand
+// finished, which changes what the lobby shows.
+#[allow(clippy::too_many_arguments)]
+async fn client_msg(client_id: &str, game_id: u64, role: ClientRole, request_id: Option<String>, message: ClientMessage, sender: &ClientSender, games: &Games, store: &Store, hub: &Hub) -> bool {
 
-    println!("received message from {}: {:?}", websocket_id, msg);
-    let message = match msg.to_str() {
-        Ok(v) => v,
-        Err(_) => return,
+    // Find the game and process the request for the game
+    let mut games = games.write().await;
+    let game = match games.get_mut(&game_id){
+        Some(game) => game,
+        None => {
+            send(sender, request_id, ServerMessage::error(ActionError::GameNotFound{game_id}));
+            return false;
+        }
     };
 
-    let mut locked = clients.write().await;
-
-    // Find the game and process the user command for the game
-    if let Some(v) = locked.get_mut(websocket_id) {
-        if let Some(sender) = &v.sender {
-            match games.write().await.get_mut(&game_id){ // Find the game
-                Some(game) => { // Game found
-                    if message == "state" {
-                        let state = game.get_state_json(seat);
-                        let _ = sender.send(Ok(Message::text(state)));
-                    } else {
-                        let (answer, hand_result) = game.process_user_command(&message.to_owned(), seat);
-                        let _ = sender.send(Ok(Message::text(answer)));
-
-                        drop(locked); // Drop the write lock to be able to broadcast
-
-                        // Broadcast state to all clients
-                        broadcast_state(clients, &game, game_id).await;
-
-                        // If the game is over, broadcast the showdown result to all clients
-                        if let Some(hand_result) = hand_result {
-                            broadcast_message(clients, &serde_json::to_string(&hand_result).unwrap()).await;
-                        }
-                    }
-                },
-                None => { // Game not found
-                    let _ = sender.send(Ok(Message::text("Game not found")));
-                }
-            }
-        }
+    let mut hand_result = None;
+    let mut chat = None;
+    let answer = match (message, role) {
+        (ClientMessage::Hello{..}, _) => unreachable!("hello is handled by the connection"),
+        (ClientMessage::GetState, _) => ServerMessage::State{state: state(game, role)},
+        (ClientMessage::GetChat, _) => ServerMessage::ChatHistory{messages: game.chat_history(role.seat())},
+        (ClientMessage::GetStats, _) => ServerMessage::Stats{stats: game.get_stats()},
+        (ClientMessage::GetSession, _) => ServerMessage::Session{session: game.get_session_report()},
+        (_, ClientRole::Spectator) => ServerMessage::error(ActionError::SpectatorCannotAct),
+        (ClientMessage::Action{action}, ClientRole::Seat(seat)) => match game.submit_choice(action, seat){
+            Ok((action, result)) => {
+                hand_result = result;
+                ServerMessage::ActionAccepted{action}
+            },
+            Err(e) => ServerMessage::error(e),
+        },
+        (ClientMessage::QueueAction{pre_action}, ClientRole::Seat(seat)) => match game.queue_pre_action(seat, pre_action){
+            Ok(()) => ServerMessage::ActionQueued{pre_action},
+            Err(e) => ServerMessage::error(e),
+        },
+        (ClientMessage::Undo, ClientRole::Seat(seat)) => match game.request_undo(seat){
+            Ok(status) => ServerMessage::Undo{status},
+            Err(e) => ServerMessage::error(e),
+        },
+        (ClientMessage::Chat{text}, ClientRole::Seat(seat)) => match game.send_chat(&text, seat){
+            Ok(message) => {
+                chat = Some(message.clone());
+                ServerMessage::Chat{message}
+            },
+            Err(e) => ServerMessage::error(e),
+        },
+        (ClientMessage::Show{cards}, ClientRole::Seat(seat)) => match game.show_cards(seat, cards){
+            Ok(_) => cards_shown(game, seat),
+            Err(e) => ServerMessage::error(e),
+        },
+        (ClientMessage::MuteChat{muted}, ClientRole::Seat(seat)) => match game.mute_chat(seat, muted){
+            Ok(()) => ServerMessage::ChatMuted{muted},
+            Err(e) => ServerMessage::error(e),
+        },
+    };
+
+    // The answer already tells the client that the request went through, so it comes after the log
+    let events = log_changes(game_id, game, store);
+    send(sender, request_id, answer);
+    send_changes(game_id, game, events, hand_result, chat, Some(client_id), hub).await
+}
+
+// The cards the seat has shown in the last finished hand
+pub fn cards_shown(game: &Game, seat: u8) -> ServerMessage{
+    let record = game.hand_history().last().unwrap();
+    let cards = record.result.shown.of(record.position_of(seat)).to_vec();
+    ServerMessage::CardsShown{hand_number: record.hand_number, seat, cards}
+}
+
+// Logs the changes of the game and sends them to its clients, leaving out the chat message
+// and the shown cards of the client that sent them. Returns true if a hand finished, which
+// changes what the lobby shows.
+pub async fn publish_changes(game_id: u64, game: &mut Game, hand_result: Option<HandResult>, chat: Option<ChatMessage>, sender_id: Option<&str>, store: &Store, hub: &Hub) -> bool {
+    let events = log_changes(game_id, game, store);
+    send_changes(game_id, game, events, hand_result, chat, sender_id, hub).await
+}
+
+// Logs the changes of the game before anyone sees them, and returns them
+fn log_changes(game_id: u64, game: &mut Game, store: &Store) -> Vec<GameEvent> {
+    let events = game.take_events();
+    if let Err(e) = store.append_events(game_id, events.clone()) {
+        eprintln!("could not store events of game {}: {}", game_id, e);
     }
+    events
+}
 
-}
\ No newline at end of file
+// Sends the logged changes of the game to its clients, see publish_changes
+async fn send_changes(game_id: u64, game: &Game, events: Vec<GameEvent>, hand_result: Option<HandResult>, chat: Option<ChatMessage>, sender_id: Option<&str>, hub: &Hub) -> bool {
+
+    // Nothing more to do unless the game changed
+    if events.is_empty() {
+        return false;
+    }
+    let state_changed = !events.iter().all(|event| matches!(event, GameEvent::Chat{..} | GameEvent::ChatMuted{..} | GameEvent::CardsShown{..} | GameEvent::PreActionQueued{..}));
+    let shown_by: Vec<u8> = events.iter().filter_map(|event| match event{
+        GameEvent::CardsShown{seat, ..} => Some(*seat),
+        _ => None,
+    }).collect();
+
+    // The sender already has the chat message and the shown cards as the answer
+    if let Some(message) = chat {
+        hub.publish_event(game_id, sender_id, |role| {
+            game.chat_visible_to(&message, role.seat()).then(|| ServerMessage::Chat{message: message.clone()})
+        }).await;
+    }
+    for seat in shown_by {
+        hub.publish_event(game_id, sender_id, |_| Some(cards_shown(game, seat))).await;
+    }
+    if !state_changed {
+        return false;
+    }
+
+    broadcast_state(hub, game, game_id).await;
+
+    // If the hand is over, send the showdown result to the clients of the game, without the
+    // hands that were mucked
+    if let (Some(result), Some(record)) = (hand_result, game.hand_history().last()) {
+        hub.publish_event(game_id, None, |role| {
+            let viewer = role.seat().map(|seat| record.position_of(seat));
+            Some(ServerMessage::HandResult{result: result.visible_to(viewer)})
+        }).await;
+        broadcast_revealed_hands(hub, game, game_id).await;
+        return true;
+    }
+
+    false
+}